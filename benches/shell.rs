@@ -0,0 +1,96 @@
+//! Measures the throughput of shell output, by running `cat` of a large file against a fake server.
+//!
+//! Run with `cargo bench --features testing`.
+
+use futures::stream::StreamExt;
+
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+use adb::blocking;
+use adb::client::shell::{ShellBuilder, ShellOutput};
+use adb::client::Remote;
+use adb::host::DeviceCriteria;
+use adb::testing::{device, FakeServer};
+
+const OUTPUT_SIZE: usize = 16 * 1024 * 1024;
+const ITERATIONS: u32 = 10;
+
+fn builder(shell_protocol: bool) -> ShellBuilder {
+  let mut builder = ShellBuilder::new();
+  builder
+    .command(Some(vec!["cat".into(), "/data/local/tmp/big".into()]))
+    .shell_protocol(shell_protocol)
+    .tty(false);
+  builder
+}
+
+/// Reads the output with the async [Shell](adb::client::shell::Shell) stream.
+fn run_async(remote: &Remote, shell_protocol: bool) -> usize {
+  adb::runtime::block_on(async {
+    let mut shell = builder(shell_protocol)
+      .connect(remote.clone(), DeviceCriteria::Any)
+      .await
+      .unwrap();
+    let mut total = 0;
+    while let Some(event) = shell.next().await {
+      if let ShellOutput::Stdout(data) = event.unwrap() {
+        total += data.len();
+      }
+    }
+    total
+  })
+}
+
+/// Reads the output with the [blocking::Shell] `Read` adapter.
+fn run_blocking(remote: &Remote, shell_protocol: bool) -> usize {
+  let remote = blocking::Remote::from(remote.clone());
+  let mut shell = remote
+    .connect_shell(&builder(shell_protocol), DeviceCriteria::Any)
+    .unwrap();
+  let mut buf = vec![0u8; 256 * 1024];
+  let mut total = 0;
+  loop {
+    match shell.read(&mut buf).unwrap() {
+      0 => return total,
+      len => total += len,
+    }
+  }
+}
+
+fn measure(run: impl Fn() -> usize) -> Duration {
+  // Warm up.
+  assert_eq!(OUTPUT_SIZE, run());
+
+  let start = Instant::now();
+  for _ in 0..ITERATIONS {
+    assert_eq!(OUTPUT_SIZE, run());
+  }
+  start.elapsed() / ITERATIONS
+}
+
+fn main() {
+  let output: Vec<u8> = (0..OUTPUT_SIZE).map(|i| (i % 251) as u8).collect();
+  let server = FakeServer::start().unwrap();
+  server.add_device(device("bench", 1));
+  server.on_shell("cat /data/local/tmp/big", &output, b"", 0);
+  let remote = server.remote();
+
+  for &(name, shell_protocol) in &[("shell,v2", true), ("shell", false)] {
+    for &(api, run) in &[
+      ("async", run_async as fn(&Remote, bool) -> usize),
+      ("blocking", run_blocking),
+    ] {
+      let mean = measure(|| run(&remote, shell_protocol));
+      let throughput = OUTPUT_SIZE as f64 / mean.as_secs_f64() / (1024.0 * 1024.0);
+      println!(
+        "{:<10} {:<10} cat {} MiB: {:>8.2?} per iteration, {:>8.1} MiB/s",
+        name,
+        api,
+        OUTPUT_SIZE / (1024 * 1024),
+        mean,
+        throughput
+      );
+    }
+  }
+}
@@ -0,0 +1,124 @@
+//! ssh-style escape sequences for interactive shells.
+
+/// Help message printed for the `?` command.
+pub(crate) const HELP: &str = "Supported escape sequences:\r
+  ~.  disconnect\r
+  ~?  this message\r
+  ~~  send the escape character\r
+(Note that escapes are only recognized immediately after a newline.)\r
+";
+
+/// Input, with escape sequences interpreted.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Escaped {
+  Data(Vec<u8>),
+  Disconnect,
+  Help,
+}
+
+/// Interprets escape sequences in input typed by the user.
+///
+/// The escape character is only recognized at the start of a line, so it can otherwise be typed as is.
+pub(crate) struct EscapeFilter {
+  escape_char: u8,
+  at_line_start: bool,
+  saw_escape: bool,
+}
+
+impl EscapeFilter {
+  pub(crate) fn new(escape_char: u8) -> EscapeFilter {
+    EscapeFilter {
+      escape_char,
+      at_line_start: true,
+      saw_escape: false,
+    }
+  }
+
+  /// Filters a chunk of input. Nothing after a disconnect is returned.
+  pub(crate) fn filter(&mut self, input: &[u8]) -> Vec<Escaped> {
+    let mut result = Vec::new();
+    let mut data = Vec::with_capacity(input.len());
+    for &c in input {
+      if self.saw_escape {
+        self.saw_escape = false;
+        let command = match c {
+          b'.' => Escaped::Disconnect,
+          b'?' => Escaped::Help,
+          c if c == self.escape_char => {
+            data.push(c);
+            self.at_line_start = false;
+            continue;
+          }
+          c => {
+            // Not an escape sequence after all, send it unmodified.
+            data.push(self.escape_char);
+            data.push(c);
+            self.at_line_start = c == b'\r' || c == b'\n';
+            continue;
+          }
+        };
+
+        if !data.is_empty() {
+          result.push(Escaped::Data(std::mem::take(&mut data)));
+        }
+
+        if command == Escaped::Disconnect {
+          result.push(command);
+          return result;
+        }
+
+        // The escape sequence didn't send anything, so we're still at the start of a line.
+        result.push(command);
+        self.at_line_start = true;
+      } else if self.at_line_start && c == self.escape_char {
+        self.saw_escape = true;
+      } else {
+        data.push(c);
+        self.at_line_start = c == b'\r' || c == b'\n';
+      }
+    }
+
+    if !data.is_empty() {
+      result.push(Escaped::Data(data));
+    }
+    result
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn data(s: &str) -> Escaped {
+    Escaped::Data(s.as_bytes().to_vec())
+  }
+
+  #[test]
+  fn escape_sequences() {
+    let mut filter = EscapeFilter::new(b'~');
+    assert_eq!(vec![data("ls ~/foo\r")], filter.filter(b"ls ~/foo\r"));
+    assert_eq!(vec![data("~")], filter.filter(b"~~"));
+    assert_eq!(vec![data("~.\r")], filter.filter(b"~.\r"));
+    assert_eq!(vec![data("~x")], filter.filter(b"~x"));
+    assert_eq!(
+      vec![data("\n"), Escaped::Help, data("ls\r")],
+      filter.filter(b"\n~?ls\r")
+    );
+    assert_eq!(
+      vec![data("echo\r"), Escaped::Disconnect],
+      filter.filter(b"echo\r~.ignored")
+    );
+  }
+
+  #[test]
+  fn split_across_reads() {
+    let mut filter = EscapeFilter::new(b'#');
+    assert!(filter.filter(b"#").is_empty());
+    assert_eq!(vec![Escaped::Disconnect], filter.filter(b"."));
+
+    let mut filter = EscapeFilter::new(b'#');
+    assert_eq!(vec![data("~")], filter.filter(b"~"));
+    assert_eq!(vec![data("\r")], filter.filter(b"\r#"));
+    assert_eq!(vec![data("#")], filter.filter(b"#"));
+  }
+}
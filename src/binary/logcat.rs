@@ -0,0 +1,198 @@
+//! Formatting of log entries like logcat's `-v` formats.
+
+use adb::client::logcat::{LogEntry, Priority};
+
+/// Layout of each line of output.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Format {
+  /// `P/tag(  pid): message`
+  Brief,
+
+  /// `MM-DD hh:mm:ss.mmm   pid   tid P tag     : message`
+  ThreadTime,
+}
+
+pub(crate) struct Formatter {
+  pub(crate) format: Format,
+
+  /// Whether to color lines by priority with ANSI escape sequences.
+  pub(crate) color: bool,
+}
+
+impl Formatter {
+  /// Formats an entry, with a line of output for every line of its message.
+  pub(crate) fn format(&self, entry: &LogEntry) -> String {
+    let priority = entry.message.priority();
+    let tag = entry.message.tag();
+    let message = entry.message.text();
+
+    let prefix = match self.format {
+      Format::Brief => format!("{}/{:<8}({:>5}): ", priority.letter(), tag, entry.pid),
+      Format::ThreadTime => format!(
+        "{}.{:03} {:>5} {:>5} {} {:<8}: ",
+        local_time(entry.sec),
+        entry.nsec / 1_000_000,
+        entry.pid,
+        entry.tid,
+        priority.letter(),
+        tag
+      ),
+    };
+
+    let (color_start, color_end) = if self.color {
+      (format!("\x1b[38;5;{}m", color(priority)), "\x1b[0m")
+    } else {
+      (String::new(), "")
+    };
+
+    let mut output = String::new();
+    for line in message.trim_end_matches('\n').split('\n') {
+      output.push_str(&color_start);
+      output.push_str(&prefix);
+      output.push_str(line);
+      output.push_str(color_end);
+      output.push('\n');
+    }
+    output
+  }
+}
+
+/// Returns the 256-color palette index that logcat uses for a priority.
+fn color(priority: Priority) -> u8 {
+  match priority {
+    Priority::Fatal | Priority::Error => 196,
+    Priority::Warn => 226,
+    Priority::Info => 40,
+    Priority::Debug => 75,
+    _ => 231,
+  }
+}
+
+/// Formats seconds since the epoch as `MM-DD hh:mm:ss` in the local timezone.
+#[cfg(not(windows))]
+pub(crate) fn local_time(sec: u32) -> String {
+  let time = sec as libc::time_t;
+  let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+  if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+    return utc_time(sec);
+  }
+  format!(
+    "{:02}-{:02} {:02}:{:02}:{:02}",
+    tm.tm_mon + 1,
+    tm.tm_mday,
+    tm.tm_hour,
+    tm.tm_min,
+    tm.tm_sec
+  )
+}
+
+/// Formats seconds since the epoch as `MM-DD hh:mm:ss`, in UTC, since there's no portable way to get the timezone.
+#[cfg(windows)]
+pub(crate) fn local_time(sec: u32) -> String {
+  utc_time(sec)
+}
+
+/// Formats seconds since the epoch as `MM-DD hh:mm:ss` in UTC.
+fn utc_time(sec: u32) -> String {
+  let days = sec / 86400;
+  let secs = sec % 86400;
+
+  // Convert days since the epoch to a civil date, with years starting in March so that leap days come last.
+  let days = days + 719_468;
+  let era = days / 146_097;
+  let day_of_era = days - era * 146_097;
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let month = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * month + 2) / 5 + 1;
+  let month = if month < 10 { month + 3 } else { month - 9 };
+
+  format!(
+    "{:02}-{:02} {:02}:{:02}:{:02}",
+    month,
+    day,
+    secs / 3600,
+    secs / 60 % 60,
+    secs % 60
+  )
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use adb::client::logcat::{EventValue, LogMessage};
+
+  fn entry(priority: Priority, message: &str) -> LogEntry {
+    LogEntry {
+      pid: 123,
+      tid: 456,
+      sec: 1_700_000_000,
+      nsec: 42_000_000,
+      buffer: None,
+      uid: None,
+      message: LogMessage::Text {
+        priority,
+        tag: "tag".into(),
+        message: message.into(),
+      },
+    }
+  }
+
+  #[test]
+  fn formats() {
+    let brief = Formatter {
+      format: Format::Brief,
+      color: false,
+    };
+    assert_eq!(
+      "I/tag     (  123): one\nI/tag     (  123): two\n",
+      brief.format(&entry(Priority::Info, "one\ntwo\n"))
+    );
+
+    let threadtime = Formatter {
+      format: Format::ThreadTime,
+      color: true,
+    };
+    assert_eq!(
+      format!(
+        "\x1b[38;5;196m{}.042   123   456 E tag     : oops\x1b[0m\n",
+        local_time(1_700_000_000)
+      ),
+      threadtime.format(&entry(Priority::Error, "oops"))
+    );
+  }
+
+  #[test]
+  fn events() {
+    use adb::client::logcat::{Event, EventField};
+
+    let mut entry = entry(Priority::Info, "");
+    entry.message = LogMessage::Event(Event {
+      tag: 2722,
+      name: Some("battery_level".into()),
+      fields: vec![
+        EventField {
+          name: Some("level".into()),
+          value: EventValue::Int(95),
+        },
+        EventField {
+          name: None,
+          value: EventValue::String("foo".into()),
+        },
+      ],
+    });
+
+    let brief = Formatter {
+      format: Format::Brief,
+      color: false,
+    };
+    assert_eq!("I/battery_level(  123): [95,foo]\n", brief.format(&entry));
+  }
+
+  #[test]
+  fn utc_times() {
+    assert_eq!("01-01 00:00:00", utc_time(0));
+    assert_eq!("11-14 22:13:20", utc_time(1_700_000_000));
+    assert_eq!("02-29 12:00:00", utc_time(1_709_208_000));
+  }
+}
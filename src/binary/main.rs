@@ -1,5 +1,3 @@
-#![feature(async_await)]
-
 macro_rules! fatal {
   ($($tt:tt)*) => {{
     use std::io::Write;
@@ -9,28 +7,29 @@ macro_rules! fatal {
   }}
 }
 
-#[cfg(not(feature = "client-binary"))]
-fn main() {
-  eprintln!("adb client-binary feature not enabled");
-  std::process::exit(1)
-}
+mod escape;
+mod logcat;
+mod terminal;
 
-#[cfg(feature = "client-binary")]
 fn main() -> adb::Result<()> {
   client::main()
 }
 
-#[cfg(feature = "client-binary")]
 mod client {
+  use adb::client::capture::Capture;
+  use adb::client::Remote;
   use adb::core::*;
   use adb::host::*;
   use clap::{clap_app, crate_version};
 
-  use futures::executor::{self, ThreadPool};
+  use crate::terminal;
+  use adb::runtime;
+  use futures::channel::mpsc;
   use futures::future;
   use futures::future::Either;
   use futures::io::{AsyncReadExt, AsyncWriteExt};
-  use futures::task::SpawnExt;
+  use futures::sink::SinkExt;
+  use futures::stream::{self, StreamExt};
 
   pub(crate) fn main() -> adb::Result<()> {
     let app = clap_app!(("adb-rs") =>
@@ -60,6 +59,12 @@ mod client {
       (@arg HOST: -H +takes_value display_order(5) conflicts_with("SPEC") "hostname of adb server")
       (@arg PORT: -P +takes_value display_order(6) conflicts_with("SPEC") "port of adb server")
       (@arg SPEC: -L +takes_value display_order(7) "socket specification of adb server")
+      (@arg VERBOSE: -v +multiple display_order(8) "log to stderr (multiple for more detail, overrides $ADB_TRACE)")
+      (@arg ALL_DEVICES: --("all-devices") display_order(9)
+        conflicts_with_all(&["DEVICE_SELECT_USB", "DEVICE_SELECT_TCP", "DEVICE_SELECT_SERIAL",
+                             "DEVICE_SELECT_TRANSPORT_ID"])
+        "run shell commands on all online devices"
+      )
 
       (@subcommand version =>
         (about: "display version information")
@@ -80,6 +85,60 @@ mod client {
         (@arg COMMAND: ... "command to run")
       )
 
+      (@subcommand logcat =>
+        (about: "show the device log")
+        (@arg BUFFER: -b +takes_value +multiple number_of_values(1)
+          "log buffer to read (main, system, crash, events, etc., or all), may be repeated or comma-separated")
+        (@arg DUMP: -d "dump the log and exit, instead of waiting for new entries")
+        (@arg START: -T +takes_value "start at a number of recent entries, or a time ('MM-DD hh:mm:ss.mmm' or 'sec.nsec')")
+        (@arg FORMAT: -v +takes_value +multiple number_of_values(1)
+          possible_values(&["brief", "threadtime", "color"]) "output format, may be combined with color")
+        (@arg WAIT_FOR: --("wait-for") +takes_value value_names(&["REGEX"])
+          "wait for an entry whose message matches a regex, print it, and exit")
+        (@arg TIMEOUT: --timeout +takes_value value_names(&["SECONDS"]) requires("WAIT_FOR")
+          "fail if no entry matches within a number of seconds")
+        (@arg FILTERSPEC: ... "filters like 'tag:priority' or '*:S'")
+      )
+
+      (@subcommand install =>
+        (about: "push a package to the device and install it")
+        (@arg REPLACE: -r "replace existing application")
+        (@arg ALLOW_TEST: -t "allow test packages")
+        (@arg ALLOW_DOWNGRADE: -d "allow version code downgrade")
+        (@arg GRANT_PERMISSIONS: -g "grant all runtime permissions")
+        (@arg USER: --user +takes_value "install for a user")
+        (@arg PACKAGE: +required "APK file to install, or .apks or .xapk bundle to install the device's splits from")
+      )
+
+      (@subcommand install_multiple =>
+        (name: "install-multiple")
+        (about: "push the APKs of a single package (e.g. splits) to the device and install them atomically")
+        (@arg REPLACE: -r "replace existing application")
+        (@arg ALLOW_TEST: -t "allow test packages")
+        (@arg ALLOW_DOWNGRADE: -d "allow version code downgrade")
+        (@arg GRANT_PERMISSIONS: -g "grant all runtime permissions")
+        (@arg USER: --user +takes_value "install for a user")
+        (@arg PACKAGES: +required ... "APK files to install")
+      )
+
+      (@subcommand install_multi_package =>
+        (name: "install-multi-package")
+        (about: "push several packages to the device and install them atomically")
+        (@arg REPLACE: -r "replace existing application")
+        (@arg ALLOW_TEST: -t "allow test packages")
+        (@arg ALLOW_DOWNGRADE: -d "allow version code downgrade")
+        (@arg GRANT_PERMISSIONS: -g "grant all runtime permissions")
+        (@arg USER: --user +takes_value "install for a user")
+        (@arg PACKAGES: +required ... "APK files to install, one per package")
+      )
+
+      (@subcommand uninstall =>
+        (about: "remove this app package from the device")
+        (@arg KEEP_DATA: -k "keep the data and cache directories")
+        (@arg USER: --user +takes_value "uninstall for a user")
+        (@arg PACKAGE: +required "package name to uninstall")
+      )
+
       (@subcommand raw =>
         (about: "directly connect to a service")
         (@arg RAW_TERMINAL: -r "switch the terminal to raw mode")
@@ -89,6 +148,8 @@ mod client {
     );
 
     let matches = app.get_matches();
+    init_tracing(matches.occurrences_of("VERBOSE"));
+
     let criteria = if matches.is_present("DEVICE_SELECT_USB") {
       DeviceCriteria::Usb
     } else if matches.is_present("DEVICE_SELECT_TCP") {
@@ -119,71 +180,218 @@ mod client {
       SocketSpec::tcp(Some(host.into()), port)
     };
 
-    let result = || -> Result<i32> {
-      executor::block_on(async {
-        match matches.subcommand() {
-          ("version", Some(_)) => cmd_version(server_address).await,
-          ("devices", Some(submatches)) => cmd_devices(server_address, submatches.is_present("LONG")).await,
+    let mut remote = Remote::new(server_address);
+    match Capture::from_env() {
+      Ok(capture) => remote.set_capture(capture),
+      Err(err) => fatal!("failed to set up traffic capture: {}", err),
+    };
 
-          ("raw", Some(submatches)) => {
-            let service = submatches.value_of("SERVICE").unwrap();
-            let raw_terminal = submatches.is_present("RAW_TERMINAL");
-            cmd_raw(server_address, criteria, service, raw_terminal).await
-          }
+    let result: Result<i32> = runtime::block_on(async {
+      match matches.subcommand() {
+        ("version", Some(_)) => cmd_version(remote).await,
+        ("devices", Some(submatches)) => cmd_devices(remote, submatches.is_present("LONG")).await,
+
+        ("logcat", Some(submatches)) => {
+          use adb::client::logcat::LogcatBuilder;
+
+          let mut builder = LogcatBuilder::new();
+          for buffer in submatches
+            .values_of("BUFFER")
+            .into_iter()
+            .flatten()
+            .flat_map(|b| b.split(','))
+          {
+            let binary = if buffer == "all" {
+              builder.all_buffers(true);
+              true
+            } else {
+              let buffer: adb::client::logcat::LogBuffer = buffer.parse().unwrap_or_else(|err| fatal!("{}", err));
+              builder.buffer(buffer);
+              buffer.is_binary()
+            };
 
-          ("shell", Some(submatches)) => {
-            if let Some(_escape_char) = submatches.value_of("ESCAPE_CHAR") {
-              fatal!("shell -e unimplemented");
+            // Only look up the names of events if there might be some.
+            if binary {
+              builder.load_event_tags(true);
             }
+          }
 
-            if submatches.is_present("NO_STDIN") {
-              fatal!("shell -n unimplemented");
-            }
+          if let Some(start) = submatches.value_of("START") {
+            builder.start(Some(start.parse().unwrap_or_else(|err| fatal!("{}", err))));
+          }
 
-            let raw = submatches.is_present("RAW");
-            let command = submatches.values_of("COMMAND").map(|cmd| cmd.collect());
+          for filterspec in submatches.values_of("FILTERSPEC").into_iter().flatten() {
+            builder.filterspec(filterspec);
+          }
+          builder.dump(submatches.is_present("DUMP"));
 
-            let tty = if submatches.is_present("DISABLE_PTY") {
-              false
+          let formats: Vec<&str> = submatches.values_of("FORMAT").into_iter().flatten().collect();
+          let formatter = crate::logcat::Formatter {
+            format: if formats.contains(&"brief") {
+              crate::logcat::Format::Brief
             } else {
-              let occurences = submatches.occurrences_of("FORCE_PTY");
-              if occurences > 1 {
-                true
-              } else if occurences == 1 {
-                // TODO: Check isatty.
-                eprintln!("warning: forcing tty allocation without checking isatty");
-                true
-              } else {
-                !command.is_some()
-              }
-            };
+              crate::logcat::Format::ThreadTime
+            },
+            color: formats.contains(&"color"),
+          };
+
+          let wait_for = submatches.value_of("WAIT_FOR").map(|regex| {
+            let mut matcher = adb::client::logcat::LogMatcher::new();
+            matcher.regex(regex::Regex::new(regex).unwrap_or_else(|err| fatal!("invalid regex: {}", err)));
+            let timeout = submatches.value_of("TIMEOUT").map(|timeout| {
+              timeout
+                .parse()
+                .ok()
+                .and_then(|secs| std::time::Duration::try_from_secs_f64(secs).ok())
+                .unwrap_or_else(|| fatal!("failed to parse timeout '{}'", timeout))
+            });
+            (matcher, timeout)
+          });
+          cmd_logcat(remote, criteria, builder, formatter, wait_for).await
+        }
 
-            cmd_shell(server_address, criteria, command, tty, raw).await
+        ("install", Some(submatches)) => {
+          let path = submatches.value_of("PACKAGE").unwrap();
+          let options = install_options(submatches);
+          if adb::client::install::is_bundle(path) {
+            remote.install_bundle(criteria, path, &options).await?;
+          } else {
+            remote.install(criteria, path, &options).await?;
           }
+          println!("Success");
+          Ok(0)
+        }
 
-          (cmd, None) => fatal!("mismatched command {}", cmd),
-          (cmd, Some(_)) => fatal!("unhandled command {}", cmd),
+        ("install-multiple", Some(submatches)) => {
+          let paths: Vec<&str> = submatches.values_of("PACKAGES").unwrap().collect();
+          remote
+            .install_multiple(criteria, &paths, &install_options(submatches))
+            .await?;
+          println!("Success");
+          Ok(0)
+        }
+
+        ("install-multi-package", Some(submatches)) => {
+          let paths: Vec<&str> = submatches.values_of("PACKAGES").unwrap().collect();
+          remote
+            .install_multi_package(criteria, &paths, &install_options(submatches))
+            .await?;
+          println!("Success");
+          Ok(0)
+        }
+
+        ("uninstall", Some(submatches)) => {
+          let options = adb::client::install::UninstallOptions {
+            keep_data: submatches.is_present("KEEP_DATA"),
+            user: submatches.value_of("USER").map(String::from),
+          };
+          remote
+            .uninstall(criteria, submatches.value_of("PACKAGE").unwrap(), &options)
+            .await?;
+          println!("Success");
+          Ok(0)
+        }
+
+        ("raw", Some(submatches)) => {
+          let service = submatches.value_of("SERVICE").unwrap();
+          let raw_terminal = submatches.is_present("RAW_TERMINAL");
+          cmd_raw(remote, criteria, service, raw_terminal).await
         }
-      })
-    }();
+
+        ("shell", Some(submatches)) => {
+          let escape_char = match submatches.value_of("ESCAPE_CHAR") {
+            None => Some(b'~'),
+            Some("none") => None,
+            Some(c) if c.len() == 1 => Some(c.as_bytes()[0]),
+            Some(c) => fatal!("-e requires a single-character argument or 'none', got '{}'", c),
+          };
+
+          let no_stdin = submatches.is_present("NO_STDIN");
+          let raw = submatches.is_present("RAW");
+          let command: Option<Vec<&str>> = submatches.values_of("COMMAND").map(|cmd| cmd.collect());
+
+          if matches.is_present("ALL_DEVICES") {
+            match command {
+              Some(command) => return cmd_shell_all_devices(remote, command).await,
+              None => fatal!("--all-devices requires a command"),
+            }
+          }
+
+          // Like adb, a pty is allocated for interactive shells when stdin is a terminal, or when forced with -tt.
+          // With -n, stdin is effectively /dev/null, which isn't a terminal.
+          let stdin_is_tty = !no_stdin && terminal::stdin_is_tty();
+          let tty = if submatches.is_present("DISABLE_PTY") {
+            false
+          } else {
+            let occurences = submatches.occurrences_of("FORCE_PTY");
+            if occurences > 1 {
+              true
+            } else if occurences == 1 {
+              if !stdin_is_tty {
+                eprintln!("Remote PTY will not be allocated because stdin is not a terminal.");
+                eprintln!("Use multiple -t options to force remote PTY allocation.");
+              }
+              stdin_is_tty
+            } else {
+              command.is_none() && stdin_is_tty
+            }
+          };
+
+          // Escape sequences are only useful when typing into an interactive terminal.
+          let escape_char = if tty && stdin_is_tty { escape_char } else { None };
+          let options = ShellOptions {
+            tty,
+            raw,
+            escape_char,
+            no_stdin,
+            local_raw_mode: tty && stdin_is_tty,
+          };
+          cmd_shell(remote, criteria, command, options).await
+        }
+
+        (cmd, None) => fatal!("mismatched command {}", cmd),
+        (cmd, Some(_)) => fatal!("unhandled command {}", cmd),
+      }
+    });
 
     match result {
       Ok(rc) => std::process::exit(rc),
-      Err(err) => fatal!("{:?}", err),
+      Err(err) => fatal!("{}", err),
     }
   }
 
-  async fn cmd_version(server: SocketSpec) -> Result<i32> {
+  /// Sets up logging to stderr, if requested with `-v` or `$ADB_TRACE`.
+  ///
+  /// `$ADB_TRACE` accepts either `all` (or `1`) to log everything, or a tracing filter directive like `adb=debug`.
+  fn init_tracing(verbosity: u64) {
+    let filter = match verbosity {
+      0 => match std::env::var("ADB_TRACE") {
+        Ok(ref value) if value == "all" || value == "1" => "adb=trace".to_string(),
+        Ok(ref value) if value.is_empty() => return,
+        Ok(value) => value,
+        Err(_) => return,
+      },
+      1 => "adb=debug".to_string(),
+      _ => "adb=trace".to_string(),
+    };
+
+    let filter = tracing_subscriber::EnvFilter::try_new(&filter)
+      .unwrap_or_else(|err| fatal!("failed to parse trace filter '{}': {}", filter, err));
+    tracing_subscriber::fmt()
+      .with_env_filter(filter)
+      .with_writer(std::io::stderr)
+      .init();
+  }
+
+  async fn cmd_version(remote: Remote) -> Result<i32> {
     println!("adb-rs {}", crate_version!());
-    let remote = adb::client::Remote::new(server.clone());
     if let Ok(version) = remote.version().await {
-      println!("Server version ({}): {}", server, version);
+      println!("Server version ({}): {}", remote.socket_spec(), version);
     }
     Ok(0)
   }
 
-  async fn cmd_devices(server: SocketSpec, long_output: bool) -> Result<i32> {
-    let remote = adb::client::Remote::new(server);
+  async fn cmd_devices(remote: Remote, long_output: bool) -> Result<i32> {
     let devices = remote.devices().await?;
 
     println!("List of devices attached");
@@ -217,31 +425,128 @@ mod client {
     Ok(0)
   }
 
+  /// Reads stdin on a separate thread, since it can't be read asynchronously portably.
+  ///
+  /// The stream ends after EOF or the first error.
+  fn stdin_stream() -> mpsc::Receiver<std::io::Result<Vec<u8>>> {
+    let (mut sender, receiver) = mpsc::channel(1);
+    std::thread::spawn(move || {
+      use std::io::Read;
+      let mut stdin = std::io::stdin();
+      let mut buf = [0u8; 2048];
+      loop {
+        let (result, done) = match stdin.read(&mut buf) {
+          Ok(len) => (Ok(buf[..len].to_vec()), len == 0),
+          Err(err) => (Err(err), true),
+        };
+
+        if futures::executor::block_on(futures::SinkExt::send(&mut sender, result)).is_err() || done {
+          break;
+        }
+      }
+    });
+    receiver
+  }
+
+  /// Returns the size of the terminal, if there is one.
   #[cfg(windows)]
-  fn scoped_raw_terminal(_: bool) -> Option<()> {
+  fn window_size() -> Option<adb::client::shell::ShellInput> {
     None
   }
 
+  /// Returns the size of the terminal, if there is one.
   #[cfg(not(windows))]
-  fn scoped_raw_terminal(enable: bool) -> Option<termion::raw::RawTerminal<std::io::Stdout>> {
-    if enable {
-      // FIXME: Raw is not quite what we want: it turns off \n -> \r\n processing.
-      use termion::raw::IntoRawMode;
-      Some(std::io::stdout().into_raw_mode().unwrap())
-    } else {
-      None
+  fn window_size() -> Option<adb::client::shell::ShellInput> {
+    let (cols, rows) = termion::terminal_size().ok()?;
+    let (xpixels, ypixels) = termion::terminal_size_pixels().unwrap_or((0, 0));
+    Some(adb::client::shell::ShellInput::WindowSizeChange {
+      rows,
+      cols,
+      xpixels,
+      ypixels,
+    })
+  }
+
+  /// Watches for changes of the size of the terminal (SIGWINCH), on a separate thread.
+  #[cfg(windows)]
+  fn window_size_changes() -> mpsc::Receiver<()> {
+    mpsc::channel(1).1
+  }
+
+  /// Watches for changes of the size of the terminal (SIGWINCH), on a separate thread.
+  #[cfg(not(windows))]
+  fn window_size_changes() -> mpsc::Receiver<()> {
+    let (mut sender, receiver) = mpsc::channel(1);
+    match signal_hook::iterator::Signals::new([signal_hook::consts::SIGWINCH]) {
+      Ok(mut signals) => {
+        std::thread::spawn(move || {
+          for _ in signals.forever() {
+            // Coalesce signals that arrive while the previous one is still being handled.
+            match sender.try_send(()) {
+              Err(err) if err.is_disconnected() => break,
+              _ => {}
+            }
+          }
+        });
+      }
+      Err(err) => eprintln!("warning: failed to watch for window size changes: {}", err),
     }
+    receiver
   }
 
-  async fn cmd_raw(
-    server: SocketSpec,
+  async fn cmd_logcat(
+    remote: Remote,
     device_criteria: DeviceCriteria,
-    service: &str,
-    raw_terminal: bool,
+    builder: adb::client::logcat::LogcatBuilder,
+    formatter: crate::logcat::Formatter,
+    wait_for: Option<(adb::client::logcat::LogMatcher, Option<std::time::Duration>)>,
   ) -> Result<i32> {
-    let mut pool = ThreadPool::new()?;
-    let remote = adb::client::Remote::new(server);
+    use std::io::Write;
 
+    let mut logcat = builder.connect(remote, device_criteria).await?;
+    if let Some((matcher, timeout)) = wait_for {
+      return match logcat.wait_for(&matcher, timeout, 0).await {
+        Ok(found) => {
+          print!("{}", formatter.format(&found.entry));
+          Ok(0)
+        }
+        Err(adb::Error::TimedOut(_)) => {
+          eprintln!("timed out waiting for a matching log entry");
+          Ok(1)
+        }
+        Err(adb::Error::Closed) => {
+          eprintln!("logcat exited without a matching log entry");
+          Ok(1)
+        }
+        Err(err) => Err(err),
+      };
+    }
+
+    while let Some(entry) = logcat.next().await {
+      let mut stdout = std::io::stdout().lock();
+      let result = stdout
+        .write_all(formatter.format(&entry?).as_bytes())
+        .and_then(|_| stdout.flush());
+      match result {
+        Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => break,
+        Err(err) => return Err(err.into()),
+        Ok(()) => {}
+      }
+    }
+    Ok(0)
+  }
+
+  fn install_options(matches: &clap::ArgMatches) -> adb::client::install::InstallOptions {
+    adb::client::install::InstallOptions {
+      replace: matches.is_present("REPLACE"),
+      allow_test: matches.is_present("ALLOW_TEST"),
+      allow_downgrade: matches.is_present("ALLOW_DOWNGRADE"),
+      grant_permissions: matches.is_present("GRANT_PERMISSIONS"),
+      user: matches.value_of("USER").map(String::from),
+    }
+  }
+
+  async fn cmd_raw(remote: Remote, device_criteria: DeviceCriteria, service: &str, raw_terminal: bool) -> Result<i32> {
     let channel = if service.starts_with("host:") {
       remote.open_channel(service).await?
     } else {
@@ -251,110 +556,251 @@ mod client {
 
     let (mut channel_read, mut channel_write) = channel.split();
 
-    let raw_terminal = scoped_raw_terminal(raw_terminal);
-    let read = pool
-      .spawn_with_handle(async move {
-        let mut stdout = futures::io::AllowStdIo::new(std::io::stdout());
-        let mut buf = [0u8; 2048];
-        loop {
-          match channel_read.read(&mut buf).await {
-            Ok(0) | Err(_) => break,
-            Ok(len) => {
-              let _ = stdout.write_all(&buf[..len]).await;
-              let _ = stdout.flush().await;
-            }
+    let raw_terminal = if raw_terminal { terminal::raw_mode() } else { None };
+    let read = runtime::spawn(async move {
+      let mut stdout = futures::io::AllowStdIo::new(std::io::stdout());
+      let mut buf = [0u8; 2048];
+      loop {
+        match channel_read.read(&mut buf).await {
+          Ok(0) | Err(_) => break,
+          Ok(len) => {
+            let _ = stdout.write_all(&buf[..len]).await;
+            let _ = stdout.flush().await;
           }
         }
-      })
-      .unwrap();
+      }
+    });
 
-    let write = pool
-      .spawn_with_handle(async move {
-        let mut stdin = futures::io::AllowStdIo::new(std::io::stdin());
-        let _ = stdin.copy_into(&mut channel_write).await;
-      })
-      .unwrap();
+    let write = runtime::spawn(async move {
+      let mut stdin = stdin_stream();
+      while let Some(Ok(data)) = stdin.next().await {
+        if data.is_empty() || channel_write.write_all(&data).await.is_err() {
+          break;
+        }
+      }
+    });
 
     future::select(read, write).await;
     drop(raw_terminal);
     Ok(0)
   }
 
+  /// Maximum number of devices that `--all-devices` runs commands on at once.
+  const ALL_DEVICES_CONCURRENCY: usize = 16;
+
+  /// Writes output to a stream, with every line prefixed by the serial of the device that it came from.
+  fn write_prefixed(out: &mut dyn std::io::Write, serial: &str, data: &[u8]) {
+    if data.is_empty() {
+      return;
+    }
+
+    let data = data.strip_suffix(b"\n").unwrap_or(data);
+    for line in data.split(|&c| c == b'\n') {
+      let _ = write!(out, "{}: ", serial);
+      let _ = out.write_all(line);
+      let _ = out.write_all(b"\n");
+    }
+    let _ = out.flush();
+  }
+
+  async fn cmd_shell_all_devices(remote: Remote, command: Vec<&str>) -> Result<i32> {
+    use std::io::Write;
+
+    // Like adb, a single argument is a command line for the device's shell, and multiple arguments are quoted.
+    let argv: Vec<String> = match command.as_slice() {
+      [command] => vec!["sh".into(), "-c".into(), command.to_string()],
+      argv => argv.iter().map(|arg| arg.to_string()).collect(),
+    };
+
+    let devices = remote.online_devices().await?;
+    if devices.is_empty() {
+      return Err(adb::Error::NoDevices);
+    }
+
+    let options = adb::client::CommandOptions::default();
+
+    // Print the output of every device as soon as it's done, so that it isn't interleaved with other devices.
+    let results = remote
+      .fan_out(devices, ALL_DEVICES_CONCURRENCY, |device, device_criteria| {
+        let (remote, argv, options) = (&remote, &argv, &options);
+        async move {
+          let output = remote.shell_output_with(device_criteria, argv.clone(), options).await?;
+          write_prefixed(&mut std::io::stdout().lock(), &device.serial, &output.stdout);
+          write_prefixed(&mut std::io::stderr().lock(), &device.serial, &output.stderr);
+          Ok(output.exit_code)
+        }
+      })
+      .await;
+
+    let failures: Vec<_> = results
+      .iter()
+      .filter(|result| !matches!(result.result, Ok(0)))
+      .collect();
+    let mut stderr = std::io::stderr().lock();
+    let _ = writeln!(
+      stderr,
+      "{} devices: {} succeeded, {} failed",
+      results.len(),
+      results.len() - failures.len(),
+      failures.len()
+    );
+
+    for failure in &failures {
+      let _ = match &failure.result {
+        Ok(exit_code) => writeln!(stderr, "  {}: exit code {}", failure.device.serial, exit_code),
+        Err(err) => writeln!(stderr, "  {}: {}", failure.device.serial, err),
+      };
+    }
+
+    Ok(if failures.is_empty() { 0 } else { 1 })
+  }
+
+  struct ShellOptions {
+    /// Whether to allocate a pty on the device.
+    tty: bool,
+
+    /// Whether to disable the shell protocol.
+    raw: bool,
+
+    escape_char: Option<u8>,
+    no_stdin: bool,
+
+    /// Whether to put the local terminal into raw mode.
+    local_raw_mode: bool,
+  }
+
   async fn cmd_shell(
-    server: SocketSpec,
+    remote: Remote,
     device_criteria: DeviceCriteria,
     command: Option<Vec<&str>>,
-    tty: bool,
-    raw: bool,
+    options: ShellOptions,
   ) -> Result<i32> {
+    use crate::escape::{EscapeFilter, Escaped, HELP};
     use adb::client::shell::*;
 
-    let mut pool = ThreadPool::new()?;
-    let remote = adb::client::Remote::new(server);
+    // Like adb, a single argument is a command line for the device's shell, and multiple arguments are quoted.
+    let mut shell_builder = ShellBuilder::new();
+    match command.as_deref() {
+      None => shell_builder.command(None),
+      Some([command]) => shell_builder.raw_command(Some(command.to_string())),
+      Some(argv) => shell_builder.command(Some(argv.iter().map(|s| s.to_string()).collect())),
+    };
 
-    let command = command.map(|vec| vec.iter().map(|s| s.to_string()).collect());
-    let mut shell_builder = Shell::builder();
+    // Like adb, fall back to a raw shell if the device doesn't support the shell protocol. The device is resolved
+    // first, so that the shell is opened on the same one whose features were checked.
+    let device_criteria = DeviceCriteria::TransportId(remote.transport_id(device_criteria).await?);
+    let shell_protocol = !options.raw
+      && remote
+        .device_features(device_criteria.clone())
+        .await?
+        .iter()
+        .any(|feature| feature == "shell_v2");
+
+    // Without the shell protocol, closing stdin closes the whole channel, so -n is handled by the shell instead.
     let shell = shell_builder
-      .command(command)
-      .shell_protocol(!raw)
+      .shell_protocol(shell_protocol)
       .term(std::env::var("TERM").ok())
-      .tty(tty)
+      .tty(options.tty)
+      .no_stdin(options.no_stdin)
       .connect(remote, device_criteria)
       .await?;
 
-    let raw_terminal = scoped_raw_terminal(tty);
+    let raw_terminal = if options.local_raw_mode {
+      terminal::raw_mode()
+    } else {
+      None
+    };
     let (mut read, mut write) = shell.split();
 
-    let reader = pool
-      .spawn_with_handle(async move {
-        let mut stdout = futures::io::AllowStdIo::new(std::io::stdout());
-        let mut stderr = futures::io::AllowStdIo::new(std::io::stderr());
-        loop {
-          match read.read().await {
-            Ok(event) => match event {
-              ShellOutput::Stdout(data) => {
-                let _ = stdout.write_all(&data).await;
-                let _ = stdout.flush().await;
-              }
-
-              ShellOutput::Stderr(data) => {
-                let _ = stderr.write_all(&data).await;
-                let _ = stderr.flush().await;
-              }
-
-              ShellOutput::Exit(exit_code) => return Ok(exit_code),
-            },
+    let reader = runtime::spawn(async move {
+      let mut stdout = futures::io::AllowStdIo::new(std::io::stdout());
+      let mut stderr = futures::io::AllowStdIo::new(std::io::stderr());
+      loop {
+        match read.next().await.unwrap_or(Err(adb::Error::Closed)) {
+          Ok(event) => match event {
+            ShellOutput::Stdout(data) => {
+              let _ = stdout.write_all(&data).await;
+              let _ = stdout.flush().await;
+            }
 
-            Err(err) => {
-              return Err(err);
+            ShellOutput::Stderr(data) => {
+              let _ = stderr.write_all(&data).await;
+              let _ = stderr.flush().await;
             }
-          };
-        }
-      })
-      .unwrap();
-
-    let writer = pool
-      .spawn_with_handle(async move {
-        let mut stdin = futures::io::AllowStdIo::new(std::io::stdin());
-        let mut buf = [0u8; 2048];
-        loop {
-          let event = match stdin.read(&mut buf).await {
-            Ok(0) => ShellInput::CloseStdin,
-            Ok(len) => ShellInput::Stdin(buf[..len].to_vec()),
-            Err(err) => return adb::Error::IoError(err),
-          };
 
-          if let Err(err) = write.write(event).await {
-            return err;
+            ShellOutput::Exit(exit_code) => return Ok(exit_code),
+          },
+
+          Err(err) => {
+            return Err(err);
           }
-        }
-      })
-      .unwrap();
+        };
+      }
+    });
+
+    // Events are `None` when the user asked to disconnect.
+    let writer = runtime::spawn(async move {
+      let mut escape_filter = options.escape_char.map(EscapeFilter::new);
+      let stdin = if options.no_stdin {
+        stream::empty().boxed()
+      } else {
+        stdin_stream()
+          .flat_map(move |result| {
+            let events = match result {
+              // Only the shell protocol can close stdin without closing the output along with it.
+              Ok(ref data) if data.is_empty() && !shell_protocol => vec![],
+              Ok(ref data) if data.is_empty() => vec![Ok(Some(ShellInput::CloseStdin))],
+              Ok(data) => match &mut escape_filter {
+                None => vec![Ok(Some(ShellInput::Stdin(data.into())))],
+                Some(filter) => filter
+                  .filter(&data)
+                  .into_iter()
+                  .filter_map(|escaped| match escaped {
+                    Escaped::Data(data) => Some(Ok(Some(ShellInput::Stdin(data.into())))),
+                    Escaped::Disconnect => Some(Ok(None)),
+                    Escaped::Help => {
+                      eprint!("{}", HELP);
+                      None
+                    }
+                  })
+                  .collect(),
+              },
+              Err(err) => vec![Err(adb::Error::IoError(err))],
+            };
+            stream::iter(events)
+          })
+          .boxed()
+      };
+
+      // The initial size is sent right away, and then again whenever it changes.
+      let window_sizes = if options.tty {
+        stream::once(future::ready(())).chain(window_size_changes()).boxed()
+      } else {
+        stream::empty().boxed()
+      };
+      let window_sizes = window_sizes.filter_map(|()| future::ready(window_size().map(|size| Ok(Some(size)))));
+
+      let mut events = stream::select(stdin, window_sizes);
+      loop {
+        let event = match events.next().await {
+          None => future::pending().await,
+          Some(Ok(Some(event))) => event,
+          Some(Ok(None)) => return Ok(()),
+          Some(Err(err)) => return Err(err),
+        };
+
+        write.send(event).await?;
+      }
+    });
 
     let rc = match future::select(reader, writer).await {
       Either::Left((Ok(rc), _)) => rc,
-      Either::Left((Err(err), _)) | Either::Right((err, _)) => {
-        eprintln!("fatal: failed to write: {:?}", err);
+      Either::Right((Ok(()), _)) => {
+        eprint!("\r\n[ disconnected ]\r\n");
+        0
+      }
+      Either::Left((Err(err), _)) | Either::Right((Err(err), _)) => {
+        eprintln!("fatal: failed to write: {}", err);
         1
       }
     };
@@ -0,0 +1,133 @@
+//! Local terminal setup for interactive shells.
+
+use std::io::IsTerminal;
+
+/// Returns whether stdin is a terminal.
+pub(crate) fn stdin_is_tty() -> bool {
+  std::io::stdin().is_terminal()
+}
+
+/// Guard that restores the terminal to its original mode when dropped.
+pub(crate) struct RawMode {
+  _private: (),
+}
+
+impl Drop for RawMode {
+  fn drop(&mut self) {
+    imp::restore();
+  }
+}
+
+/// Puts the terminal attached to stdin into the mode used by adb for interactive shells, if stdin is a terminal.
+///
+/// Echo, canonical input processing and signal generation are disabled, so that every keystroke (including ^C) goes
+/// to the device. Unlike a fully raw mode, output processing is left alone, so `\n` still moves to the start of the
+/// next line.
+///
+/// The original mode is restored when the returned guard is dropped, and also when the process panics or is killed
+/// by a signal.
+pub(crate) fn raw_mode() -> Option<RawMode> {
+  if !stdin_is_tty() {
+    return None;
+  }
+
+  match imp::enable() {
+    Ok(()) => Some(RawMode { _private: () }),
+    Err(err) => {
+      eprintln!("warning: failed to set terminal mode: {}", err);
+      None
+    }
+  }
+}
+
+#[cfg(windows)]
+mod imp {
+  pub(super) fn enable() -> std::io::Result<()> {
+    Err(std::io::Error::new(
+      std::io::ErrorKind::Unsupported,
+      "terminal mode is not supported on Windows",
+    ))
+  }
+
+  pub(super) fn restore() {}
+}
+
+#[cfg(not(windows))]
+mod imp {
+  use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+  use std::sync::{Mutex, Once};
+
+  /// The original mode of the terminal, while it has been changed.
+  static SAVED: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+  pub(super) fn enable() -> std::io::Result<()> {
+    install_handlers();
+
+    let mut saved = SAVED.lock().unwrap();
+    let mut tio = std::mem::MaybeUninit::<libc::termios>::uninit();
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, tio.as_mut_ptr()) } != 0 {
+      return Err(std::io::Error::last_os_error());
+    }
+
+    let original = unsafe { tio.assume_init() };
+    let mut tio = original;
+
+    // Disable echoing, canonical mode, extended input processing, and signal generation.
+    tio.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+
+    // Disable CR to NL translation, parity checking, stripping of the eighth bit, and flow control.
+    tio.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+
+    // Return from reads as soon as a byte is available.
+    tio.c_cc[libc::VMIN] = 1;
+    tio.c_cc[libc::VTIME] = 0;
+
+    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &tio) } != 0 {
+      return Err(std::io::Error::last_os_error());
+    }
+
+    // Keep the outermost original mode, in case this is called more than once.
+    if saved.is_none() {
+      *saved = Some(original);
+    }
+    Ok(())
+  }
+
+  pub(super) fn restore() {
+    // This can be called from the panic hook, so avoid panicking again if the lock is poisoned.
+    let mut saved = match SAVED.lock() {
+      Ok(saved) => saved,
+      Err(poisoned) => poisoned.into_inner(),
+    };
+
+    if let Some(tio) = saved.take() {
+      unsafe {
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &tio);
+      }
+    }
+  }
+
+  /// Makes sure that the terminal is restored if the process panics or is killed by a signal.
+  fn install_handlers() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+      let hook = std::panic::take_hook();
+      std::panic::set_hook(Box::new(move |info| {
+        restore();
+        hook(info);
+      }));
+
+      match signal_hook::iterator::Signals::new([SIGHUP, SIGINT, SIGQUIT, SIGTERM]) {
+        Ok(mut signals) => {
+          std::thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+              restore();
+              let _ = signal_hook::low_level::emulate_default_handler(signal);
+            }
+          });
+        }
+        Err(err) => eprintln!("warning: failed to install signal handlers: {}", err),
+      }
+    });
+  }
+}
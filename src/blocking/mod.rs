@@ -0,0 +1,425 @@
+//! Synchronous wrappers around the client API, for callers that don't otherwise use async code.
+//!
+//! Every operation blocks the calling thread until it completes, driving the underlying futures with
+//! [runtime::block_on](crate::runtime::block_on). None of these functions may be called from within an async context.
+//!
+//! ```no_run
+//! use adb::blocking::Remote;
+//! use adb::host::DeviceCriteria;
+//! use std::io::Read;
+//!
+//! let mut shell = Remote::default().shell(DeviceCriteria::Any, "getprop")?;
+//! let mut output = String::new();
+//! shell.read_to_string(&mut output)?;
+//! assert_eq!(0, shell.wait()?);
+//! # Ok::<(), adb::Error>(())
+//! ```
+
+use bytes::{Buf, Bytes};
+use futures::io::AllowStdIo;
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate as adb;
+use crate::client::capture::Capture;
+use crate::client::shell::{Shell as AsyncShell, ShellBuilder, ShellInput, ShellOutput};
+use crate::client::sync::{DirEntry, SyncClient as AsyncSyncClient};
+use crate::client::{self, CommandOptions, CommandOutput, RetryPolicy, Timeouts};
+use crate::core::sync::{SyncStatV1, SyncStatV2};
+use crate::core::SocketSpec;
+use crate::host::{DeviceCriteria, DeviceDescription, TransportId};
+use crate::runtime::block_on;
+
+/// Blocking version of [client::Remote].
+#[derive(Clone, Default)]
+pub struct Remote {
+  inner: client::Remote,
+}
+
+impl Remote {
+  /// Constructs a new `Remote`.
+  pub fn new(socket_spec: SocketSpec) -> Remote {
+    Remote {
+      inner: client::Remote::new(socket_spec),
+    }
+  }
+
+  /// Returns the location of the adb server.
+  pub fn socket_spec(&self) -> &SocketSpec {
+    self.inner.socket_spec()
+  }
+
+  /// See [client::Remote::set_capture].
+  pub fn set_capture(&mut self, capture: Option<Capture>) -> &mut Remote {
+    self.inner.set_capture(capture);
+    self
+  }
+
+  /// See [client::Remote::set_timeouts].
+  pub fn set_timeouts(&mut self, timeouts: Timeouts) -> &mut Remote {
+    self.inner.set_timeouts(timeouts);
+    self
+  }
+
+  /// See [client::Remote::set_retry_policy].
+  pub fn set_retry_policy(&mut self, retry_policy: Option<RetryPolicy>) -> &mut Remote {
+    self.inner.set_retry_policy(retry_policy);
+    self
+  }
+
+  /// Returns the underlying async [client::Remote].
+  pub fn as_async(&self) -> &client::Remote {
+    &self.inner
+  }
+
+  /// Get the server's protocol version.
+  pub fn version(&self) -> adb::Result<u32> {
+    block_on(self.inner.version())
+  }
+
+  /// Lists the devices connected to the server.
+  pub fn devices(&self) -> adb::Result<Vec<DeviceDescription>> {
+    block_on(self.inner.devices())
+  }
+
+  /// Starts tracking the devices connected to the server. See [client::Remote::track_devices].
+  pub fn track_devices(&self) -> adb::Result<DeviceTracker> {
+    let inner = block_on(self.inner.track_devices())?;
+    Ok(DeviceTracker { inner: Some(inner) })
+  }
+
+  /// Gets the features supported by a device. See [client::Remote::device_features].
+  pub fn device_features(&self, device_criteria: DeviceCriteria) -> adb::Result<Vec<String>> {
+    block_on(self.inner.device_features(device_criteria))
+  }
+
+  /// Runs a command and captures its output. See [client::Remote::shell_output].
+  pub fn shell_output(
+    &self,
+    device_criteria: DeviceCriteria,
+    argv: impl IntoIterator<Item = impl Into<String>>,
+  ) -> adb::Result<CommandOutput> {
+    block_on(self.inner.shell_output(device_criteria, argv))
+  }
+
+  /// Runs a command and captures its output, failing if it exits with a non-zero exit code. See
+  /// [client::Remote::shell_output_checked].
+  pub fn shell_output_checked(
+    &self,
+    device_criteria: DeviceCriteria,
+    argv: impl IntoIterator<Item = impl Into<String>>,
+  ) -> adb::Result<CommandOutput> {
+    block_on(self.inner.shell_output_checked(device_criteria, argv))
+  }
+
+  /// Runs a command and captures its output, with [CommandOptions]. See [client::Remote::shell_output_with].
+  pub fn shell_output_with(
+    &self,
+    device_criteria: DeviceCriteria,
+    argv: impl IntoIterator<Item = impl Into<String>>,
+    options: &CommandOptions,
+  ) -> adb::Result<CommandOutput> {
+    block_on(self.inner.shell_output_with(device_criteria, argv, options))
+  }
+
+  /// Runs a command with the shell protocol, without a pty.
+  ///
+  /// The command is passed to the device's shell as is, so it can contain pipes, redirections, etc.
+  pub fn shell(&self, device_criteria: DeviceCriteria, command: impl AsRef<str>) -> adb::Result<Shell> {
+    let mut builder = ShellBuilder::new();
+    builder
+      .raw_command(Some(command.as_ref().to_string()))
+      .shell_protocol(true)
+      .tty(false);
+    self.connect_shell(&builder, device_criteria)
+  }
+
+  /// Connects to a shell configured with a [ShellBuilder].
+  pub fn connect_shell(&self, builder: &ShellBuilder, device_criteria: DeviceCriteria) -> adb::Result<Shell> {
+    let inner = block_on(builder.connect(self.inner.clone(), device_criteria))?;
+    Ok(Shell {
+      inner,
+      stdout: VecDeque::new(),
+      stderr: Vec::new(),
+      exit_code: None,
+    })
+  }
+
+  /// Opens a file synchronization session on a device.
+  pub fn sync(&self, device_criteria: DeviceCriteria) -> adb::Result<SyncClient> {
+    let inner = block_on(AsyncSyncClient::connect(self.inner.clone(), device_criteria))?;
+    Ok(SyncClient { inner })
+  }
+}
+
+impl From<client::Remote> for Remote {
+  fn from(inner: client::Remote) -> Remote {
+    Remote { inner }
+  }
+}
+
+/// Blocking version of [client::DeviceTracker].
+///
+/// Iterating yields the list of devices every time it changes, until an error occurs.
+pub struct DeviceTracker {
+  inner: Option<client::DeviceTracker>,
+}
+
+impl Iterator for DeviceTracker {
+  type Item = adb::Result<Vec<DeviceDescription>>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let result = block_on(self.inner.as_mut()?.next());
+    if result.is_err() {
+      self.inner = None;
+    }
+    Some(result)
+  }
+}
+
+/// A running shell command.
+///
+/// Reading from a `Shell` returns the command's stdout, and writing to it sends data to the command's stdin. Output
+/// on stderr is accumulated separately, and can be retrieved with [Shell::stderr].
+pub struct Shell {
+  inner: Box<dyn AsyncShell>,
+  stdout: VecDeque<Bytes>,
+  stderr: Vec<u8>,
+  exit_code: Option<u8>,
+}
+
+impl Shell {
+  /// Reads the next chunk of output from the shell, buffering it.
+  ///
+  /// Returns false once the command has exited.
+  fn poll_output(&mut self) -> adb::Result<bool> {
+    if self.exit_code.is_some() {
+      return Ok(false);
+    }
+
+    match block_on(self.inner.next()).ok_or(adb::Error::Closed)?? {
+      ShellOutput::Stdout(data) => self.stdout.push_back(data),
+      ShellOutput::Stderr(data) => self.stderr.extend_from_slice(&data),
+      ShellOutput::Exit(exit_code) => self.exit_code = Some(exit_code),
+    }
+    Ok(true)
+  }
+
+  /// Closes the command's stdin.
+  pub fn close_stdin(&mut self) -> adb::Result<()> {
+    block_on(self.inner.send(ShellInput::CloseStdin))
+  }
+
+  /// Returns the output the command has written to stderr so far.
+  pub fn stderr(&self) -> &[u8] {
+    &self.stderr
+  }
+
+  /// Waits for the command to exit, and returns its exit code.
+  ///
+  /// Output that hasn't been read yet is buffered, and can still be read afterwards.
+  pub fn wait(&mut self) -> adb::Result<u8> {
+    while self.poll_output()? {}
+    Ok(self.exit_code.expect("shell exited without an exit code"))
+  }
+
+  /// Returns the command's exit code, if it has exited.
+  pub fn exit_code(&self) -> Option<u8> {
+    self.exit_code
+  }
+}
+
+impl Read for Shell {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let chunk = loop {
+      match self.stdout.front_mut() {
+        Some(chunk) if chunk.is_empty() => {
+          self.stdout.pop_front();
+        }
+        Some(chunk) => break chunk,
+        None => {
+          if !self.poll_output()? {
+            return Ok(0);
+          }
+        }
+      }
+    };
+
+    let len = std::cmp::min(chunk.len(), buf.len());
+    buf[..len].copy_from_slice(&chunk[..len]);
+    chunk.advance(len);
+    Ok(len)
+  }
+}
+
+impl Write for Shell {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    block_on(self.inner.send(ShellInput::Stdin(Bytes::copy_from_slice(buf))))?;
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
+}
+
+/// Blocking version of [client::sync::SyncClient].
+pub struct SyncClient {
+  inner: AsyncSyncClient,
+}
+
+impl SyncClient {
+  /// Returns the id of the transport of the device that the session is connected to.
+  pub fn transport_id(&self) -> TransportId {
+    self.inner.transport_id()
+  }
+
+  /// See [client::sync::SyncClient::stat].
+  pub fn stat(&mut self, path: &str) -> adb::Result<SyncStatV1> {
+    block_on(self.inner.stat(path))
+  }
+
+  /// See [client::sync::SyncClient::stat_v2].
+  pub fn stat_v2(&mut self, path: &str) -> adb::Result<SyncStatV2> {
+    block_on(self.inner.stat_v2(path))
+  }
+
+  /// See [client::sync::SyncClient::lstat_v2].
+  pub fn lstat_v2(&mut self, path: &str) -> adb::Result<SyncStatV2> {
+    block_on(self.inner.lstat_v2(path))
+  }
+
+  /// See [client::sync::SyncClient::list].
+  pub fn list(&mut self, path: &str) -> adb::Result<Vec<DirEntry>> {
+    block_on(self.inner.list(path))
+  }
+
+  /// Sends the contents of `source` to a file on the device. See [client::sync::SyncClient::push].
+  pub fn push(&mut self, source: impl Read, path: &str, mode: u32, mtime: u32) -> adb::Result<u64> {
+    block_on(self.inner.push(AllowStdIo::new(source), path, mode, mtime))
+  }
+
+  /// Receives the contents of a file on the device. See [client::sync::SyncClient::pull].
+  pub fn pull(&mut self, path: &str, destination: impl Write) -> adb::Result<u64> {
+    block_on(self.inner.pull(path, AllowStdIo::new(destination)))
+  }
+
+  /// Sends a local file to the device, preserving its permissions and modification time.
+  pub fn push_file(&mut self, local_path: impl AsRef<Path>, remote_path: &str) -> adb::Result<u64> {
+    let file = std::fs::File::open(local_path)?;
+    let metadata = file.metadata()?;
+    let mtime = metadata
+      .modified()?
+      .duration_since(std::time::UNIX_EPOCH)
+      .map(|d| d.as_secs() as u32)
+      .unwrap_or(0);
+    self.push(file, remote_path, file_mode(&metadata), mtime)
+  }
+
+  /// Receives a file from the device, creating or truncating the local file.
+  pub fn pull_file(&mut self, remote_path: &str, local_path: impl AsRef<Path>) -> adb::Result<u64> {
+    let file = std::fs::File::create(local_path)?;
+    self.pull(remote_path, file)
+  }
+
+  /// Ends the session.
+  pub fn quit(self) -> adb::Result<()> {
+    block_on(self.inner.quit())
+  }
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+  use std::os::unix::fs::PermissionsExt;
+  metadata.permissions().mode()
+}
+
+#[cfg(not(unix))]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+  const S_IFREG: u32 = 0o100000;
+  if metadata.permissions().readonly() {
+    S_IFREG | 0o444
+  } else {
+    S_IFREG | 0o644
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::testing::{device, FakeServer};
+
+  #[test]
+  fn shell() {
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1));
+    server.on_shell("getprop", b"[ro.product.model]: [Fake_Model]\n", b"warning\n", 0);
+
+    let remote = Remote::from(server.remote());
+    let mut shell = remote.shell(DeviceCriteria::Any, "getprop").unwrap();
+    let mut output = String::new();
+    shell.read_to_string(&mut output).unwrap();
+    assert_eq!("[ro.product.model]: [Fake_Model]\n", output);
+    assert_eq!(0, shell.wait().unwrap());
+    assert_eq!(b"warning\n", shell.stderr());
+
+    server.assert_device_services(&["shell,v2,raw:getprop"]);
+  }
+
+  #[test]
+  fn devices_and_tracking() {
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1));
+
+    let remote = Remote::from(server.remote());
+    assert_eq!(1, remote.devices().unwrap().len());
+
+    let mut tracker = remote.track_devices().unwrap();
+    let devices = tracker.next().unwrap().unwrap();
+    assert_eq!("foo", devices[0].serial);
+
+    server.add_device(device("bar", 2));
+    let devices = tracker.next().unwrap().unwrap();
+    assert_eq!(
+      vec!["foo", "bar"],
+      devices.iter().map(|d| d.serial.as_str()).collect::<Vec<_>>()
+    );
+
+    server.remove_device(TransportId(1));
+    let devices = tracker.next().unwrap().unwrap();
+    assert_eq!("bar", devices[0].serial);
+    assert_eq!(1, devices.len());
+
+    // The connection is closed when the server goes away.
+    drop(server);
+    assert!(tracker.next().unwrap().is_err());
+    assert!(tracker.next().is_none());
+  }
+
+  #[test]
+  #[cfg(feature = "daemon")]
+  fn sync() {
+    let root = tempfile::tempdir().unwrap();
+    let local = root.path().join("local");
+    std::fs::write(&local, b"contents").unwrap();
+
+    std::fs::create_dir(root.path().join("device")).unwrap();
+
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1)).on_sync(root.path().join("device"));
+
+    let remote = Remote::from(server.remote());
+    let mut sync = remote.sync(DeviceCriteria::Any).unwrap();
+    assert_eq!(8, sync.push_file(&local, "/data/local/tmp/file").unwrap());
+    assert_eq!(8, sync.stat("/data/local/tmp/file").unwrap().size);
+
+    let pulled = root.path().join("pulled");
+    sync.pull_file("/data/local/tmp/file", &pulled).unwrap();
+    assert_eq!(b"contents", &std::fs::read(&pulled).unwrap()[..]);
+    sync.quit().unwrap();
+  }
+}
@@ -0,0 +1,389 @@
+//! Recording and replay of the traffic on [Remote](crate::client::Remote) channels.
+//!
+//! A [Recorder] logs every chunk of data sent or received on each channel (including the smart socket requests and
+//! their OKAY/FAIL responses) to a text file, one event per line:
+//!
+//! ```text
+//! 0.000000 1 connect tcp:127.0.0.1:5037
+//! 0.000081 1 > 000chost:version
+//! 0.000532 1 < OKAY00040029
+//! 0.000544 1 eof
+//! ```
+//!
+//! Each line contains the time since the recording began, the channel number, and the event. Data is written with
+//! non-printable bytes escaped, `>` for data sent to the server and `<` for data received from it.
+//!
+//! A [Replay] serves the data received on the recorded channels back to a client, in the order in which the channels
+//! were opened, and checks that the client sends the same data as it did during the recording.
+
+use futures::io::{AsyncRead, AsyncWrite};
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use crate as adb;
+use crate::core::{Socket, SocketSpec};
+
+/// Traffic capture mode of a [Remote](crate::client::Remote).
+#[derive(Clone)]
+pub enum Capture {
+  /// Record traffic to a [Recorder].
+  Record(Arc<Recorder>),
+
+  /// Serve channels from a [Replay] instead of connecting to a server.
+  Replay(Arc<Replay>),
+}
+
+impl Capture {
+  /// Constructs a [Capture] from the `ADB_CAPTURE` or `ADB_REPLAY` environment variables, if either is set.
+  ///
+  /// `ADB_CAPTURE=<path>` records traffic to a file, and `ADB_REPLAY=<path>` replays a previous recording.
+  pub fn from_env() -> adb::Result<Option<Capture>> {
+    if let Some(path) = std::env::var_os("ADB_REPLAY") {
+      Ok(Some(Capture::Replay(Arc::new(Replay::open(path)?))))
+    } else if let Some(path) = std::env::var_os("ADB_CAPTURE") {
+      Ok(Some(Capture::Record(Arc::new(Recorder::create(path)?))))
+    } else {
+      Ok(None)
+    }
+  }
+}
+
+/// Direction of a chunk of data.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+  /// Sent from the client to the server.
+  Send,
+
+  /// Received by the client from the server.
+  Receive,
+}
+
+/// Sink for recorded traffic.
+pub struct Recorder {
+  start: Instant,
+  next_channel: AtomicU64,
+  output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Recorder {
+  /// Constructs a [Recorder] that writes to a file, truncating it if it exists.
+  pub fn create(path: impl AsRef<Path>) -> adb::Result<Recorder> {
+    let file = std::fs::File::create(path)?;
+    Ok(Recorder::new(file))
+  }
+
+  /// Constructs a [Recorder] that writes to an arbitrary destination.
+  pub fn new(output: impl Write + Send + 'static) -> Recorder {
+    Recorder {
+      start: Instant::now(),
+      next_channel: AtomicU64::new(1),
+      output: Mutex::new(Box::new(output)),
+    }
+  }
+
+  /// Wraps a newly connected socket, recording all of the traffic on it.
+  pub fn wrap(self: &Arc<Self>, socket_spec: &SocketSpec, socket: Box<dyn Socket>) -> Box<dyn Socket> {
+    let channel = self.next_channel.fetch_add(1, Ordering::SeqCst);
+    self.log(channel, &format!("connect {}", socket_spec));
+    Box::new(RecordingSocket {
+      inner: socket,
+      recorder: self.clone(),
+      channel,
+    })
+  }
+
+  fn log(&self, channel: u64, event: &str) {
+    let elapsed = self.start.elapsed();
+    let line = format!(
+      "{}.{:06} {} {}\n",
+      elapsed.as_secs(),
+      elapsed.subsec_micros(),
+      channel,
+      event
+    );
+
+    // Failing to record shouldn't break the traffic being recorded.
+    let mut output = self.output.lock().unwrap();
+    let _ = output.write_all(line.as_bytes());
+    let _ = output.flush();
+  }
+
+  fn log_data(&self, channel: u64, direction: Direction, data: &[u8]) {
+    let prefix = match direction {
+      Direction::Send => "> ",
+      Direction::Receive => "< ",
+    };
+    self.log(channel, &(prefix.to_string() + &escape(data)));
+  }
+}
+
+struct RecordingSocket {
+  inner: Box<dyn Socket>,
+  recorder: Arc<Recorder>,
+  channel: u64,
+}
+
+impl AsyncRead for RecordingSocket {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+    let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+    match &result {
+      Poll::Ready(Ok(0)) => self.recorder.log(self.channel, "eof"),
+      Poll::Ready(Ok(len)) => self.recorder.log_data(self.channel, Direction::Receive, &buf[..*len]),
+      Poll::Ready(Err(err)) => self.recorder.log(self.channel, &format!("! {}", err)),
+      Poll::Pending => {}
+    }
+    result
+  }
+}
+
+impl AsyncWrite for RecordingSocket {
+  fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+    let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+    match &result {
+      Poll::Ready(Ok(len)) => self.recorder.log_data(self.channel, Direction::Send, &buf[..*len]),
+      Poll::Ready(Err(err)) => self.recorder.log(self.channel, &format!("! {}", err)),
+      Poll::Pending => {}
+    }
+    result
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+    Pin::new(&mut self.inner).poll_flush(cx)
+  }
+
+  fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+    let result = Pin::new(&mut self.inner).poll_close(cx);
+    if result.is_ready() {
+      self.recorder.log(self.channel, "close");
+    }
+    result
+  }
+}
+
+/// An event received on a replayed channel.
+#[derive(Debug)]
+enum ReplayEvent {
+  Data(Vec<u8>),
+  Error(String),
+}
+
+#[derive(Debug, Default)]
+struct ReplayChannel {
+  received: VecDeque<ReplayEvent>,
+  sent: Vec<u8>,
+}
+
+/// A recorded session, which can be served back to a client.
+pub struct Replay {
+  channels: Mutex<VecDeque<ReplayChannel>>,
+}
+
+impl Replay {
+  /// Loads a recording made by a [Recorder] from a file.
+  pub fn open(path: impl AsRef<Path>) -> adb::Result<Replay> {
+    let file = std::fs::File::open(path)?;
+    Replay::parse(std::io::BufReader::new(file))
+  }
+
+  /// Loads a recording made by a [Recorder].
+  pub fn parse(input: impl BufRead) -> adb::Result<Replay> {
+    let mut channels: Vec<(u64, ReplayChannel)> = Vec::new();
+    for line in input.lines() {
+      let line = line?;
+      if line.is_empty() {
+        continue;
+      }
+
+      let invalid = || adb::Error::UnexpectedData(format!("invalid capture line: '{}'", line));
+      let mut fields = line.splitn(4, ' ');
+      let _timestamp = fields.next().ok_or_else(invalid)?;
+      let channel: u64 = fields.next().and_then(|s| s.parse().ok()).ok_or_else(invalid)?;
+      let event = fields.next().ok_or_else(invalid)?;
+      let payload = fields.next().unwrap_or("");
+
+      if event == "connect" {
+        channels.push((channel, ReplayChannel::default()));
+        continue;
+      }
+
+      let (_, replay_channel) = channels
+        .iter_mut()
+        .rev()
+        .find(|(id, _)| *id == channel)
+        .ok_or_else(invalid)?;
+
+      match event {
+        ">" => replay_channel.sent.extend(unescape(payload).ok_or_else(invalid)?),
+        "<" => replay_channel
+          .received
+          .push_back(ReplayEvent::Data(unescape(payload).ok_or_else(invalid)?)),
+        "!" => replay_channel.received.push_back(ReplayEvent::Error(payload.into())),
+        "eof" | "close" => {}
+        _ => return Err(invalid()),
+      }
+    }
+
+    Ok(Replay {
+      channels: Mutex::new(channels.into_iter().map(|(_, channel)| channel).collect()),
+    })
+  }
+
+  /// Returns a socket serving the next recorded channel.
+  pub fn next_channel(&self) -> adb::Result<Box<dyn Socket>> {
+    let channel = self
+      .channels
+      .lock()
+      .unwrap()
+      .pop_front()
+      .ok_or_else(|| adb::Error::UnexpectedData("replay has no more recorded channels".into()))?;
+
+    Ok(Box::new(ReplaySocket {
+      received: channel.received,
+      sent: channel.sent,
+      sent_offset: 0,
+    }))
+  }
+
+  /// Returns the number of recorded channels that haven't been replayed yet.
+  pub fn remaining_channels(&self) -> usize {
+    self.channels.lock().unwrap().len()
+  }
+}
+
+struct ReplaySocket {
+  received: VecDeque<ReplayEvent>,
+  sent: Vec<u8>,
+  sent_offset: usize,
+}
+
+impl AsyncRead for ReplaySocket {
+  fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+    let result = match self.received.pop_front() {
+      None => Ok(0),
+      Some(ReplayEvent::Error(message)) => Err(std::io::Error::other(message)),
+      Some(ReplayEvent::Data(mut data)) => {
+        let len = std::cmp::min(buf.len(), data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        if len < data.len() {
+          self.received.push_front(ReplayEvent::Data(data.split_off(len)));
+        }
+        Ok(len)
+      }
+    };
+    Poll::Ready(result)
+  }
+}
+
+impl AsyncWrite for ReplaySocket {
+  fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+    let offset = self.sent_offset;
+    let expected = &self.sent[offset..std::cmp::min(self.sent.len(), offset + buf.len())];
+    if expected != buf {
+      let message = format!(
+        "replay diverged at offset {}: expected '{}', got '{}'",
+        offset,
+        escape(expected),
+        escape(buf)
+      );
+      return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message)));
+    }
+
+    self.sent_offset += buf.len();
+    Poll::Ready(Ok(buf.len()))
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+    Poll::Ready(Ok(()))
+  }
+}
+
+fn escape(data: &[u8]) -> String {
+  let mut result = String::with_capacity(data.len());
+  for &byte in data {
+    if byte == b' ' {
+      // Spaces are printable, but escape them anyway so that trailing whitespace survives in the capture file.
+      result.push_str("\\x20");
+    } else {
+      result.extend(std::ascii::escape_default(byte).map(char::from));
+    }
+  }
+  result
+}
+
+fn unescape(s: &str) -> Option<Vec<u8>> {
+  let mut result = Vec::with_capacity(s.len());
+  let mut bytes = s.bytes();
+  while let Some(byte) = bytes.next() {
+    if byte != b'\\' {
+      result.push(byte);
+      continue;
+    }
+
+    match bytes.next()? {
+      b't' => result.push(b'\t'),
+      b'r' => result.push(b'\r'),
+      b'n' => result.push(b'\n'),
+      b'x' => {
+        let hex = [bytes.next()?, bytes.next()?];
+        let hex = std::str::from_utf8(&hex).ok()?;
+        result.push(u8::from_str_radix(hex, 16).ok()?);
+      }
+      other => result.push(other),
+    }
+  }
+  Some(result)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use futures::executor::block_on;
+  use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+  #[test]
+  fn escape_roundtrip() {
+    let data: Vec<u8> = (0..=255).collect();
+    assert_eq!(Some(data.clone()), unescape(&escape(&data)));
+    assert_eq!("OKAY0004\\x20\\n\\\\", escape(b"OKAY0004 \n\\"));
+  }
+
+  #[test]
+  fn replay() {
+    let recording = "\
+0.000000 1 connect tcp:127.0.0.1:5037
+0.000081 1 > 000chost:version
+0.000532 1 < OKAY
+0.000533 1 < 00040029
+0.000544 1 eof
+0.001000 2 connect tcp:127.0.0.1:5037
+0.001001 2 ! connection reset
+";
+    let replay = Replay::parse(recording.as_bytes()).unwrap();
+    assert_eq!(2, replay.remaining_channels());
+
+    block_on(async {
+      let mut channel = replay.next_channel().unwrap();
+      channel.write_all(b"000chost:version").await.unwrap();
+      let mut received = Vec::new();
+      channel.read_to_end(&mut received).await.unwrap();
+      assert_eq!(b"OKAY00040029", &received[..]);
+
+      let mut channel = replay.next_channel().unwrap();
+      assert!(channel.write_all(b"0008host:foo").await.is_err());
+      let mut buf = [0u8; 4];
+      assert!(channel.read(&mut buf).await.is_err());
+    });
+
+    assert!(replay.next_channel().is_err());
+  }
+}
@@ -0,0 +1,286 @@
+//! Running commands on a device and capturing their output.
+
+use bytes::Bytes;
+use futures::future;
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use tracing::{debug, trace};
+
+use std::time::Duration;
+
+use crate as adb;
+use crate::client::policy::with_timeout;
+use crate::client::shell::{ShellBuilder, ShellInput, ShellOutput};
+use crate::client::Remote;
+use crate::host::DeviceCriteria;
+
+/// Options for [Remote::shell_output_with].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CommandOptions {
+  /// Data to send to the command's stdin.
+  ///
+  /// Stdin is closed afterwards (or immediately, if there's no data), so commands that read from stdin don't hang.
+  /// This requires the shell protocol: without it, the only way to close stdin is to close the whole channel, which
+  /// loses the command's output, so commands with stdin fail with
+  /// [Error::UnimplementedOperation](crate::Error::UnimplementedOperation) instead.
+  pub stdin: Option<Vec<u8>>,
+
+  /// Maximum combined size of stdout and stderr. Commands that produce more output fail with
+  /// [Error::UnexpectedData](crate::Error::UnexpectedData).
+  pub max_output: Option<usize>,
+
+  /// Maximum time for the command to run, including connecting to the device.
+  pub timeout: Option<Duration>,
+}
+
+/// The captured result of a command run with [Remote::shell_output].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CommandOutput {
+  pub stdout: Vec<u8>,
+
+  /// Output written to stderr. Devices that don't support the shell protocol merge it into stdout.
+  pub stderr: Vec<u8>,
+  pub exit_code: u8,
+}
+
+impl CommandOutput {
+  /// Returns whether the command exited with a non-zero exit code.
+  pub fn failed(&self) -> bool {
+    self.exit_code != 0
+  }
+
+  /// Turns a non-zero exit code into [Error::CommandFailed](crate::Error::CommandFailed).
+  pub fn check(self) -> adb::Result<CommandOutput> {
+    if self.failed() {
+      return Err(adb::Error::CommandFailed {
+        exit_code: self.exit_code,
+        stderr: String::from_utf8_lossy(&self.stderr).into_owned(),
+      });
+    }
+    Ok(self)
+  }
+}
+
+impl Remote {
+  /// Runs a command on a device, and waits for it to exit.
+  ///
+  /// The shell protocol is used if the device supports it, and otherwise the exit code is retrieved with a
+  /// [sentinel](crate::client::shell::ShellBuilder::exit_code_sentinel). The arguments are [quoted](crate::client::shell::quote),
+  /// so they're passed to the command unchanged.
+  pub async fn shell_output(
+    &self,
+    device_criteria: DeviceCriteria,
+    argv: impl IntoIterator<Item = impl Into<String>>,
+  ) -> adb::Result<CommandOutput> {
+    self
+      .shell_output_with(device_criteria, argv, &CommandOptions::default())
+      .await
+  }
+
+  /// Runs a command on a device like [Remote::shell_output], but fails with
+  /// [Error::CommandFailed](crate::Error::CommandFailed) if it exits with a non-zero exit code.
+  pub async fn shell_output_checked(
+    &self,
+    device_criteria: DeviceCriteria,
+    argv: impl IntoIterator<Item = impl Into<String>>,
+  ) -> adb::Result<CommandOutput> {
+    self.shell_output(device_criteria, argv).await?.check()
+  }
+
+  /// Runs a command on a device like [Remote::shell_output], with [CommandOptions].
+  pub async fn shell_output_with(
+    &self,
+    device_criteria: DeviceCriteria,
+    argv: impl IntoIterator<Item = impl Into<String>>,
+    options: &CommandOptions,
+  ) -> adb::Result<CommandOutput> {
+    let argv: Vec<String> = argv.into_iter().map(Into::into).collect();
+    with_timeout(options.timeout, "shell command", async {
+      // Resolve the device once, so that the features and the shell are both from the same one.
+      let transport_id = self.transport_id(device_criteria).await?;
+      let features = self.device_features(DeviceCriteria::TransportId(transport_id)).await?;
+      let shell_protocol = features.iter().any(|feature| feature == "shell_v2");
+      if !shell_protocol && options.stdin.is_some() {
+        return Err(adb::Error::UnimplementedOperation(
+          "sending stdin to a command requires the shell protocol".into(),
+        ));
+      }
+      debug!(argv = ?argv, shell_protocol, "running command");
+
+      let shell = ShellBuilder::new()
+        .command(Some(argv.clone()))
+        .shell_protocol(shell_protocol)
+        .exit_code_sentinel(true)
+        .tty(false)
+        .connect(self.clone(), DeviceCriteria::TransportId(transport_id))
+        .await?;
+      let (mut read, mut write) = shell.split();
+
+      let send_input = async {
+        // Without the shell protocol, closing stdin closes the whole channel, and there's no stdin to send.
+        if !shell_protocol {
+          return adb::Result::Ok(());
+        }
+
+        if let Some(stdin) = &options.stdin {
+          write.send(ShellInput::Stdin(Bytes::copy_from_slice(stdin))).await?;
+        }
+        write.send(ShellInput::CloseStdin).await
+      };
+
+      let receive_output = async {
+        let mut output = CommandOutput::default();
+        while let Some(event) = read.next().await {
+          match event? {
+            ShellOutput::Stdout(data) => output.stdout.extend_from_slice(&data),
+            ShellOutput::Stderr(data) => output.stderr.extend_from_slice(&data),
+            ShellOutput::Exit(exit_code) => {
+              output.exit_code = exit_code;
+              break;
+            }
+          }
+
+          if let Some(max_output) = options.max_output {
+            if output.stdout.len() + output.stderr.len() > max_output {
+              return Err(adb::Error::UnexpectedData(format!(
+                "command output exceeded {} bytes",
+                max_output
+              )));
+            }
+          }
+        }
+        Ok(output)
+      };
+
+      // The command might exit without reading its input, so failures to send it don't matter if it exited.
+      let (input_result, output) = future::join(send_input, receive_output).await;
+      if let Err(err) = input_result {
+        trace!(error = %err, "failed to send input");
+      }
+      output
+    })
+    .await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::host::TransportId;
+  use crate::runtime::block_on;
+  use crate::testing::{device, FakeServer};
+
+  #[test]
+  fn shell_output() {
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1));
+    server.on_shell("echo foo", b"foo\n", b"bar\n", 0);
+    server.on_shell("false", b"", b"oops\n", 1);
+
+    let remote = server.remote();
+    let output = block_on(remote.shell_output(DeviceCriteria::Any, ["echo", "foo"])).unwrap();
+    assert_eq!(b"foo\n", &output.stdout[..]);
+    assert_eq!(b"bar\n", &output.stderr[..]);
+    assert_eq!(0, output.exit_code);
+    server.assert_services(&[
+      "host:tport:any",
+      "host-transport-id:1:features",
+      "host:transport-id:1",
+      "shell,v2,raw:echo foo",
+    ]);
+
+    let output = block_on(remote.shell_output(DeviceCriteria::Any, ["false"])).unwrap();
+    assert!(output.failed());
+
+    match block_on(remote.shell_output_checked(DeviceCriteria::Any, ["false"])) {
+      Err(adb::Error::CommandFailed { exit_code, stderr }) => {
+        assert_eq!(1, exit_code);
+        assert_eq!("oops\n", stderr);
+      }
+      other => panic!("unexpected result: {:?}", other),
+    }
+  }
+
+  #[test]
+  fn options() {
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1));
+    server.on_shell("cat", &[b'x'; 1024], b"", 0);
+
+    let mut options = CommandOptions {
+      stdin: Some(b"input".to_vec()),
+      ..Default::default()
+    };
+    let output = block_on(
+      server
+        .remote()
+        .shell_output_with(DeviceCriteria::Any, ["cat"], &options),
+    )
+    .unwrap();
+    assert_eq!(1024, output.stdout.len());
+
+    options.max_output = Some(1000);
+    match block_on(
+      server
+        .remote()
+        .shell_output_with(DeviceCriteria::Any, ["cat"], &options),
+    ) {
+      Err(adb::Error::UnexpectedData(_)) => {}
+      other => panic!("unexpected result: {:?}", other),
+    }
+  }
+
+  #[test]
+  fn legacy_shell() {
+    let server = FakeServer::start().unwrap();
+    server
+      .add_device(device("foo", 1))
+      .set_features(TransportId(1), &["cmd"]);
+    server.on_shell("ls", b"out\n", b"err\n", 1);
+
+    let output = block_on(
+      server
+        .remote()
+        .shell_output(DeviceCriteria::Serial("foo".into()), ["ls"]),
+    )
+    .unwrap();
+    assert_eq!(b"out\nerr\n", &output.stdout[..]);
+    assert_eq!(1, output.exit_code);
+
+    let services = server.services();
+    assert_eq!(
+      &[
+        "host:tport:serial:foo",
+        "host-transport-id:1:features",
+        "host:transport-id:1"
+      ],
+      &services[..3]
+    );
+    assert!(services[3].starts_with("shell:ls\necho "));
+  }
+
+  #[test]
+  fn legacy_shell_stdin() {
+    let server = FakeServer::start().unwrap();
+    server
+      .add_device(device("foo", 1))
+      .set_features(TransportId(1), &["cmd"]);
+    server.on_shell("cat", b"out\n", b"", 0);
+
+    let options = CommandOptions {
+      stdin: Some(b"input".to_vec()),
+      ..Default::default()
+    };
+    match block_on(
+      server
+        .remote()
+        .shell_output_with(DeviceCriteria::Any, ["cat"], &options),
+    ) {
+      Err(adb::Error::UnimplementedOperation(_)) => {}
+      other => panic!("unexpected result: {:?}", other),
+    }
+
+    // The command isn't started, since its output would be lost when closing stdin.
+    assert!(server.device_services().is_empty());
+  }
+}
@@ -0,0 +1,131 @@
+//! Running operations on many devices at once.
+
+use futures::future::Future;
+use futures::stream::{self, StreamExt};
+
+use crate as adb;
+use crate::client::{CommandOptions, CommandOutput, Remote};
+use crate::host::{DeviceCriteria, DeviceDescription, TransportType};
+
+/// The result of an operation on one of the devices passed to [Remote::fan_out].
+#[derive(Debug)]
+pub struct DeviceResult<T> {
+  pub device: DeviceDescription,
+  pub result: adb::Result<T>,
+}
+
+impl Remote {
+  /// Lists the devices that are online, i.e. that can run commands.
+  pub async fn online_devices(&self) -> adb::Result<Vec<DeviceDescription>> {
+    let devices = self.devices().await?;
+    Ok(
+      devices
+        .into_iter()
+        .filter(|device| matches!(device.transport_type, TransportType::Online(_)))
+        .collect(),
+    )
+  }
+
+  /// Runs an operation on each of the given devices, with at most `concurrency` of them running at once.
+  ///
+  /// The operation is passed [DeviceCriteria] that select the device by transport id, so that it isn't confused with
+  /// another device with the same serial. The results are returned in the same order as the devices, and a failure on
+  /// one device doesn't affect the others.
+  pub async fn fan_out<T, F, Fut>(
+    &self,
+    devices: Vec<DeviceDescription>,
+    concurrency: usize,
+    mut operation: F,
+  ) -> Vec<DeviceResult<T>>
+  where
+    F: FnMut(DeviceDescription, DeviceCriteria) -> Fut,
+    Fut: Future<Output = adb::Result<T>>,
+  {
+    stream::iter(devices)
+      .map(|device| {
+        let future = operation(device.clone(), DeviceCriteria::TransportId(device.id));
+        async move {
+          let result = future.await;
+          DeviceResult { device, result }
+        }
+      })
+      .buffered(std::cmp::max(concurrency, 1))
+      .collect()
+      .await
+  }
+
+  /// Runs a command on each of the given devices with [Remote::shell_output_with], with at most `concurrency` of them
+  /// running at once.
+  pub async fn shell_output_fan_out(
+    &self,
+    devices: Vec<DeviceDescription>,
+    concurrency: usize,
+    argv: &[String],
+    options: &CommandOptions,
+  ) -> Vec<DeviceResult<CommandOutput>> {
+    self
+      .fan_out(devices, concurrency, |_, device_criteria| {
+        self.shell_output_with(device_criteria, argv.to_vec(), options)
+      })
+      .await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::host::TransportId;
+  use crate::runtime::block_on;
+  use crate::testing::{device, FakeServer};
+
+  #[test]
+  fn fan_out() {
+    let server = FakeServer::start().unwrap();
+    let mut offline = device("offline", 4);
+    offline.transport_type = TransportType::Offline;
+    server
+      .add_device(device("foo", 1))
+      .add_device(device("bar", 2))
+      .add_device(device("baz", 3))
+      .add_device(offline)
+      .set_features(TransportId(3), &[]);
+    server.on_shell("getprop ro.serialno", b"serial\n", b"", 0);
+
+    let remote = server.remote();
+    let devices = block_on(remote.online_devices()).unwrap();
+    assert_eq!(3, devices.len());
+
+    let argv = vec!["getprop".to_string(), "ro.serialno".to_string()];
+    let results = block_on(remote.shell_output_fan_out(devices.clone(), 2, &argv, &CommandOptions::default()));
+    let serials: Vec<_> = results.iter().map(|result| result.device.serial.as_str()).collect();
+    assert_eq!(vec!["foo", "bar", "baz"], serials);
+    for result in &results {
+      assert_eq!(b"serial\n", &result.result.as_ref().unwrap().stdout[..]);
+    }
+
+    // Each device is selected by its transport id, and the last one doesn't support the shell protocol.
+    let requests = server.requests();
+    for id in 1..=3 {
+      assert!(requests
+        .iter()
+        .any(|r| r.service == format!("host:transport-id:{}", id)));
+    }
+    assert_eq!(
+      2,
+      requests
+        .iter()
+        .filter(|r| r.service == "shell,v2,raw:getprop ro.serialno")
+        .count()
+    );
+
+    let results = block_on(remote.fan_out(devices, 8, |device, _| async move {
+      match device.serial.as_str() {
+        "bar" => Err(adb::Error::DeviceOffline),
+        serial => Ok(serial.len()),
+      }
+    }));
+    assert_eq!(3, *results[0].result.as_ref().unwrap());
+    assert!(results[1].result.is_err());
+    assert_eq!(3, *results[2].result.as_ref().unwrap());
+  }
+}
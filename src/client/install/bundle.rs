@@ -0,0 +1,591 @@
+//! Installing split APKs from bundle archives: `.apks` files built by bundletool, and `.xapk` files.
+//!
+//! Both are zip archives of split APKs. The splits that match a device are picked by their file names, and streamed
+//! out of the archive into an install session without being extracted to disk.
+
+use futures::channel::mpsc;
+use futures::io::AsyncRead;
+use futures::stream::TryStreamExt;
+use tracing::debug;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate as adb;
+use crate::client::install::session::split_name;
+use crate::client::install::InstallOptions;
+use crate::client::Remote;
+use crate::host::DeviceCriteria;
+use crate::runtime;
+
+/// ABIs that can appear in the names of ABI splits, with dashes replaced by underscores.
+const ABIS: &[&str] = &["armeabi", "armeabi_v7a", "arm64_v8a", "x86", "x86_64", "mips", "mips64"];
+
+/// Densities that can appear in the names of density splits, and their dpi.
+const DENSITIES: &[(&str, u32)] = &[
+  ("ldpi", 120),
+  ("mdpi", 160),
+  ("tvdpi", 213),
+  ("hdpi", 240),
+  ("xhdpi", 320),
+  ("xxhdpi", 480),
+  ("xxxhdpi", 640),
+];
+
+/// Returns whether a path looks like a bundle archive, rather than an APK.
+pub fn is_bundle(path: impl AsRef<Path>) -> bool {
+  let extension = path.as_ref().extension().and_then(|extension| extension.to_str());
+  matches!(extension, Some(extension) if extension.eq_ignore_ascii_case("apks") || extension.eq_ignore_ascii_case("xapk"))
+}
+
+/// The properties of a device that determine which splits it needs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceSpec {
+  /// Supported ABIs, most preferred first, like `arm64-v8a`.
+  pub abis: Vec<String>,
+
+  /// Screen density in dpi.
+  pub density: Option<u32>,
+
+  /// Locales, like `en-US`.
+  pub locales: Vec<String>,
+}
+
+impl DeviceSpec {
+  /// Builds a spec from a device's system properties.
+  pub fn from_properties(properties: &HashMap<String, String>) -> DeviceSpec {
+    let get = |name: &str| {
+      properties
+        .get(name)
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+    };
+
+    let abis = match get("ro.product.cpu.abilist") {
+      Some(abilist) => abilist.split(',').map(String::from).collect(),
+      None => ["ro.product.cpu.abi", "ro.product.cpu.abi2"]
+        .iter()
+        .filter_map(|name| get(name).map(String::from))
+        .collect(),
+    };
+
+    let density = ["ro.sf.lcd_density", "qemu.sf.lcd_density"]
+      .iter()
+      .find_map(|name| get(name).and_then(|density| density.parse().ok()));
+
+    let locales = [
+      "persist.sys.locale",
+      "ro.product.locale",
+      "persist.sys.language",
+      "ro.product.locale.language",
+    ]
+    .iter()
+    .find_map(|name| get(name))
+    .map(|locales| locales.split(',').map(String::from).collect())
+    .unwrap_or_default();
+
+    DeviceSpec { abis, density, locales }
+  }
+
+  /// Returns whether the device has a locale in a language, like `en`.
+  fn has_language(&self, language: &str) -> bool {
+    self.locales.iter().any(|locale| {
+      let locale_language = locale.split(['-', '_']).next().unwrap_or_default();
+      locale_language.eq_ignore_ascii_case(language)
+    })
+  }
+}
+
+/// Parses the output of `getprop`, which has a line like `[name]: [value]` for every property.
+fn parse_properties(output: &str) -> HashMap<String, String> {
+  output
+    .lines()
+    .filter_map(|line| {
+      let (name, value) = line.split_once("]: [")?;
+      let name = name.strip_prefix('[')?;
+      let value = value.strip_suffix(']')?;
+      Some((name.to_string(), value.to_string()))
+    })
+    .collect()
+}
+
+/// The device configuration that a split APK targets, parsed from its file name.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SplitConfig {
+  /// The base APK of a module, which is always installed.
+  Master,
+  Abi(String),
+  Density(u32),
+  Language(String),
+
+  /// A configuration that isn't understood, which is never installed.
+  Unknown(String),
+}
+
+impl SplitConfig {
+  fn parse(config: &str) -> SplitConfig {
+    if config == "master" {
+      SplitConfig::Master
+    } else if ABIS.contains(&config) {
+      SplitConfig::Abi(config.to_string())
+    } else if let Some(&(_, dpi)) = DENSITIES.iter().find(|(name, _)| *name == config) {
+      SplitConfig::Density(dpi)
+    } else if (2..=3).contains(&config.len()) && config.bytes().all(|b| b.is_ascii_lowercase()) {
+      SplitConfig::Language(config.to_string())
+    } else {
+      SplitConfig::Unknown(config.to_string())
+    }
+  }
+}
+
+/// A split APK in a bundle archive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BundleSplit {
+  /// Path of the APK in the archive.
+  pub path: String,
+
+  /// The module the split belongs to, like `base`.
+  pub module: String,
+  pub config: SplitConfig,
+
+  /// Uncompressed size of the APK.
+  pub size: u64,
+
+  index: usize,
+}
+
+/// Splits a file name into a module and a configuration.
+///
+/// bundletool names splits like `base-master.apk` and `base-arm64_v8a.apk`, while `.xapk` files have the base APK
+/// under the package name and splits like `config.arm64_v8a.apk`.
+fn parse_split_name(name: &str) -> Option<(String, SplitConfig)> {
+  let stem = name.strip_suffix(".apk")?;
+  if let Some(config) = stem.strip_prefix("config.") {
+    Some(("base".into(), SplitConfig::parse(config)))
+  } else if let Some((module, config)) = stem.rsplit_once('-') {
+    Some((module.into(), SplitConfig::parse(config)))
+  } else {
+    Some((stem.into(), SplitConfig::Master))
+  }
+}
+
+/// A `.apks` or `.xapk` archive of split APKs.
+#[derive(Clone, Debug)]
+pub struct ApkBundle {
+  path: PathBuf,
+  splits: Vec<BundleSplit>,
+}
+
+fn zip_error(path: &Path, err: zip::result::ZipError) -> adb::Error {
+  match err {
+    zip::result::ZipError::Io(err) => adb::Error::IoError(err),
+    err => adb::Error::InvalidArgument(format!("failed to read bundle '{}': {}", path.display(), err)),
+  }
+}
+
+impl ApkBundle {
+  /// Opens a bundle archive and lists its splits.
+  ///
+  /// Only the APKs in `splits/` are considered if there is such a directory, as in archives built by bundletool,
+  /// which also contain standalone APKs for old devices. Otherwise the APKs at the top level of the archive are.
+  pub fn open(path: impl AsRef<Path>) -> adb::Result<ApkBundle> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|err| zip_error(path, err))?;
+
+    let mut entries = Vec::new();
+    for index in 0..archive.len() {
+      let entry = archive.by_index_raw(index).map_err(|err| zip_error(path, err))?;
+      if entry.is_file() {
+        entries.push((index, entry.name().to_string(), entry.size()));
+      }
+    }
+
+    let directory = if entries.iter().any(|(_, name, _)| name.starts_with("splits/")) {
+      "splits/"
+    } else {
+      ""
+    };
+    let splits = entries
+      .into_iter()
+      .filter_map(|(index, name, size)| {
+        let file_name = name.strip_prefix(directory)?;
+        if file_name.contains('/') {
+          return None;
+        }
+        let (module, config) = parse_split_name(file_name)?;
+        Some(BundleSplit {
+          path: name,
+          module,
+          config,
+          size,
+          index,
+        })
+      })
+      .collect::<Vec<_>>();
+
+    if splits.is_empty() {
+      return Err(adb::Error::InvalidArgument(format!(
+        "bundle '{}' doesn't contain any APKs",
+        path.display()
+      )));
+    }
+    Ok(ApkBundle {
+      path: path.to_path_buf(),
+      splits,
+    })
+  }
+
+  /// Reads the uncompressed contents of a split.
+  ///
+  /// The split is decompressed with [runtime::spawn_blocking], since reading the archive blocks, with its own handle
+  /// to the archive. Errors, including checksum mismatches, are returned by the reader.
+  pub fn read_split(&self, split: &BundleSplit) -> impl AsyncRead + Send + Unpin {
+    let (mut sender, receiver) = mpsc::channel(1);
+    let path = self.path.clone();
+    let index = split.index;
+    runtime::spawn_blocking(move || {
+      use std::io::Read;
+      let mut send = |result| futures::executor::block_on(futures::SinkExt::send(&mut sender, result)).is_ok();
+
+      let mut archive = match File::open(&path).and_then(|file| Ok(zip::ZipArchive::new(file)?)) {
+        Ok(archive) => archive,
+        Err(err) => {
+          send(Err(err));
+          return;
+        }
+      };
+      let mut entry = match archive.by_index(index) {
+        Ok(entry) => entry,
+        Err(err) => {
+          send(Err(err.into()));
+          return;
+        }
+      };
+
+      let mut buf = vec![0u8; 64 * 1024];
+      loop {
+        let (result, done) = match entry.read(&mut buf) {
+          Ok(len) => (Ok(buf[..len].to_vec()), len == 0),
+          Err(err) => (Err(err), true),
+        };
+        if !send(result) || done {
+          break;
+        }
+      }
+    });
+    receiver.into_async_read()
+  }
+
+  pub fn splits(&self) -> &[BundleSplit] {
+    &self.splits
+  }
+
+  /// Selects the splits that a device needs.
+  ///
+  /// Every module gets its master APK, the ABI split for the device's most preferred ABI, the density split closest
+  /// to the device's density (preferring higher densities), and the language splits for the device's locales.
+  /// Fails if a module has ABI splits, but none for an ABI the device supports.
+  pub fn select(&self, spec: &DeviceSpec) -> adb::Result<Vec<&BundleSplit>> {
+    let mut modules: BTreeMap<&str, Vec<&BundleSplit>> = BTreeMap::new();
+    for split in &self.splits {
+      modules.entry(&split.module).or_default().push(split);
+    }
+
+    let mut selected = Vec::new();
+    for (module, splits) in modules {
+      let mut abi_splits = Vec::new();
+      let mut density_splits = Vec::new();
+      for split in splits {
+        match &split.config {
+          SplitConfig::Master => selected.push(split),
+          SplitConfig::Abi(abi) => abi_splits.push((abi, split)),
+          SplitConfig::Density(dpi) => density_splits.push((*dpi, split)),
+          SplitConfig::Language(language) => {
+            if spec.has_language(language) {
+              selected.push(split);
+            }
+          }
+          SplitConfig::Unknown(config) => debug!(path = %split.path, config = %config, "skipping unknown split"),
+        }
+      }
+
+      if !abi_splits.is_empty() {
+        let abi_split = spec.abis.iter().find_map(|abi| {
+          let abi = abi.replace('-', "_");
+          abi_splits.iter().find(|(split_abi, _)| **split_abi == abi)
+        });
+        match abi_split {
+          Some((_, split)) => selected.push(split),
+          None => {
+            return Err(adb::Error::InvalidArgument(format!(
+              "module '{}' has no split for the device's ABIs ({})",
+              module,
+              spec.abis.join(", ")
+            )))
+          }
+        }
+      }
+
+      let density_split = match spec.density {
+        Some(density) => density_splits
+          .iter()
+          .filter(|(dpi, _)| *dpi >= density)
+          .min_by_key(|(dpi, _)| *dpi)
+          .or_else(|| density_splits.iter().max_by_key(|(dpi, _)| *dpi)),
+        None => density_splits.iter().max_by_key(|(dpi, _)| *dpi),
+      };
+      if let Some((_, split)) = density_split {
+        selected.push(split);
+      }
+    }
+
+    // Keep the order of the archive, so that base APKs are usually written first.
+    selected.sort_by_key(|split| split.index);
+    Ok(selected)
+  }
+}
+
+impl Remote {
+  /// Queries the properties of a device that determine which splits it needs.
+  pub async fn device_spec(&self, device_criteria: DeviceCriteria) -> adb::Result<DeviceSpec> {
+    let output = self.shell_output_checked(device_criteria, ["getprop"]).await?;
+    let properties = parse_properties(&String::from_utf8_lossy(&output.stdout));
+    Ok(DeviceSpec::from_properties(&properties))
+  }
+
+  /// Installs the splits of a `.apks` or `.xapk` bundle archive that match a device, atomically.
+  pub async fn install_bundle(
+    &self,
+    device_criteria: DeviceCriteria,
+    path: impl AsRef<Path>,
+    options: &InstallOptions,
+  ) -> adb::Result<()> {
+    let path = path.as_ref();
+    let bundle = ApkBundle::open(path)?;
+
+    // Install on the same device whose spec was used to select the splits.
+    let device_criteria = DeviceCriteria::TransportId(self.transport_id(device_criteria).await?);
+    let spec = self.device_spec(device_criteria.clone()).await?;
+    debug!(spec = ?spec, "selecting splits");
+
+    let splits: Vec<BundleSplit> = bundle.select(&spec)?.into_iter().cloned().collect();
+    let total_size = splits.iter().map(|split| split.size).sum();
+    let mut session = self
+      .create_install_session(device_criteria, options, Some(total_size))
+      .await?;
+
+    for (i, split) in splits.iter().enumerate() {
+      let name = split_name(i, Path::new(&split.path));
+      let data = bundle.read_split(split);
+      if let Err(err) = session.write(&name, data, split.size).await {
+        session.abandon_after_error().await;
+        return Err(err);
+      }
+    }
+    session.commit().await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::host::TransportId;
+  use crate::runtime::block_on;
+  use crate::testing::{device, FakeServer, Response};
+  use std::io::Write;
+
+  fn spec(abis: &[&str], density: Option<u32>, locales: &[&str]) -> DeviceSpec {
+    DeviceSpec {
+      abis: abis.iter().map(|abi| abi.to_string()).collect(),
+      density,
+      locales: locales.iter().map(|locale| locale.to_string()).collect(),
+    }
+  }
+
+  fn write_bundle(path: &Path, entries: &[(&str, &[u8])]) {
+    let mut writer = zip::ZipWriter::new(File::create(path).unwrap());
+    for (i, (name, data)) in entries.iter().enumerate() {
+      let method = if i % 2 == 0 {
+        zip::CompressionMethod::Stored
+      } else {
+        zip::CompressionMethod::Deflated
+      };
+      let options = zip::write::SimpleFileOptions::default().compression_method(method);
+      writer.start_file(*name, options).unwrap();
+      writer.write_all(data).unwrap();
+    }
+    writer.finish().unwrap();
+  }
+
+  #[test]
+  fn properties() {
+    let output = "[ro.product.cpu.abilist]: [arm64-v8a,armeabi-v7a,armeabi]\n\
+                  [ro.sf.lcd_density]: [420]\n\
+                  [persist.sys.locale]: [fr-CA]\n\
+                  [ro.product.locale]: [en-US]\n\
+                  [multi.line]: [foo\n\
+                  bar]\n";
+    let spec = DeviceSpec::from_properties(&parse_properties(output));
+    assert_eq!(vec!["arm64-v8a", "armeabi-v7a", "armeabi"], spec.abis);
+    assert_eq!(Some(420), spec.density);
+    assert_eq!(vec!["fr-CA"], spec.locales);
+
+    let spec = DeviceSpec::from_properties(&parse_properties("[ro.product.cpu.abi]: [x86]\n"));
+    assert_eq!(vec!["x86"], spec.abis);
+    assert_eq!(None, spec.density);
+    assert!(spec.locales.is_empty());
+  }
+
+  #[test]
+  fn split_names() {
+    assert_eq!(
+      Some(("base".into(), SplitConfig::Master)),
+      parse_split_name("base-master.apk")
+    );
+    assert_eq!(
+      Some(("feature_x".into(), SplitConfig::Abi("x86_64".into()))),
+      parse_split_name("feature_x-x86_64.apk")
+    );
+    assert_eq!(
+      Some(("base".into(), SplitConfig::Density(480))),
+      parse_split_name("config.xxhdpi.apk")
+    );
+    assert_eq!(
+      Some(("com.foo".into(), SplitConfig::Master)),
+      parse_split_name("com.foo.apk")
+    );
+    assert_eq!(
+      Some(("base".into(), SplitConfig::Language("fil".into()))),
+      parse_split_name("base-fil.apk")
+    );
+    assert_eq!(
+      Some(("base".into(), SplitConfig::Unknown("master_2".into()))),
+      parse_split_name("base-master_2.apk")
+    );
+    assert_eq!(None, parse_split_name("manifest.json"));
+    assert!(is_bundle("foo.APKS"));
+    assert!(is_bundle("/tmp/foo.xapk"));
+    assert!(!is_bundle("foo.apk"));
+  }
+
+  #[test]
+  fn select() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("app.apks");
+    write_bundle(
+      &path,
+      &[
+        ("toc.pb", b"toc"),
+        ("standalones/standalone-arm64_v8a_hdpi.apk", b"standalone"),
+        ("splits/base-master.apk", b"base"),
+        ("splits/base-arm64_v8a.apk", b"arm64"),
+        ("splits/base-armeabi_v7a.apk", b"arm"),
+        ("splits/base-hdpi.apk", b"hdpi"),
+        ("splits/base-xhdpi.apk", b"xhdpi"),
+        ("splits/base-xxhdpi.apk", b"xxhdpi"),
+        ("splits/base-en.apk", b"en"),
+        ("splits/base-fr.apk", b"fr"),
+        ("splits/feature-master.apk", b"feature"),
+      ],
+    );
+    let bundle = ApkBundle::open(&path).unwrap();
+    assert_eq!(9, bundle.splits().len());
+
+    let paths = |spec: &DeviceSpec| -> Vec<String> {
+      let splits = bundle.select(spec).unwrap();
+      splits.into_iter().map(|split| split.path.clone()).collect()
+    };
+    assert_eq!(
+      vec![
+        "splits/base-master.apk",
+        "splits/base-arm64_v8a.apk",
+        "splits/base-xxhdpi.apk",
+        "splits/base-fr.apk",
+        "splits/feature-master.apk",
+      ],
+      paths(&spec(&["arm64-v8a", "armeabi-v7a"], Some(420), &["fr-FR"]))
+    );
+    assert_eq!(
+      vec![
+        "splits/base-master.apk",
+        "splits/base-armeabi_v7a.apk",
+        "splits/base-xxhdpi.apk",
+        "splits/base-en.apk",
+        "splits/feature-master.apk",
+      ],
+      paths(&spec(&["armeabi-v7a"], Some(640), &["en-US", "de-DE"]))
+    );
+    assert_eq!(
+      vec![
+        "splits/base-master.apk",
+        "splits/base-armeabi_v7a.apk",
+        "splits/base-hdpi.apk",
+        "splits/feature-master.apk",
+      ],
+      paths(&spec(&["x86", "armeabi-v7a"], Some(160), &[]))
+    );
+    assert!(matches!(
+      bundle.select(&spec(&["x86"], None, &[])),
+      Err(adb::Error::InvalidArgument(_))
+    ));
+  }
+
+  #[test]
+  fn install_bundle() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("app.xapk");
+    write_bundle(
+      &path,
+      &[
+        ("manifest.json", b"{}"),
+        ("icon.png", b"png"),
+        ("com.foo.apk", b"base"),
+        ("config.arm64_v8a.apk", b"arm64"),
+        ("config.x86.apk", b"x86"),
+        ("config.mdpi.apk", b"mdpi"),
+      ],
+    );
+
+    let server = FakeServer::start().unwrap();
+    server
+      .add_device(device("foo", 1))
+      .set_features(TransportId(1), &["shell_v2", "cmd"]);
+    server.on_shell(
+      "getprop",
+      b"[ro.product.cpu.abilist]: [x86_64,x86]\n[ro.sf.lcd_density]: [160]\n",
+      b"",
+      0,
+    );
+    server.on_device(
+      "exec:cmd package install-create -S 11",
+      Response::raw("Success: created install session [7]\n"),
+    );
+    server.on_device(
+      "exec:cmd package install-write -S 4 7 0_com.foo.apk -",
+      Response::input(4, "Success\n"),
+    );
+    server.on_device(
+      "exec:cmd package install-write -S 3 7 1_config.x86.apk -",
+      Response::input(3, "Success\n"),
+    );
+    server.on_device(
+      "exec:cmd package install-write -S 4 7 2_config.mdpi.apk -",
+      Response::input(4, "Success\n"),
+    );
+    server.on_device("exec:cmd package install-commit 7", Response::raw("Success\n"));
+
+    block_on(
+      server
+        .remote()
+        .install_bundle(DeviceCriteria::Any, &path, &InstallOptions::default()),
+    )
+    .unwrap();
+    assert_eq!(
+      vec![b"base".to_vec(), b"x86".to_vec(), b"mdpi".to_vec()],
+      server.inputs()
+    );
+    server.assert_requested("exec:cmd package install-commit 7");
+  }
+}
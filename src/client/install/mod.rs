@@ -0,0 +1,484 @@
+//! Installing and uninstalling packages.
+//!
+//! Packages are streamed straight to the package manager on devices that support it, and otherwise pushed to the
+//! device and installed from there with `pm install`, like adb does.
+//!
+//! Several APKs can be installed atomically with install sessions, including the splits of a bundle archive that match
+//! a device, with the `bundle` feature.
+
+use futures::channel::mpsc;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use futures::stream::TryStreamExt;
+use tracing::debug;
+
+use std::fs::File;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate as adb;
+use crate::client::shell::quote;
+use crate::client::sync::SyncClient;
+use crate::client::Remote;
+use crate::host::{DeviceCriteria, TransportId};
+use crate::runtime;
+
+#[cfg(feature = "bundle")]
+mod bundle;
+#[cfg(feature = "bundle")]
+pub use bundle::{is_bundle, ApkBundle, BundleSplit, DeviceSpec, SplitConfig};
+mod session;
+pub use session::InstallSession;
+
+/// Directory on the device to which packages are pushed by [InstallMethod::Legacy].
+pub const LEGACY_INSTALL_DIR: &str = "/data/local/tmp";
+
+/// Options for [Remote::install].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InstallOptions {
+  /// Replace an existing package (`-r`).
+  pub replace: bool,
+
+  /// Allow test packages (`-t`).
+  pub allow_test: bool,
+
+  /// Allow version code downgrades (`-d`).
+  pub allow_downgrade: bool,
+
+  /// Grant all runtime permissions (`-g`).
+  pub grant_permissions: bool,
+
+  /// Install for a user (`--user`), like `0`, `current` or `all`.
+  pub user: Option<String>,
+}
+
+impl InstallOptions {
+  /// Returns the package manager arguments for the options.
+  pub(crate) fn args(&self) -> Vec<String> {
+    let mut args = Vec::new();
+    for (enabled, flag) in [
+      (self.replace, "-r"),
+      (self.allow_test, "-t"),
+      (self.allow_downgrade, "-d"),
+      (self.grant_permissions, "-g"),
+    ] {
+      if enabled {
+        args.push(flag.to_string());
+      }
+    }
+
+    if let Some(user) = &self.user {
+      args.push("--user".into());
+      args.push(user.clone());
+    }
+    args
+  }
+}
+
+/// Options for [Remote::uninstall].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UninstallOptions {
+  /// Keep the package's data and cache directories (`-k`).
+  pub keep_data: bool,
+
+  /// Uninstall for a user (`--user`).
+  pub user: Option<String>,
+}
+
+/// How packages are installed on a device, depending on its features.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InstallMethod {
+  /// Streamed to the package service with `abb_exec:` (Android 10 and later).
+  Abb,
+
+  /// Streamed to `cmd package install` with `exec:` (Android 7.0 and later).
+  Cmd,
+
+  /// Pushed to [LEGACY_INSTALL_DIR], and installed from there with `pm install`.
+  Legacy,
+}
+
+impl InstallMethod {
+  /// Returns the best install method for a device with the given features.
+  pub fn from_features(features: &[String]) -> InstallMethod {
+    let has = |feature: &str| features.iter().any(|f| f == feature);
+    if has("abb_exec") {
+      InstallMethod::Abb
+    } else if has("cmd") {
+      InstallMethod::Cmd
+    } else {
+      InstallMethod::Legacy
+    }
+  }
+
+  /// Returns the service that runs a package manager command, which can read data from stdin.
+  pub(crate) fn service(&self, args: &[String]) -> String {
+    let quoted = || args.iter().map(|arg| quote(arg)).collect::<Vec<_>>().join(" ");
+    match self {
+      // abb_exec: takes its arguments separated by NULs, and doesn't use a shell.
+      InstallMethod::Abb => format!("abb_exec:package\0{}", args.join("\0")),
+      InstallMethod::Cmd => format!("exec:cmd package {}", quoted()),
+      InstallMethod::Legacy => format!("exec:pm {}", quoted()),
+    }
+  }
+}
+
+/// Turns the output of the package manager into a result.
+pub(crate) fn parse_result(output: &str) -> adb::Result<()> {
+  if output.lines().any(|line| line.trim_end().starts_with("Success")) {
+    return Ok(());
+  }
+
+  let failure = output.lines().find_map(|line| {
+    let reason = &line[line.find("Failure [")? + "Failure [".len()..];
+    Some(&reason[..reason.rfind(']')?])
+  });
+
+  let (code, message) = match failure {
+    Some(reason) => {
+      let (code, message) = reason.split_once(": ").unwrap_or((reason, ""));
+      if !code.is_empty()
+        && code
+          .bytes()
+          .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == b'_')
+      {
+        (Some(code.to_string()), message.to_string())
+      } else {
+        (None, reason.to_string())
+      }
+    }
+    None => (None, output.trim().to_string()),
+  };
+  Err(adb::Error::PackageManagerFailed { code, message })
+}
+
+/// Reads a file with [runtime::spawn_blocking], so that streaming it to a device doesn't block the runtime's other
+/// tasks.
+pub(crate) fn read_file(mut file: File) -> impl AsyncRead + Send + Unpin {
+  let (mut sender, receiver) = mpsc::channel(1);
+  runtime::spawn_blocking(move || {
+    use std::io::Read;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+      let (result, done) = match file.read(&mut buf) {
+        Ok(len) => (Ok(buf[..len].to_vec()), len == 0),
+        Err(err) => (Err(err), true),
+      };
+
+      if futures::executor::block_on(futures::SinkExt::send(&mut sender, result)).is_err() || done {
+        break;
+      }
+    }
+  });
+  receiver.into_async_read()
+}
+
+impl Remote {
+  /// Returns how packages will be installed on a device.
+  pub async fn install_method(&self, device_criteria: DeviceCriteria) -> adb::Result<InstallMethod> {
+    Ok(InstallMethod::from_features(
+      &self.device_features(device_criteria).await?,
+    ))
+  }
+
+  /// Installs an APK from the host on a device.
+  pub async fn install(
+    &self,
+    device_criteria: DeviceCriteria,
+    path: impl AsRef<Path>,
+    options: &InstallOptions,
+  ) -> adb::Result<()> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let name = path
+      .file_name()
+      .map(|name| name.to_string_lossy().into_owned())
+      .unwrap_or_else(|| "package.apk".into());
+    self
+      .install_from(device_criteria, read_file(file), size, &name, options)
+      .await
+  }
+
+  /// Installs an APK of a known size from a reader, without writing it to the host's disk.
+  ///
+  /// The name is only used for the temporary file on devices that don't support streamed installs.
+  pub async fn install_from(
+    &self,
+    device_criteria: DeviceCriteria,
+    data: impl AsyncRead + Unpin,
+    size: u64,
+    name: &str,
+    options: &InstallOptions,
+  ) -> adb::Result<()> {
+    // Resolve the device once, so that every step of the install happens on the one whose features were checked.
+    let device_criteria = DeviceCriteria::TransportId(self.transport_id(device_criteria).await?);
+    let method = self.install_method(device_criteria.clone()).await?;
+    debug!(method = ?method, size, name, "installing package");
+
+    if method == InstallMethod::Legacy {
+      return self.legacy_install(device_criteria, data, name, options).await;
+    }
+
+    let mut args = vec!["install".to_string(), "-S".into(), size.to_string()];
+    args.extend(options.args());
+    let (_, output) = self
+      .stream_to_service(device_criteria, &method.service(&args), data, size)
+      .await?;
+    parse_result(&output)
+  }
+
+  /// Sends exactly `size` bytes to a service that reads them from stdin, and returns the id of the transport that
+  /// was used and the output of the service.
+  pub(crate) async fn stream_to_service(
+    &self,
+    device_criteria: DeviceCriteria,
+    service: &str,
+    data: impl AsyncRead + Unpin,
+    size: u64,
+  ) -> adb::Result<(TransportId, String)> {
+    let (transport_id, mut channel) = self.open_device_channel(device_criteria, service).await?;
+    let sent = futures::io::copy(data.take(size), &mut channel).await?;
+    if sent != size {
+      return Err(adb::Error::InvalidArgument(format!(
+        "package data ended after {} of {} bytes",
+        sent, size
+      )));
+    }
+    channel.flush().await?;
+
+    // The service exits after reading all of the data, so the output ends when the channel is closed.
+    let mut output = Vec::new();
+    channel.read_to_end(&mut output).await?;
+    Ok((transport_id, String::from_utf8_lossy(&output).into_owned()))
+  }
+
+  async fn legacy_install(
+    &self,
+    device_criteria: DeviceCriteria,
+    data: impl AsyncRead + Unpin,
+    name: &str,
+    options: &InstallOptions,
+  ) -> adb::Result<()> {
+    let name = name.rsplit('/').next().unwrap_or(name);
+    let path = format!("{}/{}", LEGACY_INSTALL_DIR, name);
+    let mtime = SystemTime::now()
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .map_or(0, |time| time.as_secs() as u32);
+
+    let mut sync = SyncClient::connect(self.clone(), device_criteria.clone()).await?;
+    sync.push(data, &path, 0o644, mtime).await?;
+    sync.quit().await?;
+
+    let mut argv = vec!["pm".to_string(), "install".into()];
+    argv.extend(options.args());
+    argv.push(path.clone());
+    let result = self.shell_output(device_criteria.clone(), argv).await;
+
+    // Clean up even if the install failed.
+    if let Err(err) = self.shell_output(device_criteria, ["rm", "-f", &path]).await {
+      debug!(error = %err, path = %path, "failed to remove pushed package");
+    }
+
+    let output = result?;
+    parse_result(&String::from_utf8_lossy(&[output.stdout, output.stderr].concat()))
+  }
+
+  /// Uninstalls a package from a device.
+  pub async fn uninstall(
+    &self,
+    device_criteria: DeviceCriteria,
+    package: &str,
+    options: &UninstallOptions,
+  ) -> adb::Result<()> {
+    let device_criteria = DeviceCriteria::TransportId(self.transport_id(device_criteria).await?);
+    let features = self.device_features(device_criteria.clone()).await?;
+    let mut argv: Vec<String> = if features.iter().any(|feature| feature == "cmd") {
+      vec!["cmd".into(), "package".into(), "uninstall".into()]
+    } else {
+      vec!["pm".into(), "uninstall".into()]
+    };
+
+    if options.keep_data {
+      argv.push("-k".into());
+    }
+    if let Some(user) = &options.user {
+      argv.push("--user".into());
+      argv.push(user.clone());
+    }
+    argv.push(package.into());
+
+    debug!(argv = ?argv, "uninstalling package");
+    let output = self.shell_output(device_criteria, argv).await?;
+    parse_result(&String::from_utf8_lossy(&[output.stdout, output.stderr].concat()))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::runtime::block_on;
+  use crate::testing::{device, FakeServer, Response};
+
+  #[test]
+  fn results() {
+    assert!(parse_result("Performing Streamed Install\nSuccess\n").is_ok());
+
+    let failure = |output| match parse_result(output) {
+      Err(adb::Error::PackageManagerFailed { code, message }) => (code, message),
+      other => panic!("unexpected result: {:?}", other),
+    };
+    assert_eq!(
+      (
+        Some("INSTALL_FAILED_ALREADY_EXISTS".into()),
+        "Attempt to re-install com.foo without first uninstalling.".into()
+      ),
+      failure("Failure [INSTALL_FAILED_ALREADY_EXISTS: Attempt to re-install com.foo without first uninstalling.]\n")
+    );
+    assert_eq!(
+      (Some("DELETE_FAILED_INTERNAL_ERROR".into()), "".into()),
+      failure("Failure [DELETE_FAILED_INTERNAL_ERROR]")
+    );
+    assert_eq!(
+      (None, "Error: unknown option".into()),
+      failure("Error: unknown option\n")
+    );
+  }
+
+  #[test]
+  fn methods() {
+    let options = InstallOptions {
+      replace: true,
+      grant_permissions: true,
+      user: Some("current".into()),
+      ..Default::default()
+    };
+    assert_eq!(vec!["-r", "-g", "--user", "current"], options.args());
+
+    let features = |features: &[&str]| features.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+      InstallMethod::Abb,
+      InstallMethod::from_features(&features(&["cmd", "abb_exec"]))
+    );
+    assert_eq!(InstallMethod::Cmd, InstallMethod::from_features(&features(&["cmd"])));
+    assert_eq!(
+      InstallMethod::Legacy,
+      InstallMethod::from_features(&features(&["shell_v2"]))
+    );
+
+    let args = vec!["install".to_string(), "-S".into(), "3".into()];
+    assert_eq!("abb_exec:package\0install\0-S\x003", InstallMethod::Abb.service(&args));
+    assert_eq!("exec:cmd package install -S 3", InstallMethod::Cmd.service(&args));
+    assert_eq!("exec:pm install -S 3", InstallMethod::Legacy.service(&args));
+  }
+
+  #[test]
+  fn streamed_install() {
+    let server = FakeServer::start().unwrap();
+    server
+      .add_device(device("foo", 1))
+      .add_device(device("bar", 2))
+      .set_features(TransportId(2), &["shell_v2", "cmd"]);
+    server.on_device(
+      "abb_exec:package\0install\0-S\x005\0-r",
+      Response::input(5, "Success\n"),
+    );
+    server.on_device(
+      "exec:cmd package install -S 5",
+      Response::input(
+        5,
+        "Failure [INSTALL_FAILED_OLDER_SDK: Requires newer sdk version #30]\n",
+      ),
+    );
+
+    let remote = server.remote();
+    let options = InstallOptions {
+      replace: true,
+      ..Default::default()
+    };
+    block_on(remote.install_from(
+      DeviceCriteria::Serial("foo".into()),
+      &b"hello"[..],
+      5,
+      "foo.apk",
+      &options,
+    ))
+    .unwrap();
+
+    match block_on(remote.install_from(
+      DeviceCriteria::Serial("bar".into()),
+      &b"world"[..],
+      5,
+      "foo.apk",
+      &InstallOptions::default(),
+    )) {
+      Err(adb::Error::PackageManagerFailed { code, .. }) => {
+        assert_eq!(Some("INSTALL_FAILED_OLDER_SDK".into()), code)
+      }
+      other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(vec![b"hello".to_vec(), b"world".to_vec()], server.inputs());
+
+    match block_on(remote.install_from(DeviceCriteria::Serial("foo".into()), &b"hi"[..], 5, "foo.apk", &options)) {
+      Err(adb::Error::InvalidArgument(_)) => {}
+      other => panic!("unexpected result: {:?}", other),
+    }
+  }
+
+  #[test]
+  fn uninstall() {
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1));
+    server.on_shell("cmd package uninstall -k com.foo", b"Success\n", b"", 0);
+    server.on_shell(
+      "cmd package uninstall com.bar",
+      b"Failure [DELETE_FAILED_INTERNAL_ERROR]\n",
+      b"",
+      0,
+    );
+
+    let remote = server.remote();
+    let options = UninstallOptions {
+      keep_data: true,
+      ..Default::default()
+    };
+    block_on(remote.uninstall(DeviceCriteria::Any, "com.foo", &options)).unwrap();
+
+    // The device is only selected once, and then referred to by its transport id.
+    server.assert_services(&[
+      "host:tport:any",
+      "host-transport-id:1:features",
+      "host-transport-id:1:features",
+      "host:transport-id:1",
+      "shell,v2,raw:cmd package uninstall -k com.foo",
+    ]);
+    assert!(block_on(remote.uninstall(DeviceCriteria::Any, "com.bar", &UninstallOptions::default())).is_err());
+  }
+
+  #[test]
+  #[cfg(feature = "daemon")]
+  fn legacy_install() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(root.path().join("data/local/tmp")).unwrap();
+    let apk = root.path().join("app.apk");
+    std::fs::write(&apk, b"apk").unwrap();
+
+    let server = FakeServer::start().unwrap();
+    server
+      .add_device(device("foo", 1))
+      .set_features(TransportId(1), &["shell_v2"])
+      .on_sync(root.path());
+    server.on_shell("pm install -t /data/local/tmp/app.apk", b"Success\n", b"", 0);
+    server.on_shell("rm -f /data/local/tmp/app.apk", b"", b"", 0);
+
+    let options = InstallOptions {
+      allow_test: true,
+      ..Default::default()
+    };
+    block_on(server.remote().install(DeviceCriteria::Any, &apk, &options)).unwrap();
+    assert_eq!(
+      b"apk",
+      &std::fs::read(root.path().join("data/local/tmp/app.apk")).unwrap()[..]
+    );
+    server.assert_requested("shell,v2,raw:rm -f /data/local/tmp/app.apk");
+  }
+}
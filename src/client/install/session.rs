@@ -0,0 +1,472 @@
+//! Install sessions, for installing several APKs atomically.
+
+use futures::io::AsyncRead;
+use tracing::{debug, warn};
+
+use std::path::Path;
+
+use crate as adb;
+use crate::client::install::{parse_result, read_file, InstallMethod, InstallOptions};
+use crate::client::Remote;
+use crate::host::DeviceCriteria;
+
+/// A package manager install session on a device, created with [Remote::create_install_session].
+///
+/// APKs are streamed into the session with [InstallSession::write], and installed together by
+/// [InstallSession::commit]. If the session is dropped without being committed or abandoned (e.g. because an error
+/// was returned early, or the future using it was cancelled), it's abandoned in the background.
+pub struct InstallSession {
+  remote: Remote,
+
+  /// Criteria that select the device on which the session was created, rather than whatever device the original
+  /// criteria would select later.
+  device_criteria: DeviceCriteria,
+  method: InstallMethod,
+  id: u32,
+
+  /// Sessions added to a multi-package session, which are committed or abandoned along with it.
+  children: Vec<InstallSession>,
+  finished: bool,
+}
+
+/// Parses the session id out of the output of `install-create`, e.g. `Success: created install session [1234]`.
+fn parse_session_id(output: &str) -> adb::Result<u32> {
+  let id = output
+    .lines()
+    .filter(|line| line.starts_with("Success"))
+    .find_map(|line| {
+      let start = line.find('[')? + 1;
+      let end = start + line[start..].find(']')?;
+      line[start..end].parse().ok()
+    });
+
+  match id {
+    Some(id) => Ok(id),
+    None => {
+      parse_result(output)?;
+      Err(adb::Error::UnexpectedData(format!(
+        "failed to parse install session id from '{}'",
+        output.trim()
+      )))
+    }
+  }
+}
+
+/// Returns the name of the `index`th APK in a session, which has to be unique within the session.
+pub(crate) fn split_name(index: usize, path: &Path) -> String {
+  let name = path
+    .file_name()
+    .map(|name| name.to_string_lossy().into_owned())
+    .unwrap_or_else(|| "split.apk".into());
+  format!("{}_{}", index, name)
+}
+
+impl Remote {
+  /// Creates an install session on a device.
+  ///
+  /// `total_size` is the sum of the sizes of the APKs that will be written, which lets the device check that it has
+  /// enough space up front.
+  pub async fn create_install_session(
+    &self,
+    device_criteria: DeviceCriteria,
+    options: &InstallOptions,
+    total_size: Option<u64>,
+  ) -> adb::Result<InstallSession> {
+    let mut args = vec!["install-create".to_string()];
+    args.extend(options.args());
+    if let Some(total_size) = total_size {
+      args.push("-S".into());
+      args.push(total_size.to_string());
+    }
+    self.create_session(device_criteria, args).await
+  }
+
+  /// Creates a multi-package install session on a device, to which other sessions are added with
+  /// [InstallSession::add_session] to install several packages atomically.
+  pub async fn create_multi_package_session(
+    &self,
+    device_criteria: DeviceCriteria,
+    options: &InstallOptions,
+  ) -> adb::Result<InstallSession> {
+    let mut args = vec!["install-create".to_string(), "--multi-package".into()];
+    args.extend(options.args());
+    self.create_session(device_criteria, args).await
+  }
+
+  async fn create_session(&self, device_criteria: DeviceCriteria, args: Vec<String>) -> adb::Result<InstallSession> {
+    // The session only exists on the device whose features were checked, so resolve it once and stick to it.
+    let device_criteria = DeviceCriteria::TransportId(self.transport_id(device_criteria).await?);
+    let method = self.install_method(device_criteria.clone()).await?;
+    let (_, output) = self
+      .stream_to_service(device_criteria.clone(), &method.service(&args), futures::io::empty(), 0)
+      .await?;
+    let id = parse_session_id(&output)?;
+    debug!(id, method = ?method, "created install session");
+
+    Ok(InstallSession {
+      remote: self.clone(),
+      device_criteria,
+      method,
+      id,
+      children: Vec::new(),
+      finished: false,
+    })
+  }
+
+  /// Installs the APKs of a single package (e.g. a base APK and its splits) atomically.
+  pub async fn install_multiple(
+    &self,
+    device_criteria: DeviceCriteria,
+    paths: &[impl AsRef<Path>],
+    options: &InstallOptions,
+  ) -> adb::Result<()> {
+    let mut total_size = 0;
+    for path in paths {
+      total_size += std::fs::metadata(path)?.len();
+    }
+
+    let mut session = self
+      .create_install_session(device_criteria, options, Some(total_size))
+      .await?;
+    if let Err(err) = session.write_files(paths).await {
+      session.abandon_after_error().await;
+      return Err(err);
+    }
+    session.commit().await
+  }
+
+  /// Installs several packages (each with a single APK) atomically.
+  pub async fn install_multi_package(
+    &self,
+    device_criteria: DeviceCriteria,
+    paths: &[impl AsRef<Path>],
+    options: &InstallOptions,
+  ) -> adb::Result<()> {
+    let mut parent = self.create_multi_package_session(device_criteria, options).await?;
+    let criteria = parent.device_criteria.clone();
+
+    let result = async {
+      for path in paths {
+        let size = std::fs::metadata(path)?.len();
+        let child = self
+          .create_install_session(criteria.clone(), options, Some(size))
+          .await?;
+
+        // Add the child before writing to it, so that it's abandoned along with the parent if anything fails.
+        parent.add_session(child).await?;
+        let child = parent.children.last_mut().unwrap();
+        child.write_files(&[path]).await?;
+      }
+      adb::Result::Ok(())
+    }
+    .await;
+
+    if let Err(err) = result {
+      parent.abandon_after_error().await;
+      return Err(err);
+    }
+    parent.commit().await
+  }
+}
+
+impl InstallSession {
+  /// Returns the id of the session on the device.
+  pub fn id(&self) -> u32 {
+    self.id
+  }
+
+  async fn run(&self, args: &[String], data: impl AsyncRead + Unpin, size: u64) -> adb::Result<()> {
+    let service = self.method.service(args);
+    let (_, output) = self
+      .remote
+      .stream_to_service(self.device_criteria.clone(), &service, data, size)
+      .await?;
+    parse_result(&output)
+  }
+
+  /// Streams an APK of a known size into the session, under a name that is unique within the session.
+  pub async fn write(&mut self, name: &str, data: impl AsyncRead + Unpin, size: u64) -> adb::Result<()> {
+    debug!(id = self.id, name, size, "writing to install session");
+    let args = [
+      "install-write".to_string(),
+      "-S".into(),
+      size.to_string(),
+      self.id.to_string(),
+      name.into(),
+      "-".into(),
+    ];
+    self.run(&args, data, size).await
+  }
+
+  async fn write_files(&mut self, paths: &[impl AsRef<Path>]) -> adb::Result<()> {
+    for (index, path) in paths.iter().enumerate() {
+      let path = path.as_ref();
+      let file = std::fs::File::open(path)?;
+      let size = file.metadata()?.len();
+      self.write(&split_name(index, path), read_file(file), size).await?;
+    }
+    Ok(())
+  }
+
+  /// Adds a session to a multi-package session, which then takes care of committing or abandoning it.
+  pub async fn add_session(&mut self, child: InstallSession) -> adb::Result<()> {
+    debug!(
+      id = self.id,
+      child = child.id,
+      "adding session to multi-package session"
+    );
+    self.children.push(child);
+    let args = [
+      "install-add-session".to_string(),
+      self.id.to_string(),
+      self.children.last().unwrap().id.to_string(),
+    ];
+    self.run(&args, futures::io::empty(), 0).await
+  }
+
+  /// Installs the APKs that were written to the session.
+  pub async fn commit(mut self) -> adb::Result<()> {
+    debug!(id = self.id, "committing install session");
+
+    // The device discards the session once it has been committed, even if that fails.
+    self.mark_finished();
+    self
+      .run(
+        &["install-commit".to_string(), self.id.to_string()],
+        futures::io::empty(),
+        0,
+      )
+      .await
+  }
+
+  /// Discards the session, and any sessions that were added to it.
+  pub async fn abandon(mut self) -> adb::Result<()> {
+    self.abandon_all().await
+  }
+
+  async fn abandon_all(&mut self) -> adb::Result<()> {
+    debug!(id = self.id, "abandoning install session");
+    self.finished = true;
+
+    // Multi-package sessions can't be nested, so children don't have children of their own.
+    let mut result = Ok(());
+    for mut child in std::mem::take(&mut self.children) {
+      child.finished = true;
+      result = result.and(child.abandon_one().await);
+    }
+    self.abandon_one().await.and(result)
+  }
+
+  async fn abandon_one(&self) -> adb::Result<()> {
+    let args = ["install-abandon".to_string(), self.id.to_string()];
+    self.run(&args, futures::io::empty(), 0).await
+  }
+
+  /// Abandons the session after another error, which is more interesting than any failure to abandon it.
+  pub(crate) async fn abandon_after_error(&mut self) {
+    if let Err(err) = self.abandon_all().await {
+      warn!(id = self.id, error = %err, "failed to abandon install session");
+    }
+  }
+
+  fn mark_finished(&mut self) {
+    self.finished = true;
+    for child in &mut self.children {
+      child.mark_finished();
+    }
+  }
+
+  /// Moves the session out of `self`, leaving a finished placeholder that won't be abandoned when dropped.
+  fn take(&mut self) -> InstallSession {
+    let placeholder = InstallSession {
+      remote: self.remote.clone(),
+      device_criteria: self.device_criteria.clone(),
+      method: self.method,
+      id: self.id,
+      children: Vec::new(),
+      finished: true,
+    };
+    std::mem::replace(self, placeholder)
+  }
+}
+
+impl Drop for InstallSession {
+  fn drop(&mut self) {
+    if self.finished {
+      return;
+    }
+
+    let mut session = self.take();
+    warn!(
+      id = session.id,
+      "abandoning install session that was dropped while in progress"
+    );
+    adb::runtime::spawn(async move { session.abandon_after_error().await });
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::host::TransportId;
+  use crate::runtime::block_on;
+  use crate::testing::{device, FakeServer, Response};
+
+  #[test]
+  fn session_ids() {
+    assert_eq!(
+      1234,
+      parse_session_id("Success: created install session [1234]\n").unwrap()
+    );
+    match parse_session_id("Failure [INSTALL_FAILED_INVALID_APK]") {
+      Err(adb::Error::PackageManagerFailed { code, .. }) => assert_eq!(Some("INSTALL_FAILED_INVALID_APK".into()), code),
+      other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(parse_session_id("Success\n").is_err());
+    assert_eq!("2_base.apk", split_name(2, Path::new("/tmp/base.apk")));
+  }
+
+  fn write_apks(dir: &Path, apks: &[(&str, &[u8])]) -> Vec<std::path::PathBuf> {
+    apks
+      .iter()
+      .map(|(name, data)| {
+        let path = dir.join(name);
+        std::fs::write(&path, data).unwrap();
+        path
+      })
+      .collect()
+  }
+
+  #[test]
+  fn install_multiple() {
+    let dir = tempfile::tempdir().unwrap();
+    let apks = write_apks(dir.path(), &[("base.apk", b"base"), ("split.apk", b"split!")]);
+
+    let server = FakeServer::start().unwrap();
+    server
+      .add_device(device("foo", 1))
+      .set_features(TransportId(1), &["shell_v2", "cmd"]);
+    server.on_device(
+      "exec:cmd package install-create -r -S 10",
+      Response::raw("Success: created install session [42]\n"),
+    );
+    server.on_device(
+      "exec:cmd package install-write -S 4 42 0_base.apk -",
+      Response::input(4, "Success: streamed 4 bytes\n"),
+    );
+    server.on_device(
+      "exec:cmd package install-write -S 6 42 1_split.apk -",
+      Response::input(6, "Success: streamed 6 bytes\n"),
+    );
+    server.on_device("exec:cmd package install-commit 42", Response::raw("Success\n"));
+
+    let options = InstallOptions {
+      replace: true,
+      ..Default::default()
+    };
+    block_on(server.remote().install_multiple(DeviceCriteria::Any, &apks, &options)).unwrap();
+    assert_eq!(vec![b"base".to_vec(), b"split!".to_vec()], server.inputs());
+    server.assert_device_services(&[
+      "exec:cmd package install-create -r -S 10",
+      "exec:cmd package install-write -S 4 42 0_base.apk -",
+      "exec:cmd package install-write -S 6 42 1_split.apk -",
+      "exec:cmd package install-commit 42",
+    ]);
+  }
+
+  #[test]
+  fn abandon_on_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    let apks = write_apks(dir.path(), &[("base.apk", b"base"), ("split.apk", b"split!")]);
+
+    let server = FakeServer::start().unwrap();
+    server
+      .add_device(device("foo", 1))
+      .set_features(TransportId(1), &["shell_v2", "cmd"]);
+    server.on_device(
+      "exec:cmd package install-create -S 10",
+      Response::raw("Success: created install session [42]\n"),
+    );
+    server.on_device(
+      "exec:cmd package install-write -S 4 42 0_base.apk -",
+      Response::input(4, "Failure [INSTALL_FAILED_INVALID_APK: not an apk]\n"),
+    );
+    server.on_device("exec:cmd package install-abandon 42", Response::raw("Success\n"));
+
+    match block_on(
+      server
+        .remote()
+        .install_multiple(DeviceCriteria::Any, &apks, &InstallOptions::default()),
+    ) {
+      Err(adb::Error::PackageManagerFailed { code, .. }) => assert_eq!(Some("INSTALL_FAILED_INVALID_APK".into()), code),
+      other => panic!("unexpected result: {:?}", other),
+    }
+    server.assert_device_services(&[
+      "exec:cmd package install-create -S 10",
+      "exec:cmd package install-write -S 4 42 0_base.apk -",
+      "exec:cmd package install-abandon 42",
+    ]);
+  }
+
+  #[test]
+  fn install_multi_package() {
+    let dir = tempfile::tempdir().unwrap();
+    let apks = write_apks(dir.path(), &[("a.apk", b"a"), ("b.apk", b"bb")]);
+
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1));
+    let service = |args: &[&str]| format!("abb_exec:package\0{}", args.join("\0"));
+    server.on_device(
+      service(&["install-create", "--multi-package"]),
+      Response::raw("Success: created install session [1]\n"),
+    );
+    server.on_device(
+      service(&["install-create", "-S", "1"]),
+      Response::raw("Success: created install session [2]\n"),
+    );
+    server.on_device(
+      service(&["install-create", "-S", "2"]),
+      Response::raw("Success: created install session [3]\n"),
+    );
+    server.on_device(service(&["install-add-session", "1", "2"]), Response::raw("Success\n"));
+    server.on_device(service(&["install-add-session", "1", "3"]), Response::raw("Success\n"));
+    server.on_device(
+      service(&["install-write", "-S", "1", "2", "0_a.apk", "-"]),
+      Response::input(1, "Success\n"),
+    );
+    server.on_device(
+      service(&["install-write", "-S", "2", "3", "0_b.apk", "-"]),
+      Response::input(2, "Failure [INSTALL_FAILED_INSUFFICIENT_STORAGE]\n"),
+    );
+    for id in 1..=3 {
+      server.on_device(
+        service(&["install-abandon", &id.to_string()]),
+        Response::raw("Success\n"),
+      );
+    }
+
+    let result = block_on(server.remote().install_multi_package(
+      DeviceCriteria::Any,
+      &apks,
+      &InstallOptions::default(),
+    ));
+    assert!(result.is_err());
+
+    // Both children are abandoned along with the parent.
+    let services = server.device_services();
+    let abandoned: Vec<_> = services
+      .iter()
+      .filter(|service| service.contains("install-abandon"))
+      .cloned()
+      .collect();
+    assert_eq!(
+      vec![
+        service(&["install-abandon", "2"]),
+        service(&["install-abandon", "3"]),
+        service(&["install-abandon", "1"])
+      ],
+      abandoned
+    );
+    assert!(!services.iter().any(|service| service.contains("install-commit")));
+  }
+}
@@ -0,0 +1,265 @@
+//! Decoding of binary event log payloads, as logged to the events buffer with `EventLog.writeEvent`.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use std::collections::HashMap;
+
+use crate as adb;
+use crate::client::Remote;
+use crate::host::DeviceCriteria;
+
+/// Path on the device of the definitions of event log tags.
+pub const EVENT_LOG_TAGS_PATH: &str = "/system/etc/event-log-tags";
+
+/// Maximum nesting of lists in an event, to bound recursion on malformed payloads.
+const MAX_DEPTH: usize = 8;
+
+/// A value in an event log payload.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventValue {
+  Int(i32),
+  Long(i64),
+  Float(f32),
+  String(String),
+  List(Vec<EventValue>),
+}
+
+impl std::fmt::Display for EventValue {
+  /// Formats the value like logcat, with lists as `[a,b,c]`.
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      EventValue::Int(value) => write!(f, "{}", value),
+      EventValue::Long(value) => write!(f, "{}", value),
+      EventValue::Float(value) => write!(f, "{}", value),
+      EventValue::String(value) => f.write_str(value),
+      EventValue::List(values) => {
+        f.write_str("[")?;
+        for (i, value) in values.iter().enumerate() {
+          if i > 0 {
+            f.write_str(",")?;
+          }
+          write!(f, "{}", value)?;
+        }
+        f.write_str("]")
+      }
+    }
+  }
+}
+
+/// A field of an event, named if its tag is defined in the device's event log tags.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventField {
+  pub name: Option<String>,
+  pub value: EventValue,
+}
+
+/// A decoded event from the events buffer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+  pub tag: u32,
+
+  /// The name of the tag (e.g. `am_anr`), if it's defined in the device's event log tags.
+  pub name: Option<String>,
+
+  /// The fields of the event. An event that logged a list has a field for every element of the list.
+  pub fields: Vec<EventField>,
+}
+
+impl Event {
+  /// Returns the value of the field with the given name.
+  pub fn field(&self, name: &str) -> Option<&EventValue> {
+    self
+      .fields
+      .iter()
+      .find(|field| field.name.as_deref() == Some(name))
+      .map(|field| &field.value)
+  }
+}
+
+/// The definition of an event log tag.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventTag {
+  pub tag: u32,
+  pub name: String,
+
+  /// The names of the tag's fields, in order.
+  pub fields: Vec<String>,
+}
+
+/// Definitions of event log tags, as listed in [EVENT_LOG_TAGS_PATH].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EventTags {
+  tags: HashMap<u32, EventTag>,
+}
+
+impl EventTags {
+  /// Parses the contents of an event log tags file.
+  ///
+  /// Every line has a tag number, a name and optionally a list of fields like `(name|type|units),(name|type)`.
+  /// Comments and malformed lines are ignored.
+  pub fn parse(contents: &str) -> EventTags {
+    let mut tags = HashMap::new();
+    for line in contents.lines() {
+      let line = line.split('#').next().unwrap_or_default().trim();
+      let mut parts = line.splitn(3, char::is_whitespace);
+      let (tag, name) = match (parts.next().and_then(|tag| tag.parse().ok()), parts.next()) {
+        (Some(tag), Some(name)) => (tag, name.to_string()),
+        _ => continue,
+      };
+
+      let fields = parts
+        .next()
+        .unwrap_or_default()
+        .split('(')
+        .skip(1)
+        .map(|field| field.split(['|', ')']).next().unwrap_or_default().trim().to_string())
+        .collect();
+      tags.insert(tag, EventTag { tag, name, fields });
+    }
+    EventTags { tags }
+  }
+
+  pub fn get(&self, tag: u32) -> Option<&EventTag> {
+    self.tags.get(&tag)
+  }
+
+  pub fn len(&self) -> usize {
+    self.tags.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.tags.is_empty()
+  }
+
+  /// Decodes the payload of an event with a tag, naming its fields with the tag's definition.
+  ///
+  /// Returns `None` if the payload isn't a valid event.
+  pub fn decode(&self, tag: u32, payload: &[u8]) -> Option<Event> {
+    let (value, rest) = decode_value(payload, 0)?;
+
+    // Some writers terminate events with a newline.
+    if !rest.is_empty() && rest != b"\n" {
+      return None;
+    }
+
+    let definition = self.get(tag);
+    let values = match value {
+      EventValue::List(values) => values,
+      value => vec![value],
+    };
+    let fields = values
+      .into_iter()
+      .enumerate()
+      .map(|(i, value)| EventField {
+        name: definition.and_then(|definition| definition.fields.get(i).cloned()),
+        value,
+      })
+      .collect();
+
+    Some(Event {
+      tag,
+      name: definition.map(|definition| definition.name.clone()),
+      fields,
+    })
+  }
+}
+
+/// Decodes a typed value, returning it and the rest of the payload.
+fn decode_value(payload: &[u8], depth: usize) -> Option<(EventValue, &[u8])> {
+  let (&kind, payload) = payload.split_first()?;
+  let take = |len: usize| (payload.len() >= len).then(|| payload.split_at(len));
+  match kind {
+    0 => take(4).map(|(value, rest)| (EventValue::Int(LittleEndian::read_i32(value)), rest)),
+    1 => take(8).map(|(value, rest)| (EventValue::Long(LittleEndian::read_i64(value)), rest)),
+    2 => {
+      let (len, payload) = take(4)?;
+      let len = LittleEndian::read_u32(len) as usize;
+      if payload.len() < len {
+        return None;
+      }
+      let (value, rest) = payload.split_at(len);
+      Some((EventValue::String(String::from_utf8_lossy(value).into_owned()), rest))
+    }
+    3 if depth < MAX_DEPTH => {
+      let (&count, mut payload) = payload.split_first()?;
+      let mut values = Vec::with_capacity(count as usize);
+      for _ in 0..count {
+        let (value, rest) = decode_value(payload, depth + 1)?;
+        values.push(value);
+        payload = rest;
+      }
+      Some((EventValue::List(values), payload))
+    }
+    4 => take(4).map(|(value, rest)| (EventValue::Float(LittleEndian::read_f32(value)), rest)),
+    _ => None,
+  }
+}
+
+impl Remote {
+  /// Reads the definitions of event log tags from a device.
+  pub async fn event_log_tags(&self, device_criteria: DeviceCriteria) -> adb::Result<EventTags> {
+    let output = self
+      .shell_output_checked(device_criteria, ["cat", EVENT_LOG_TAGS_PATH])
+      .await?;
+    Ok(EventTags::parse(&String::from_utf8_lossy(&output.stdout)))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const TAGS: &str = "# comment
+42 answer (to life the universe etc|3)
+2722 battery_level (level|1|6),(voltage|1|1),(temperature|1|1)
+30008 am_anr (User|1|5),(pid|1|5),(Package Name|3),(Flags|1|5),(reason|3)
+314 pi
+bogus line
+";
+
+  #[test]
+  fn parse_tags() {
+    let tags = EventTags::parse(TAGS);
+    assert_eq!(4, tags.len());
+    assert_eq!(vec!["to life the universe etc"], tags.get(42).unwrap().fields);
+    assert_eq!(
+      vec!["User", "pid", "Package Name", "Flags", "reason"],
+      tags.get(30008).unwrap().fields
+    );
+    assert_eq!("pi", tags.get(314).unwrap().name);
+    assert!(tags.get(314).unwrap().fields.is_empty());
+  }
+
+  #[test]
+  fn decode() {
+    let tags = EventTags::parse(TAGS);
+
+    let payload = b"\x03\x03\x00\x5f\0\0\0\x01\x68\x10\0\0\0\0\0\0\x04\0\0\x80\x3f";
+    let event = tags.decode(2722, payload).unwrap();
+    assert_eq!(Some("battery_level"), event.name.as_deref());
+    assert_eq!(Some(&EventValue::Int(95)), event.field("level"));
+    assert_eq!(Some(&EventValue::Long(4200)), event.field("voltage"));
+    assert_eq!(Some(&EventValue::Float(1.0)), event.field("temperature"));
+
+    let event = tags.decode(1, b"\x02\x03\0\0\0foo\n").unwrap();
+    assert_eq!(None, event.name);
+    assert_eq!(
+      vec![EventField {
+        name: None,
+        value: EventValue::String("foo".into())
+      }],
+      event.fields
+    );
+
+    let nested = EventValue::List(vec![
+      EventValue::Int(1),
+      EventValue::List(vec![EventValue::String("a".into())]),
+    ]);
+    assert_eq!("[1,[a]]", nested.to_string());
+
+    assert!(tags.decode(1, b"\x02\x04\0\0\0foo").is_none());
+    assert!(tags.decode(1, b"\x03\x02\x00\x01\0\0\0").is_none());
+    assert!(tags.decode(1, b"\x07").is_none());
+    assert!(tags.decode(1, &[3, 1].repeat(100)).is_none());
+  }
+}
@@ -0,0 +1,682 @@
+//! Reading the log of a device with logcat.
+//!
+//! Log entries are read in logcat's binary format (`logcat -B`), which contains the raw `logger_entry` records from
+//! logd, so that they can be decoded exactly instead of parsing logcat's text output.
+
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::ready;
+use futures::stream::Stream;
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use tracing::{debug, trace};
+
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate as adb;
+use crate::client::shell::buffer::poll_fill;
+use crate::client::shell::quote;
+use crate::client::Remote;
+use crate::core::Socket;
+use crate::host::DeviceCriteria;
+use crate::util::hexdump;
+
+mod events;
+pub use events::*;
+
+mod wait;
+pub use wait::{LogMatch, LogMatcher};
+
+/// Size of a version 1 `logger_entry` header, which doesn't record its own size.
+const HEADER_V1_SIZE: usize = 20;
+
+/// Largest header size that is accepted. Headers larger than the known versions are skipped over, but anything much
+/// larger is more likely to be text (e.g. an error message from logcat) than a log entry.
+const MAX_HEADER_SIZE: usize = 64;
+
+/// A log buffer on the device.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, FromPrimitive)]
+#[repr(u8)]
+pub enum LogBuffer {
+  Main = 0,
+  Radio = 1,
+  Events = 2,
+  System = 3,
+  Crash = 4,
+  Stats = 5,
+  Security = 6,
+  Kernel = 7,
+}
+
+impl LogBuffer {
+  /// Returns the name of the buffer, as used by logcat's `-b` option.
+  pub fn name(&self) -> &'static str {
+    match self {
+      LogBuffer::Main => "main",
+      LogBuffer::Radio => "radio",
+      LogBuffer::Events => "events",
+      LogBuffer::System => "system",
+      LogBuffer::Crash => "crash",
+      LogBuffer::Stats => "stats",
+      LogBuffer::Security => "security",
+      LogBuffer::Kernel => "kernel",
+    }
+  }
+
+  /// Returns whether entries in the buffer are binary events, rather than text messages.
+  pub fn is_binary(&self) -> bool {
+    matches!(self, LogBuffer::Events | LogBuffer::Stats | LogBuffer::Security)
+  }
+}
+
+impl std::fmt::Display for LogBuffer {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+impl std::str::FromStr for LogBuffer {
+  type Err = adb::Error;
+
+  fn from_str(s: &str) -> adb::Result<LogBuffer> {
+    (0..=7)
+      .filter_map(LogBuffer::from_u8)
+      .find(|buffer| buffer.name() == s)
+      .ok_or_else(|| adb::Error::InvalidArgument(format!("unknown log buffer '{}'", s)))
+  }
+}
+
+/// The priority of a log message.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, FromPrimitive)]
+#[repr(u8)]
+pub enum Priority {
+  Unknown = 0,
+  Default = 1,
+  Verbose = 2,
+  Debug = 3,
+  Info = 4,
+  Warn = 5,
+  Error = 6,
+  Fatal = 7,
+  Silent = 8,
+}
+
+impl Priority {
+  /// Returns the letter that logcat uses for the priority, in its output and in filterspecs.
+  pub fn letter(&self) -> char {
+    match self {
+      Priority::Unknown | Priority::Default => '?',
+      Priority::Verbose => 'V',
+      Priority::Debug => 'D',
+      Priority::Info => 'I',
+      Priority::Warn => 'W',
+      Priority::Error => 'E',
+      Priority::Fatal => 'F',
+      Priority::Silent => 'S',
+    }
+  }
+}
+
+/// The payload of a log entry.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogMessage {
+  /// A text message, as logged by `android.util.Log` or `__android_log_print`.
+  Text {
+    priority: Priority,
+    tag: String,
+    message: String,
+  },
+
+  /// An event from the events or security buffers, decoded from its binary payload.
+  Event(Event),
+
+  /// A binary payload that couldn't be decoded as an event, identified by a numeric tag.
+  Binary { tag: u32, data: Bytes },
+}
+
+impl LogMessage {
+  /// Returns the priority of the message. Events are always [Priority::Info].
+  pub fn priority(&self) -> Priority {
+    match self {
+      LogMessage::Text { priority, .. } => *priority,
+      LogMessage::Event(_) | LogMessage::Binary { .. } => Priority::Info,
+    }
+  }
+
+  /// Returns the tag of the message, with the names of events falling back to their numbers.
+  pub fn tag(&self) -> Cow<'_, str> {
+    match self {
+      LogMessage::Text { tag, .. } => Cow::Borrowed(tag),
+      LogMessage::Event(Event { name: Some(name), .. }) => Cow::Borrowed(name),
+      LogMessage::Event(Event { tag, .. }) | LogMessage::Binary { tag, .. } => Cow::Owned(tag.to_string()),
+    }
+  }
+
+  /// Returns the message as text, with the values of events formatted like logcat.
+  pub fn text(&self) -> Cow<'_, str> {
+    match self {
+      LogMessage::Text { message, .. } => Cow::Borrowed(message),
+      LogMessage::Event(event) => match &event.fields[..] {
+        [field] => Cow::Owned(field.value.to_string()),
+        fields => Cow::Owned(EventValue::List(fields.iter().map(|field| field.value.clone()).collect()).to_string()),
+      },
+      LogMessage::Binary { data, .. } => Cow::Owned(format!("[{} bytes]", data.len())),
+    }
+  }
+}
+
+/// An entry read from a device's log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogEntry {
+  pub pid: i32,
+  pub tid: u32,
+
+  /// The time at which the entry was logged, in seconds and nanoseconds since the Unix epoch.
+  pub sec: u32,
+  pub nsec: u32,
+
+  /// The buffer that the entry was logged to. Devices older than Android 5.0 don't report it.
+  pub buffer: Option<LogBuffer>,
+
+  /// The uid of the process that logged the entry, if reported by the device.
+  pub uid: Option<u32>,
+
+  pub message: LogMessage,
+}
+
+/// Where to start reading the log, with logcat's `-T` option.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogStart {
+  /// Start with the most recent number of entries.
+  Count(u32),
+
+  /// Start at a time, in seconds and nanoseconds since the Unix epoch.
+  Timestamp { sec: u32, nsec: u32 },
+
+  /// Start at a time in one of the formats accepted by logcat, like `MM-DD hh:mm:ss.mmm`.
+  Time(String),
+}
+
+impl std::str::FromStr for LogStart {
+  type Err = adb::Error;
+
+  /// Parses a count, a `sec.nsec` timestamp, or anything else as a time for logcat to parse.
+  fn from_str(s: &str) -> adb::Result<LogStart> {
+    if s.is_empty() {
+      return Err(adb::Error::InvalidArgument("empty log start time".into()));
+    }
+
+    if let Ok(count) = s.parse() {
+      return Ok(LogStart::Count(count));
+    }
+
+    let timestamp = s.split_once('.').and_then(|(sec, fraction)| {
+      let digits = |s: &str| !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit());
+      if !digits(sec) || !digits(fraction) || fraction.len() > 9 {
+        return None;
+      }
+      let nsec = format!("{:0<9}", fraction).parse().ok()?;
+      Some(LogStart::Timestamp {
+        sec: sec.parse().ok()?,
+        nsec,
+      })
+    });
+    Ok(timestamp.unwrap_or_else(|| LogStart::Time(s.into())))
+  }
+}
+
+/// Builder for a [Logcat] session.
+#[derive(Clone, Debug, Default)]
+pub struct LogcatBuilder {
+  buffers: Vec<LogBuffer>,
+  all_buffers: bool,
+  start: Option<LogStart>,
+  dump: bool,
+  filterspecs: Vec<String>,
+  event_tags: Option<Arc<EventTags>>,
+  load_event_tags: bool,
+}
+
+impl LogcatBuilder {
+  pub fn new() -> LogcatBuilder {
+    LogcatBuilder::default()
+  }
+
+  /// Adds a buffer to read from. If no buffers are added, logcat's defaults are used (usually main, system and
+  /// crash).
+  pub fn buffer(&mut self, buffer: LogBuffer) -> &mut LogcatBuilder {
+    if !self.buffers.contains(&buffer) {
+      self.buffers.push(buffer);
+    }
+    self
+  }
+
+  /// Sets whether to read from all of the buffers that the device has.
+  pub fn all_buffers(&mut self, enabled: bool) -> &mut LogcatBuilder {
+    self.all_buffers = enabled;
+    self
+  }
+
+  /// Sets where to start reading, or `None` to start at the beginning of the buffers.
+  pub fn start(&mut self, start: Option<LogStart>) -> &mut LogcatBuilder {
+    self.start = start;
+    self
+  }
+
+  /// Sets whether to stop after reading the entries that are already in the log, instead of waiting for new ones.
+  pub fn dump(&mut self, enabled: bool) -> &mut LogcatBuilder {
+    self.dump = enabled;
+    self
+  }
+
+  /// Adds a filterspec, like `ActivityManager:I` or `*:S`, to select which entries are read.
+  pub fn filterspec(&mut self, filterspec: impl Into<String>) -> &mut LogcatBuilder {
+    self.filterspecs.push(filterspec.into());
+    self
+  }
+
+  /// Sets the definitions of event log tags used to name the fields of events, instead of loading them from the
+  /// device.
+  pub fn event_tags(&mut self, event_tags: Option<EventTags>) -> &mut LogcatBuilder {
+    self.event_tags = event_tags.map(Arc::new);
+    self
+  }
+
+  /// Sets whether to load the definitions of event log tags from the device when connecting, if they weren't set with
+  /// [LogcatBuilder::event_tags]. Events are decoded regardless, but their fields are only named with definitions.
+  pub fn load_event_tags(&mut self, enabled: bool) -> &mut LogcatBuilder {
+    self.load_event_tags = enabled;
+    self
+  }
+
+  /// Returns the logcat command line for the options.
+  pub(crate) fn command_line(&self) -> String {
+    let mut args = vec!["logcat".to_string(), "-B".into()];
+    if self.all_buffers {
+      args.push("-b".into());
+      args.push("all".into());
+    } else {
+      for buffer in &self.buffers {
+        args.push("-b".into());
+        args.push(buffer.name().into());
+      }
+    }
+
+    if self.dump {
+      args.push("-d".into());
+    }
+
+    match &self.start {
+      None => {}
+      Some(LogStart::Count(count)) => args.push(format!("-T{}", count)),
+      Some(LogStart::Timestamp { sec, nsec }) => args.push(format!("-T{}.{:09}", sec, nsec)),
+      Some(LogStart::Time(time)) => args.push(format!("-T{}", time)),
+    }
+
+    args.extend(self.filterspecs.iter().cloned());
+    args.iter().map(|arg| quote(arg)).collect::<Vec<_>>().join(" ")
+  }
+
+  /// Starts logcat on a device.
+  pub async fn connect(&self, remote: Remote, device_criteria: DeviceCriteria) -> adb::Result<Logcat> {
+    // exec: doesn't mangle binary output with a pty or newline translation, unlike shell:.
+    let service = format!("exec:{}", self.command_line());
+    debug!(service = %service, "starting logcat");
+    let event_tags = match &self.event_tags {
+      Some(event_tags) => event_tags.clone(),
+      None if self.load_event_tags => match remote.event_log_tags(device_criteria.clone()).await {
+        Ok(event_tags) => Arc::new(event_tags),
+        Err(err) => {
+          debug!(error = %err, "failed to load event log tags");
+          Default::default()
+        }
+      },
+      None => Default::default(),
+    };
+
+    let (_, channel) = remote.open_device_channel(device_criteria, service).await?;
+    Ok(Logcat::new(channel, event_tags))
+  }
+}
+
+/// A stream of entries from a device's log, created with [LogcatBuilder::connect].
+///
+/// The stream ends when logcat exits, which only happens by itself with [LogcatBuilder::dump].
+pub struct Logcat {
+  read: Box<dyn Socket>,
+  buf: BytesMut,
+  done: bool,
+  event_tags: Arc<EventTags>,
+}
+
+impl Logcat {
+  fn new(read: Box<dyn Socket>, event_tags: Arc<EventTags>) -> Logcat {
+    Logcat {
+      read,
+      buf: BytesMut::new(),
+      done: false,
+      event_tags,
+    }
+  }
+}
+
+impl Stream for Logcat {
+  type Item = adb::Result<LogEntry>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+    let this = &mut *self;
+    if this.done {
+      return Poll::Ready(None);
+    }
+
+    loop {
+      if let Some(result) = parse_entry(&mut this.buf, &this.event_tags) {
+        if result.is_err() {
+          this.done = true;
+        }
+        return Poll::Ready(Some(result));
+      }
+
+      match ready!(poll_fill(&mut this.read, &mut this.buf, cx)) {
+        Ok(0) => {
+          this.done = true;
+          if this.buf.is_empty() {
+            return Poll::Ready(None);
+          }
+          let msg = format!("logcat exited in the middle of an entry: {}", hexdump(&this.buf));
+          return Poll::Ready(Some(Err(adb::Error::UnexpectedData(msg))));
+        }
+        Ok(_) => {}
+        Err(err) => {
+          this.done = true;
+          return Poll::Ready(Some(Err(err.into())));
+        }
+      }
+    }
+  }
+}
+
+/// Parses a `logger_entry` record out of the buffer, if a complete one has been received.
+///
+/// The header has the following layouts, all little-endian:
+///   v1: u16 len, u16 padding (0), i32 pid, u32 tid, u32 sec, u32 nsec
+///   v2: u16 len, u16 hdr_size (24), i32 pid, u32 tid, u32 sec, u32 nsec, u32 euid
+///   v3: u16 len, u16 hdr_size (24), i32 pid, u32 tid, u32 sec, u32 nsec, u32 lid
+///   v4: u16 len, u16 hdr_size (28), i32 pid, u32 tid, u32 sec, u32 nsec, u32 lid, u32 uid
+fn parse_entry(buf: &mut BytesMut, event_tags: &EventTags) -> Option<adb::Result<LogEntry>> {
+  if buf.len() < 4 {
+    return None;
+  }
+
+  let payload_len = LittleEndian::read_u16(&buf[0..2]) as usize;
+  let header_size = match LittleEndian::read_u16(&buf[2..4]) as usize {
+    0 => HEADER_V1_SIZE,
+    size if (HEADER_V1_SIZE..=MAX_HEADER_SIZE).contains(&size) => size,
+    _ => {
+      // logcat reports errors (e.g. for invalid arguments) as text on the same stream.
+      let text = String::from_utf8_lossy(buf);
+      return Some(Err(adb::Error::UnexpectedData(format!(
+        "unexpected logcat output: {}",
+        text.trim_end()
+      ))));
+    }
+  };
+
+  if buf.len() < header_size + payload_len {
+    buf.reserve(header_size + payload_len - buf.len());
+    return None;
+  }
+
+  let header = buf.split_to(header_size);
+  let payload = buf.split_to(payload_len).freeze();
+  trace!(header = %hexdump(&header), payload = %hexdump(&payload), "received log entry");
+
+  // v2 and v3 headers have the same size, but v2 was only produced by the kernel logger before logd, which doesn't
+  // use the field for a log id, and whose euids aren't valid log ids except for root.
+  let (buffer, uid) = match header_size {
+    24 => {
+      let value = LittleEndian::read_u32(&header[20..24]);
+      match u8::try_from(value).ok().and_then(LogBuffer::from_u8) {
+        Some(buffer) => (Some(buffer), None),
+        None => (None, Some(value)),
+      }
+    }
+    size if size >= 28 => (
+      u8::try_from(LittleEndian::read_u32(&header[20..24]))
+        .ok()
+        .and_then(LogBuffer::from_u8),
+      Some(LittleEndian::read_u32(&header[24..28])),
+    ),
+    _ => (None, None),
+  };
+
+  Some(Ok(LogEntry {
+    pid: LittleEndian::read_i32(&header[4..8]),
+    tid: LittleEndian::read_u32(&header[8..12]),
+    sec: LittleEndian::read_u32(&header[12..16]),
+    nsec: LittleEndian::read_u32(&header[16..20]),
+    buffer,
+    uid,
+    message: parse_message(buffer, payload, event_tags),
+  }))
+}
+
+/// Parses the payload of a log entry.
+///
+/// Text messages consist of a priority byte, followed by a NUL-terminated tag and a NUL-terminated message, and
+/// binary events start with a u32 tag. Without a log id, binary events can only be recognized by not being valid text
+/// messages.
+fn parse_message(buffer: Option<LogBuffer>, payload: Bytes, event_tags: &EventTags) -> LogMessage {
+  let binary = |mut payload: Bytes| {
+    if payload.len() < 4 {
+      return LogMessage::Binary { tag: 0, data: payload };
+    }
+
+    let tag = payload.get_u32_le();
+    match event_tags.decode(tag, &payload) {
+      Some(event) => LogMessage::Event(event),
+      None => LogMessage::Binary { tag, data: payload },
+    }
+  };
+
+  if buffer.is_some_and(|buffer| buffer.is_binary()) || payload.is_empty() {
+    return binary(payload);
+  }
+
+  let priority = match Priority::from_u8(payload[0]) {
+    Some(priority) => priority,
+    None if buffer.is_none() => return binary(payload),
+    None => Priority::Unknown,
+  };
+
+  let mut parts = payload[1..].splitn(2, |&c| c == 0);
+  let tag = parts.next().unwrap_or_default();
+  let message = match parts.next() {
+    Some(message) => message,
+    None if buffer.is_none() => return binary(payload),
+    None => &[],
+  };
+  let message = message.strip_suffix(b"\0").unwrap_or(message);
+
+  LogMessage::Text {
+    priority,
+    tag: String::from_utf8_lossy(tag).into_owned(),
+    message: String::from_utf8_lossy(message).into_owned(),
+  }
+}
+
+impl Remote {
+  /// Reads a device's log with the default [LogcatBuilder] options, waiting for new entries.
+  pub async fn logcat(&self, device_criteria: DeviceCriteria) -> adb::Result<Logcat> {
+    LogcatBuilder::new().connect(self.clone(), device_criteria).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::runtime::block_on;
+  use crate::testing::{device, FakeServer, Response};
+  use futures::stream::StreamExt;
+
+  fn entry_v4(lid: u32, pid: i32, sec: u32, payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    data.extend_from_slice(&28u16.to_le_bytes());
+    data.extend_from_slice(&pid.to_le_bytes());
+    data.extend_from_slice(&(pid as u32 + 1).to_le_bytes());
+    data.extend_from_slice(&sec.to_le_bytes());
+    data.extend_from_slice(&5_000_000u32.to_le_bytes());
+    data.extend_from_slice(&lid.to_le_bytes());
+    data.extend_from_slice(&10042u32.to_le_bytes());
+    data.extend_from_slice(payload);
+    data
+  }
+
+  #[test]
+  fn header_versions() {
+    let mut v1 = b"\x07\0\0\0\x2a\0\0\0\x2b\0\0\0\x01\0\0\0\x02\0\0\0".to_vec();
+    v1.extend_from_slice(b"\x04tag\0m\0");
+    let mut buf = BytesMut::from(&v1[..]);
+    let entry = parse_entry(&mut buf, &EventTags::default()).unwrap().unwrap();
+    assert_eq!(
+      (42, 43, 1, 2, None, None),
+      (entry.pid, entry.tid, entry.sec, entry.nsec, entry.buffer, entry.uid)
+    );
+    assert_eq!(
+      LogMessage::Text {
+        priority: Priority::Info,
+        tag: "tag".into(),
+        message: "m".into()
+      },
+      entry.message
+    );
+    assert!(buf.is_empty());
+
+    let mut v3 = b"\x07\0\x18\0\x2a\0\0\0\x2b\0\0\0\x01\0\0\0\x02\0\0\0\x03\0\0\0".to_vec();
+    v3.extend_from_slice(b"\x06tag\0m\0");
+    let entry = parse_entry(&mut BytesMut::from(&v3[..]), &EventTags::default())
+      .unwrap()
+      .unwrap();
+    assert_eq!((Some(LogBuffer::System), None), (entry.buffer, entry.uid));
+
+    let entry = parse_entry(
+      &mut BytesMut::from(&entry_v4(2, 1, 2, b"\x01\0\0\0xyz")[..]),
+      &EventTags::default(),
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!((Some(LogBuffer::Events), Some(10042)), (entry.buffer, entry.uid));
+    assert_eq!(
+      LogMessage::Binary {
+        tag: 1,
+        data: Bytes::from_static(b"xyz")
+      },
+      entry.message
+    );
+  }
+
+  #[test]
+  fn partial_entries() {
+    let data = entry_v4(0, 1, 2, b"\x05tag\0message\0");
+    let mut buf = BytesMut::new();
+    for &byte in &data[..data.len() - 1] {
+      buf.extend_from_slice(&[byte]);
+      assert!(parse_entry(&mut buf, &EventTags::default()).is_none());
+    }
+    buf.extend_from_slice(&data[data.len() - 1..]);
+    assert!(parse_entry(&mut buf, &EventTags::default()).unwrap().is_ok());
+
+    let mut buf = BytesMut::from(&b"logcat: Unknown buffer 'foo'\n"[..]);
+    match parse_entry(&mut buf, &EventTags::default()) {
+      Some(Err(adb::Error::UnexpectedData(msg))) => assert!(msg.contains("Unknown buffer")),
+      other => panic!("unexpected result: {:?}", other),
+    }
+  }
+
+  #[test]
+  fn command_lines() {
+    let mut builder = LogcatBuilder::new();
+    assert_eq!("logcat -B", builder.command_line());
+
+    builder
+      .buffer(LogBuffer::Main)
+      .buffer(LogBuffer::Crash)
+      .dump(true)
+      .start(Some(LogStart::Timestamp { sec: 12, nsec: 34 }))
+      .filterspec("ActivityManager:I")
+      .filterspec("*:S");
+    assert_eq!(
+      "logcat -B -b main -b crash -d -T12.000000034 ActivityManager:I '*:S'",
+      builder.command_line()
+    );
+
+    builder
+      .all_buffers(true)
+      .start(Some(LogStart::Time("01-02 03:04:05.678".into())));
+    assert_eq!(
+      "logcat -B -b all -d '-T01-02 03:04:05.678' ActivityManager:I '*:S'",
+      builder.command_line()
+    );
+
+    assert_eq!(LogStart::Count(10), "10".parse().unwrap());
+    assert_eq!(
+      LogStart::Timestamp {
+        sec: 12,
+        nsec: 500_000_000
+      },
+      "12.5".parse().unwrap()
+    );
+    assert_eq!(
+      LogStart::Time("01-02 03:04:05.678".into()),
+      "01-02 03:04:05.678".parse().unwrap()
+    );
+    assert_eq!(LogBuffer::Security, "security".parse().unwrap());
+    assert!("foo".parse::<LogBuffer>().is_err());
+  }
+
+  #[test]
+  fn logcat() {
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1));
+    let mut output = entry_v4(0, 100, 1, b"\x04first\0hello\0");
+    output.extend(entry_v4(3, 200, 2, b"\x06second\0world\n\0"));
+    output.extend(entry_v4(2, 300, 3, b"\x2a\0\0\0\x03\x01\x00\x2a\0\0\0"));
+    server.on_device("exec:logcat -B -d", Response::raw(output));
+    server.on_shell("cat /system/etc/event-log-tags", b"42 answer (value|1)\n", b"", 0);
+
+    let entries: Vec<_> = block_on(async {
+      let logcat = LogcatBuilder::new()
+        .dump(true)
+        .load_event_tags(true)
+        .connect(server.remote(), DeviceCriteria::Any)
+        .await
+        .unwrap();
+      logcat.collect().await
+    });
+
+    let entries: Vec<_> = entries.into_iter().map(Result::unwrap).collect();
+    assert_eq!(3, entries.len());
+    assert_eq!((100, Some(LogBuffer::Main)), (entries[0].pid, entries[0].buffer));
+    assert_eq!(
+      LogMessage::Text {
+        priority: Priority::Error,
+        tag: "second".into(),
+        message: "world\n".into()
+      },
+      entries[1].message
+    );
+
+    match &entries[2].message {
+      LogMessage::Event(event) => {
+        assert_eq!(Some("answer"), event.name.as_deref());
+        assert_eq!(Some(&EventValue::Int(42)), event.field("value"));
+      }
+      message => panic!("unexpected message: {:?}", message),
+    }
+  }
+}
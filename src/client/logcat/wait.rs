@@ -0,0 +1,200 @@
+//! Waiting for entries to appear in a device's log.
+
+use futures::stream::StreamExt;
+use regex::Regex;
+use tracing::debug;
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate as adb;
+use crate::client::logcat::{LogEntry, Logcat, Priority};
+use crate::client::policy::with_timeout;
+
+/// A predicate on log entries, for [Logcat::wait_for].
+///
+/// An entry matches if it matches all of the conditions that were set.
+#[derive(Clone, Debug, Default)]
+pub struct LogMatcher {
+  tag: Option<String>,
+  priority: Option<Priority>,
+  regex: Option<Regex>,
+}
+
+impl LogMatcher {
+  pub fn new() -> LogMatcher {
+    LogMatcher::default()
+  }
+
+  /// Matches entries with a tag, or events with a name.
+  pub fn tag(&mut self, tag: impl Into<String>) -> &mut LogMatcher {
+    self.tag = Some(tag.into());
+    self
+  }
+
+  /// Matches entries with at least a priority.
+  pub fn priority(&mut self, priority: Priority) -> &mut LogMatcher {
+    self.priority = Some(priority);
+    self
+  }
+
+  /// Matches entries whose message matches a regex anywhere. Events are matched as they are formatted by logcat.
+  pub fn regex(&mut self, regex: Regex) -> &mut LogMatcher {
+    self.regex = Some(regex);
+    self
+  }
+
+  pub fn matches(&self, entry: &LogEntry) -> bool {
+    let message = &entry.message;
+    self.tag.as_ref().is_none_or(|tag| message.tag() == tag.as_str())
+      && self.priority.is_none_or(|priority| message.priority() >= priority)
+      && self.regex.as_ref().is_none_or(|regex| regex.is_match(&message.text()))
+  }
+}
+
+/// An entry found by [Logcat::wait_for].
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogMatch {
+  pub entry: LogEntry,
+
+  /// The entries that were read right before the match, oldest first.
+  pub context: Vec<LogEntry>,
+}
+
+impl Logcat {
+  /// Reads entries until one matches, and returns it along with up to `context` entries that preceded it.
+  ///
+  /// Unless logcat was started with [LogcatBuilder::start](crate::client::logcat::LogcatBuilder::start), this also
+  /// matches entries that were already in the log. Fails with [Error::TimedOut](crate::Error::TimedOut) if nothing
+  /// matches in time, or [Error::Closed](crate::Error::Closed) if logcat exits first. Entries that were read remain
+  /// consumed either way, so the stream can be used to wait again.
+  pub async fn wait_for(
+    &mut self,
+    matcher: &LogMatcher,
+    timeout: Option<Duration>,
+    context: usize,
+  ) -> adb::Result<LogMatch> {
+    debug!(matcher = ?matcher, timeout = ?timeout, "waiting for log entry");
+    with_timeout(timeout, "waiting for log entry", async {
+      let mut preceding = VecDeque::with_capacity(context);
+      while let Some(entry) = self.next().await {
+        let entry = entry?;
+        if matcher.matches(&entry) {
+          return Ok(LogMatch {
+            entry,
+            context: preceding.into(),
+          });
+        }
+
+        if context > 0 {
+          if preceding.len() == context {
+            preceding.pop_front();
+          }
+          preceding.push_back(entry);
+        }
+      }
+      Err(adb::Error::Closed)
+    })
+    .await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::client::logcat::{LogMessage, LogcatBuilder};
+  use crate::host::DeviceCriteria;
+  use crate::runtime::block_on;
+  use crate::testing::{device, FakeServer, Response};
+  use std::pin::Pin;
+  use std::task::{Context, Poll};
+
+  fn entry(priority: u8, tag: &str, message: &str) -> Vec<u8> {
+    let payload = [&[priority][..], tag.as_bytes(), b"\0", message.as_bytes(), b"\0"].concat();
+    let mut data = Vec::new();
+    data.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    data.extend_from_slice(&[0; 18]);
+    data.extend_from_slice(&payload);
+    data
+  }
+
+  #[test]
+  fn wait_for() {
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1));
+    let output = [
+      entry(4, "ActivityManager", "Start proc com.foo"),
+      entry(3, "ActivityTaskManager", "Displayed com.foo/.Main: +1s"),
+      entry(4, "ActivityTaskManager", "Displayed com.foo/.Main: +2s"),
+      entry(4, "marker", "done"),
+    ]
+    .concat();
+    server.on_device("exec:logcat -B -d", Response::raw(output));
+
+    block_on(async {
+      let mut logcat = LogcatBuilder::new()
+        .dump(true)
+        .connect(server.remote(), DeviceCriteria::Any)
+        .await
+        .unwrap();
+
+      let found = logcat
+        .wait_for(
+          LogMatcher::new()
+            .tag("ActivityTaskManager")
+            .priority(Priority::Info)
+            .regex(Regex::new(r"Displayed com\.foo/\.Main").unwrap()),
+          Some(Duration::from_secs(10)),
+          5,
+        )
+        .await
+        .unwrap();
+      assert_eq!("Displayed com.foo/.Main: +2s", found.entry.message.text());
+      let context: Vec<_> = found
+        .context
+        .iter()
+        .map(|entry| entry.message.text().into_owned())
+        .collect();
+      assert_eq!(vec!["Start proc com.foo", "Displayed com.foo/.Main: +1s"], context);
+
+      let found = logcat.wait_for(&LogMatcher::new(), None, 0).await.unwrap();
+      assert!(found.context.is_empty());
+      assert!(matches!(found.entry.message, LogMessage::Text { ref tag, .. } if tag == "marker"));
+
+      match logcat.wait_for(&LogMatcher::new(), None, 0).await {
+        Err(adb::Error::Closed) => {}
+        other => panic!("unexpected result: {:?}", other),
+      }
+    });
+  }
+
+  /// A socket that never receives anything, like logcat while nothing is logged.
+  struct IdleSocket;
+
+  impl futures::io::AsyncRead for IdleSocket {
+    fn poll_read(self: Pin<&mut Self>, _cx: &mut Context, _buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+      Poll::Pending
+    }
+  }
+
+  impl futures::io::AsyncWrite for IdleSocket {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+      Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+      Poll::Ready(Ok(()))
+    }
+  }
+
+  #[test]
+  fn timeout() {
+    let mut logcat = Logcat::new(Box::new(IdleSocket), Default::default());
+    let result = block_on(logcat.wait_for(&LogMatcher::new(), Some(Duration::from_millis(50)), 0));
+    assert!(matches!(result, Err(adb::Error::TimedOut(_))));
+  }
+}
@@ -1,6 +1,20 @@
 //! Types and functions for client implementations.
 
+mod policy;
+pub use policy::{RetryPolicy, Timeouts};
+
 mod remote;
 pub use remote::*;
 
+mod command;
+pub use command::{CommandOptions, CommandOutput};
+
+mod fanout;
+pub use fanout::DeviceResult;
+
+pub mod capture;
+pub mod install;
+pub mod logcat;
+pub mod process;
 pub mod shell;
+pub mod sync;
@@ -0,0 +1,232 @@
+use futures::future::{self, Either, Future};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures_timer::Delay;
+use tracing::debug;
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate as adb;
+use crate::core::Socket;
+
+/// Timeouts applied to the operations performed by a [Remote](crate::client::Remote).
+///
+/// A value of `None` means that the corresponding operation can take arbitrarily long.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Timeouts {
+  /// Maximum time to establish a connection to the adb server.
+  pub connect: Option<Duration>,
+
+  /// Maximum time for the adb server to respond to a service request, including selection of a device.
+  pub handshake: Option<Duration>,
+
+  /// Maximum time to wait for data on a channel once it has been opened.
+  ///
+  /// Note that this also applies to shells, so an interactive shell with no output will time out.
+  pub read: Option<Duration>,
+}
+
+/// Policy for retrying the opening of channels that failed with a transient error.
+///
+/// See [Error::is_transient](crate::Error::is_transient) for the errors that are considered transient.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+  /// Maximum number of attempts, including the first one.
+  pub max_attempts: u32,
+
+  /// Delay before the first retry.
+  pub initial_backoff: Duration,
+
+  /// Upper bound on the delay between attempts.
+  pub max_backoff: Duration,
+
+  /// Factor by which the delay grows after each retry.
+  pub multiplier: u32,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> RetryPolicy {
+    RetryPolicy {
+      max_attempts: 5,
+      initial_backoff: Duration::from_millis(100),
+      max_backoff: Duration::from_secs(2),
+      multiplier: 2,
+    }
+  }
+}
+
+impl RetryPolicy {
+  /// Returns the delay to wait for after the given (zero-based) failed attempt.
+  pub fn backoff(&self, attempt: u32) -> Duration {
+    let mut backoff = self.initial_backoff;
+    for _ in 0..attempt {
+      backoff = backoff.checked_mul(self.multiplier).unwrap_or(self.max_backoff);
+      if backoff >= self.max_backoff {
+        return self.max_backoff;
+      }
+    }
+    std::cmp::min(backoff, self.max_backoff)
+  }
+
+  /// Runs an operation, retrying it according to the policy while it fails with transient errors.
+  pub(crate) async fn run<T, F, Fut>(policy: Option<&RetryPolicy>, mut operation: F) -> adb::Result<T>
+  where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = adb::Result<T>>,
+  {
+    let mut attempt = 0;
+    loop {
+      match operation().await {
+        Err(err) => match policy {
+          Some(policy) if err.is_transient() && attempt + 1 < policy.max_attempts => {
+            let backoff = policy.backoff(attempt);
+            debug!(
+              attempt,
+              backoff_ms = backoff.as_millis() as u64,
+              error = %err,
+              "retrying after transient error"
+            );
+            Delay::new(backoff).await;
+            attempt += 1;
+          }
+          _ => return Err(err),
+        },
+        result => return result,
+      }
+    }
+  }
+}
+
+/// Runs a future, failing with [Error::TimedOut](crate::Error::TimedOut) if it doesn't complete in time.
+///
+/// The future is dropped if it times out.
+pub(crate) async fn with_timeout<T>(
+  timeout: Option<Duration>,
+  operation: &str,
+  future: impl Future<Output = adb::Result<T>>,
+) -> adb::Result<T> {
+  let timeout = match timeout {
+    Some(timeout) => timeout,
+    None => return future.await,
+  };
+
+  let future = Box::pin(future);
+  match future::select(future, Delay::new(timeout)).await {
+    Either::Left((result, _)) => result,
+    Either::Right(((), _)) => Err(adb::Error::TimedOut(format!("{} after {:?}", operation, timeout))),
+  }
+}
+
+/// Socket wrapper that fails reads that take longer than a timeout.
+pub(crate) struct TimeoutSocket {
+  inner: Box<dyn Socket>,
+  timeout: Duration,
+  delay: Option<Delay>,
+}
+
+impl TimeoutSocket {
+  pub(crate) fn wrap(socket: Box<dyn Socket>, timeout: Option<Duration>) -> Box<dyn Socket> {
+    match timeout {
+      Some(timeout) => Box::new(TimeoutSocket {
+        inner: socket,
+        timeout,
+        delay: None,
+      }),
+      None => socket,
+    }
+  }
+}
+
+impl AsyncRead for TimeoutSocket {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+    if let Poll::Ready(result) = Pin::new(&mut self.inner).poll_read(cx, buf) {
+      self.delay = None;
+      return Poll::Ready(result);
+    }
+
+    let timeout = self.timeout;
+    let delay = self.delay.get_or_insert_with(|| Delay::new(timeout));
+    match Pin::new(delay).poll(cx) {
+      Poll::Ready(()) => {
+        self.delay = None;
+        let message = format!("read timed out after {:?}", timeout);
+        Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::TimedOut, message)))
+      }
+      Poll::Pending => Poll::Pending,
+    }
+  }
+}
+
+impl AsyncWrite for TimeoutSocket {
+  fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+    Pin::new(&mut self.inner).poll_write(cx, buf)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+    Pin::new(&mut self.inner).poll_flush(cx)
+  }
+
+  fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+    Pin::new(&mut self.inner).poll_close(cx)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn backoff() {
+    let policy = RetryPolicy {
+      max_attempts: 10,
+      initial_backoff: Duration::from_millis(100),
+      max_backoff: Duration::from_millis(1000),
+      multiplier: 3,
+    };
+    assert_eq!(Duration::from_millis(100), policy.backoff(0));
+    assert_eq!(Duration::from_millis(300), policy.backoff(1));
+    assert_eq!(Duration::from_millis(900), policy.backoff(2));
+    assert_eq!(Duration::from_millis(1000), policy.backoff(3));
+    assert_eq!(Duration::from_millis(1000), policy.backoff(100));
+  }
+
+  #[test]
+  fn retry_transient_errors() {
+    let policy = RetryPolicy {
+      max_attempts: 3,
+      initial_backoff: Duration::from_millis(1),
+      max_backoff: Duration::from_millis(1),
+      multiplier: 1,
+    };
+
+    let mut attempts = 0;
+    let result: adb::Result<()> = futures::executor::block_on(RetryPolicy::run(Some(&policy), || {
+      attempts += 1;
+      future::ready(Err(adb::Error::DeviceOffline))
+    }));
+    assert!(result.is_err());
+    assert_eq!(3, attempts);
+
+    let mut attempts = 0;
+    let result: adb::Result<()> = futures::executor::block_on(RetryPolicy::run(Some(&policy), || {
+      attempts += 1;
+      future::ready(Err(adb::Error::DeviceUnauthorized))
+    }));
+    assert!(result.is_err());
+    assert_eq!(1, attempts);
+  }
+
+  #[test]
+  fn timeout() {
+    let result: adb::Result<()> = futures::executor::block_on(with_timeout(
+      Some(Duration::from_millis(10)),
+      "waiting forever",
+      future::pending(),
+    ));
+    match result {
+      Err(adb::Error::TimedOut(_)) => {}
+      other => panic!("unexpected result: {:?}", other),
+    }
+  }
+}
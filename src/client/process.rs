@@ -0,0 +1,354 @@
+//! Management of processes that keep running on a device after the client disconnects.
+//!
+//! A background process is started in its own session with `setsid`, so it isn't killed when the shell that started
+//! it exits. Its stdout, stderr and exit code are written to files in an output directory on the device, which can be
+//! retrieved later, even from another process that reattaches to it with a serialized [ProcessHandle].
+
+use futures_timer::Delay;
+use tracing::debug;
+
+use std::time::Duration;
+
+use crate as adb;
+use crate::client::shell::{quote, ShellBuilder};
+use crate::client::sync::SyncClient;
+use crate::client::Remote;
+use crate::host::DeviceCriteria;
+
+/// Directory on the device in which output directories are created by [Remote::spawn_background].
+pub const DEFAULT_OUTPUT_ROOT: &str = "/data/local/tmp/.adb-background";
+
+/// Interval at which [BackgroundProcess::wait] checks whether the process has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Identifies a background process on a device.
+///
+/// Handles can be converted to and from strings (with `to_string` and `parse`) to reattach to the process later.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessHandle {
+  pub pid: u32,
+
+  /// The start time of the process, in clock ticks since boot, to detect reuse of its pid.
+  pub start_time: u64,
+
+  /// Directory on the device that contains the process's output.
+  pub output_dir: String,
+}
+
+impl std::fmt::Display for ProcessHandle {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "{}:{}:{}", self.pid, self.start_time, self.output_dir)
+  }
+}
+
+impl std::str::FromStr for ProcessHandle {
+  type Err = adb::Error;
+
+  fn from_str(s: &str) -> adb::Result<ProcessHandle> {
+    let invalid = || adb::Error::InvalidArgument(format!("invalid process handle '{}'", s));
+    let mut parts = s.splitn(3, ':');
+    let pid = parts.next().and_then(|pid| pid.parse().ok()).ok_or_else(invalid)?;
+    let start_time = parts.next().and_then(|time| time.parse().ok()).ok_or_else(invalid)?;
+    let output_dir = parts.next().filter(|dir| !dir.is_empty()).ok_or_else(invalid)?;
+    Ok(ProcessHandle {
+      pid,
+      start_time,
+      output_dir: output_dir.into(),
+    })
+  }
+}
+
+/// A process running in the background on a device, started with [Remote::spawn_background].
+pub struct BackgroundProcess {
+  remote: Remote,
+  device_criteria: DeviceCriteria,
+  handle: ProcessHandle,
+}
+
+impl Remote {
+  /// Starts a command in the background on a device, in a new output directory under [DEFAULT_OUTPUT_ROOT].
+  ///
+  /// The command, and its environment, working directory, etc. are taken from a [ShellBuilder]. Options that only
+  /// apply to interactive use, like the pty, are ignored, and stdin is always redirected from `/dev/null`.
+  pub async fn spawn_background(
+    &self,
+    device_criteria: DeviceCriteria,
+    builder: &ShellBuilder,
+  ) -> adb::Result<BackgroundProcess> {
+    use std::hash::{BuildHasher, Hasher};
+    let random = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    let output_dir = format!("{}/{:016x}", DEFAULT_OUTPUT_ROOT, random);
+    self.spawn_background_in(device_criteria, builder, &output_dir).await
+  }
+
+  /// Starts a command in the background on a device like [Remote::spawn_background], with the given output
+  /// directory, which is created if needed.
+  pub async fn spawn_background_in(
+    &self,
+    device_criteria: DeviceCriteria,
+    builder: &ShellBuilder,
+    output_dir: &str,
+  ) -> adb::Result<BackgroundProcess> {
+    let script = launch_script(builder, output_dir)?;
+    let output = self
+      .shell_output_checked(device_criteria.clone(), ["sh", "-c", &script])
+      .await?;
+
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut lines = output.lines();
+    let pid = lines
+      .next()
+      .and_then(|pid| pid.trim().parse().ok())
+      .ok_or_else(|| adb::Error::UnexpectedData(format!("failed to parse pid of background process: {:?}", output)))?;
+    let start_time = lines
+      .next()
+      .and_then(parse_start_time)
+      .ok_or_else(|| adb::Error::UnexpectedData(format!("failed to get status of background process: {:?}", output)))?;
+
+    let handle = ProcessHandle {
+      pid,
+      start_time,
+      output_dir: output_dir.into(),
+    };
+    debug!(handle = %handle, "started background process");
+    Ok(BackgroundProcess::attach(self.clone(), device_criteria, handle))
+  }
+}
+
+impl BackgroundProcess {
+  /// Reattaches to a background process, e.g. with a handle from a previous run.
+  pub fn attach(remote: Remote, device_criteria: DeviceCriteria, handle: ProcessHandle) -> BackgroundProcess {
+    BackgroundProcess {
+      remote,
+      device_criteria,
+      handle,
+    }
+  }
+
+  pub fn handle(&self) -> &ProcessHandle {
+    &self.handle
+  }
+
+  pub fn pid(&self) -> u32 {
+    self.handle.pid
+  }
+
+  /// Returns whether the process is still running.
+  pub async fn is_alive(&self) -> adb::Result<bool> {
+    let output = self
+      .remote
+      .shell_output(
+        self.device_criteria.clone(),
+        ["cat", &format!("/proc/{}/stat", self.handle.pid)],
+      )
+      .await?;
+    if output.failed() {
+      return Ok(false);
+    }
+
+    // The pid might have been reused by another process after ours exited.
+    let stat = String::from_utf8_lossy(&output.stdout);
+    Ok(parse_start_time(&stat) == Some(self.handle.start_time) && parse_state(&stat) != Some('Z'))
+  }
+
+  /// Sends a signal (e.g. `libc::SIGTERM`) to the process, and any processes it started.
+  ///
+  /// Nothing is sent if the process has already exited.
+  pub async fn signal(&self, signal: i32) -> adb::Result<()> {
+    if !self.is_alive().await? {
+      return Ok(());
+    }
+
+    // The process leads its own process group, so signal the whole group.
+    let output = self
+      .remote
+      .shell_output(
+        self.device_criteria.clone(),
+        ["kill", &format!("-{}", signal), &format!("-{}", self.handle.pid)],
+      )
+      .await?;
+
+    // The process might have exited in the meantime.
+    if output.failed() && self.is_alive().await? {
+      return output.check().map(|_| ());
+    }
+    Ok(())
+  }
+
+  /// Waits for the process to exit, and returns its exit code.
+  ///
+  /// The exit code is `None` if the process was killed before it could be recorded.
+  pub async fn wait(&self) -> adb::Result<Option<u8>> {
+    while self.is_alive().await? {
+      Delay::new(POLL_INTERVAL).await;
+    }
+    self.exit_code().await
+  }
+
+  /// Returns the exit code of the process, if it has exited and the exit code was recorded.
+  pub async fn exit_code(&self) -> adb::Result<Option<u8>> {
+    let exit_code = match self.read_output_file("exit").await? {
+      Some(exit_code) => exit_code,
+      None => return Ok(None),
+    };
+
+    let exit_code = String::from_utf8_lossy(&exit_code);
+    exit_code
+      .trim()
+      .parse()
+      .map(Some)
+      .map_err(|_| adb::Error::UnexpectedData(format!("invalid exit code '{}'", exit_code.trim())))
+  }
+
+  /// Retrieves everything that the process has written to stdout so far.
+  pub async fn read_stdout(&self) -> adb::Result<Vec<u8>> {
+    Ok(self.read_output_file("stdout").await?.unwrap_or_default())
+  }
+
+  /// Retrieves everything that the process has written to stderr so far.
+  pub async fn read_stderr(&self) -> adb::Result<Vec<u8>> {
+    Ok(self.read_output_file("stderr").await?.unwrap_or_default())
+  }
+
+  /// Deletes the output directory of the process.
+  pub async fn remove_output(&self) -> adb::Result<()> {
+    self
+      .remote
+      .shell_output_checked(self.device_criteria.clone(), ["rm", "-rf", &self.handle.output_dir])
+      .await?;
+    Ok(())
+  }
+
+  /// Reads a file in the output directory, or returns `None` if it doesn't exist.
+  async fn read_output_file(&self, name: &str) -> adb::Result<Option<Vec<u8>>> {
+    let path = format!("{}/{}", self.handle.output_dir, name);
+    let mut sync = SyncClient::connect(self.remote.clone(), self.device_criteria.clone()).await?;
+    if sync.stat(&path).await?.mode == 0 {
+      sync.quit().await?;
+      return Ok(None);
+    }
+
+    let mut data = Vec::new();
+    sync.pull(&path, &mut data).await?;
+    sync.quit().await?;
+    Ok(Some(data))
+  }
+}
+
+/// Builds the script that starts a command in the background, and prints its pid and `/proc/<pid>/stat`.
+pub(crate) fn launch_script(builder: &ShellBuilder, output_dir: &str) -> adb::Result<String> {
+  let command_line = builder
+    .command_line(true)?
+    .ok_or_else(|| adb::Error::InvalidArgument("background processes require a command".into()))?;
+
+  // The exit code is written atomically, so that it's never read while partially written.
+  let dir = quote(output_dir);
+  let wrapped = format!(
+    "{}\necho $? > {dir}/exit.tmp && mv {dir}/exit.tmp {dir}/exit",
+    command_line,
+    dir = dir
+  );
+  Ok(format!(
+    "mkdir -p {dir} && rm -f {dir}/exit && \
+     setsid sh -c {wrapped} > {dir}/stdout 2> {dir}/stderr < /dev/null &\n\
+     pid=$!\n\
+     echo $pid\n\
+     cat /proc/$pid/stat",
+    dir = dir,
+    wrapped = quote(&wrapped)
+  ))
+}
+
+/// Returns the fields of `/proc/<pid>/stat` after the command name, which can contain spaces.
+fn stat_fields(stat: &str) -> Option<Vec<&str>> {
+  let (_, fields) = stat.rsplit_once(')')?;
+  Some(fields.split_whitespace().collect())
+}
+
+/// Parses the state (field 3) from the contents of `/proc/<pid>/stat`.
+fn parse_state(stat: &str) -> Option<char> {
+  stat_fields(stat)?.first()?.chars().next()
+}
+
+/// Parses the start time (field 22) from the contents of `/proc/<pid>/stat`.
+fn parse_start_time(stat: &str) -> Option<u64> {
+  stat_fields(stat)?.get(22 - 3)?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn stat(pid: u32, state: char, start_time: u64) -> String {
+    format!(
+      "{} (sh -c) {} 1 {} {} 0 -1 4194560 120 0 0 0 0 0 0 0 20 0 1 0 {} 10887168 178 18446744073709551615\n",
+      pid, state, pid, pid, start_time
+    )
+  }
+
+  #[test]
+  fn handles() {
+    let handle = ProcessHandle {
+      pid: 1234,
+      start_time: 5678,
+      output_dir: "/data/local/tmp/a:b".into(),
+    };
+    assert_eq!("1234:5678:/data/local/tmp/a:b", handle.to_string());
+    assert_eq!(handle, handle.to_string().parse().unwrap());
+    assert!("1234:/tmp".parse::<ProcessHandle>().is_err());
+    assert!("foo:1:/tmp".parse::<ProcessHandle>().is_err());
+
+    assert_eq!(Some(5678), parse_start_time(&stat(1, 'S', 5678)));
+    assert_eq!(Some('Z'), parse_state(&stat(1, 'Z', 5678)));
+  }
+
+  #[test]
+  #[cfg(feature = "daemon")]
+  fn lifecycle() {
+    use crate::runtime::block_on;
+    use crate::testing::{device, FakeServer};
+
+    let root = tempfile::tempdir().unwrap();
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1)).on_sync(root.path());
+
+    let mut builder = ShellBuilder::new();
+    builder.command(Some(vec!["sleep".into(), "100".into()]));
+    let script = launch_script(&builder, "/bg").unwrap();
+    let launch = format!("sh -c {}", quote(&script));
+    server.on_shell(launch, format!("1234\n{}", stat(1234, 'S', 42)).as_bytes(), b"", 0);
+    server.on_shell("cat /proc/1234/stat", stat(1234, 'S', 42).as_bytes(), b"", 0);
+    server.on_shell("kill -15 -1234", b"", b"", 0);
+
+    block_on(async {
+      let process = server
+        .remote()
+        .spawn_background_in(DeviceCriteria::Any, &builder, "/bg")
+        .await
+        .unwrap();
+      assert_eq!(1234, process.pid());
+      assert_eq!(42, process.handle().start_time);
+      assert!(process.is_alive().await.unwrap());
+      process.signal(15).await.unwrap();
+      assert_eq!(None, process.exit_code().await.unwrap());
+
+      // Simulate the process exiting, and its pid being reused.
+      std::fs::create_dir(root.path().join("bg")).unwrap();
+      std::fs::write(root.path().join("bg/stdout"), b"output").unwrap();
+      std::fs::write(root.path().join("bg/exit"), b"143\n").unwrap();
+      server.on_shell("cat /proc/1234/stat", stat(1234, 'S', 43).as_bytes(), b"", 0);
+
+      let process = BackgroundProcess::attach(
+        server.remote(),
+        DeviceCriteria::Any,
+        process.handle().to_string().parse().unwrap(),
+      );
+      assert!(!process.is_alive().await.unwrap());
+      assert_eq!(Some(143), process.wait().await.unwrap());
+      assert_eq!(b"output", &process.read_stdout().await.unwrap()[..]);
+      assert!(process.read_stderr().await.unwrap().is_empty());
+    });
+
+    server.assert_requested("shell,v2,raw:kill -15 -1234");
+  }
+}
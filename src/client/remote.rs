@@ -1,26 +1,40 @@
 use byteorder::{ByteOrder, LittleEndian};
 use futures::io::{AsyncReadExt, AsyncWriteExt};
 use regex::Regex;
+use tracing::{debug, debug_span, field, trace, Instrument};
+
+use std::time::Instant;
 
 use crate as adb;
+use crate::client::capture::Capture;
+use crate::client::policy::{with_timeout, TimeoutSocket};
+use crate::client::{RetryPolicy, Timeouts};
 use crate::core::{Socket, SocketSpec};
 use crate::host::{DeviceCriteria, DeviceDescription, DeviceType, TransportId, TransportType};
-use crate::util::{ConsumePrefix, SplitOnce};
+use crate::util::{hexdump, ConsumePrefix};
 
 /// A pointer to the location of an adb server.
+///
+/// By default, operations on a `Remote` have no timeouts and aren't retried. See [Remote::set_timeouts] and
+/// [Remote::set_retry_policy]. All of the futures returned by a `Remote` can be safely dropped to cancel them.
+#[derive(Clone)]
 pub struct Remote {
   socket_spec: SocketSpec,
+  capture: Option<Capture>,
+  timeouts: Timeouts,
+  retry_policy: Option<RetryPolicy>,
 }
 
-async fn write_hex_length_prefixed(socket: &mut Socket, bytes: impl Into<Vec<u8>>) -> adb::Result<()> {
+async fn write_hex_length_prefixed(socket: &mut dyn Socket, bytes: impl Into<Vec<u8>>) -> adb::Result<()> {
   let bytes = bytes.into();
+  trace!(len = bytes.len(), data = %hexdump(&bytes), "sending request");
   let s = format!("{:04x}", bytes.len());
   socket.write_all(s.as_bytes()).await?;
   socket.write_all(&bytes).await?;
   Ok(())
 }
 
-async fn read_hex_length_prefixed(socket: &mut Socket) -> adb::Result<Vec<u8>> {
+async fn read_hex_length_prefixed(socket: &mut dyn Socket) -> adb::Result<Vec<u8>> {
   let mut length = [0u8; 4];
   socket.read_exact(&mut length).await?;
 
@@ -32,10 +46,11 @@ async fn read_hex_length_prefixed(socket: &mut Socket) -> adb::Result<Vec<u8>> {
 
   let mut vec = vec![0; length];
   socket.read_exact(&mut vec).await?;
+  trace!(len = length, data = %hexdump(&vec), "received response");
   Ok(vec)
 }
 
-async fn read_okay(socket: &mut Socket) -> adb::Result<()> {
+async fn read_okay(socket: &mut dyn Socket) -> adb::Result<()> {
   let mut okay = [0u8; 4];
   socket.read_exact(&mut okay).await?;
 
@@ -45,9 +60,11 @@ async fn read_okay(socket: &mut Socket) -> adb::Result<()> {
     // Try to read the error.
     let error = read_hex_length_prefixed(socket).await?;
     let error_str = String::from_utf8_lossy(&error);
-    Err(adb::Error::ServiceError(error_str.into_owned()))
+    debug!(message = %error_str, "received FAIL");
+    Err(adb::Error::from_service_failure(error_str.into_owned()))
   } else {
     let error_str = format!("expected OKAY or FAIL, got {}", String::from_utf8_lossy(&okay));
+    debug!(message = %error_str, "received unexpected response");
     Err(adb::Error::UnexpectedData(error_str))
   }
 }
@@ -55,65 +72,203 @@ async fn read_okay(socket: &mut Socket) -> adb::Result<()> {
 impl Remote {
   /// Constructs a new `Remote`.
   pub fn new(socket_spec: SocketSpec) -> Remote {
-    Remote { socket_spec }
+    Remote {
+      socket_spec,
+      capture: None,
+      timeouts: Timeouts::default(),
+      retry_policy: None,
+    }
+  }
+
+  /// Returns the location of the adb server.
+  pub fn socket_spec(&self) -> &SocketSpec {
+    &self.socket_spec
+  }
+
+  /// Sets whether the traffic on channels opened by this `Remote` is recorded or replayed.
+  pub fn set_capture(&mut self, capture: Option<Capture>) -> &mut Remote {
+    self.capture = capture;
+    self
+  }
+
+  /// Sets the timeouts applied to operations on channels opened by this `Remote`.
+  pub fn set_timeouts(&mut self, timeouts: Timeouts) -> &mut Remote {
+    self.timeouts = timeouts;
+    self
+  }
+
+  /// Sets the policy for retrying the opening of channels that fail with transient errors.
+  ///
+  /// By default, failures aren't retried.
+  pub fn set_retry_policy(&mut self, retry_policy: Option<RetryPolicy>) -> &mut Remote {
+    self.retry_policy = retry_policy;
+    self
+  }
+
+  async fn connect(&self) -> adb::Result<Box<dyn Socket>> {
+    with_timeout(self.timeouts.connect, "connect", async {
+      match &self.capture {
+        None => self.socket_spec.connect().await,
+        Some(Capture::Record(recorder)) => {
+          let socket = self.socket_spec.connect().await?;
+          Ok(recorder.wrap(&self.socket_spec, socket))
+        }
+        Some(Capture::Replay(replay)) => replay.next_channel(),
+      }
+    })
+    .await
   }
 
   /// Opens a channel to a raw adb service.
   ///
   /// No device-selection prefix is prepended, use [Remote::open_device_channel] if you wish to connect to a device
   /// service.
-  pub async fn open_channel(&self, service: impl AsRef<str>) -> adb::Result<Box<Socket>> {
-    let mut channel = self.socket_spec.connect().await?;
+  pub async fn open_channel(&self, service: impl AsRef<str>) -> adb::Result<Box<dyn Socket>> {
+    let service = service.as_ref();
+    let span = debug_span!("open_channel", server = %self.socket_spec, service);
+    async move {
+      let start = Instant::now();
+      let result = RetryPolicy::run(self.retry_policy.as_ref(), || self.open_channel_once(service))
+        .await
+        .map(|channel| TimeoutSocket::wrap(channel, self.timeouts.read));
+      log_result(&result, start, "open channel");
+      result
+    }
+    .instrument(span)
+    .await
+  }
 
-    write_hex_length_prefixed(&mut channel, service.as_ref().as_bytes()).await?;
-    read_okay(&mut channel).await?;
-    Ok(channel)
+  async fn open_channel_once(&self, service: &str) -> adb::Result<Box<dyn Socket>> {
+    async {
+      let mut channel = self.connect().await?;
+      with_timeout(self.timeouts.handshake, "handshake", async {
+        write_hex_length_prefixed(&mut channel, service.as_bytes()).await?;
+        read_okay(&mut channel).await
+      })
+      .await?;
+      Ok(channel)
+    }
+    .await
+    .map_err(|err: adb::Error| err.with_service(service).with_socket_spec(self.socket_spec.clone()))
   }
 
-  async fn open_device_channel_id(&self, id: TransportId, service: impl AsRef<str>) -> adb::Result<Box<Socket>> {
+  async fn open_device_channel_id(&self, id: TransportId, service: &str) -> adb::Result<Box<dyn Socket>> {
     let s = format!("host:transport-id:{}", id.0);
-    let mut channel = self.open_channel(s).await?;
+    let mut channel = self.open_channel_once(&s).await?;
 
-    write_hex_length_prefixed(&mut channel, service.as_ref().as_bytes()).await?;
-    read_okay(&mut channel).await?;
+    let result = with_timeout(self.timeouts.handshake, "handshake", async {
+      write_hex_length_prefixed(&mut channel, service.as_bytes()).await?;
+      read_okay(&mut channel).await
+    })
+    .await;
+    result.map_err(|err| self.device_error(err, service, id))?;
     Ok(channel)
   }
 
   async fn open_device_channel_tport(
     &self,
-    tport_str: impl AsRef<str>,
-    service: impl AsRef<str>,
-  ) -> adb::Result<(TransportId, Box<Socket>)> {
-    let mut channel = self.open_channel(tport_str).await?;
+    tport_str: &str,
+    service: &str,
+  ) -> adb::Result<(TransportId, Box<dyn Socket>)> {
+    let mut channel = self.open_channel_once(tport_str).await?;
+
     let mut tport = [0u8; 8];
-    channel.read_exact(&mut tport).await?;
+    with_timeout(self.timeouts.handshake, "handshake", async {
+      channel.read_exact(&mut tport).await?;
+      Ok(())
+    })
+    .await
+    .map_err(|err| err.with_service(tport_str).with_socket_spec(self.socket_spec.clone()))?;
 
     let id = TransportId(LittleEndian::read_u64(&tport));
-    write_hex_length_prefixed(&mut channel, service.as_ref().as_bytes()).await?;
-    read_okay(&mut channel).await?;
+    let result = with_timeout(self.timeouts.handshake, "handshake", async {
+      write_hex_length_prefixed(&mut channel, service.as_bytes()).await?;
+      read_okay(&mut channel).await
+    })
+    .await;
+    result.map_err(|err| self.device_error(err, service, id))?;
     Ok((id, channel))
   }
 
+  fn device_error(&self, err: adb::Error, service: &str, id: TransportId) -> adb::Error {
+    err
+      .with_service(service)
+      .with_transport_id(id.0)
+      .with_socket_spec(self.socket_spec.clone())
+  }
+
+  async fn open_device_channel_once(
+    &self,
+    criteria: DeviceCriteria,
+    service: &str,
+  ) -> adb::Result<(TransportId, Box<dyn Socket>)> {
+    match criteria {
+      DeviceCriteria::TransportId(id) => self
+        .open_device_channel_id(id, service)
+        .await
+        .map(|c| (id, c))
+        .map_err(|err| err.with_transport_id(id.0)),
+
+      // Use the host:tport service to select a device and get its transport id back.
+      criteria => self.open_device_channel_tport(&tport_service(&criteria), service).await,
+    }
+  }
+
   /// Opens a channel to a service on a device specified by the provided [DeviceCriteria].
+  ///
+  /// Like all of the operations on a `Remote`, this is subject to the configured [Timeouts] and [RetryPolicy]. The
+  /// returned future can be dropped at any point to cancel the operation, which closes the partially opened channel.
   pub async fn open_device_channel(
     &self,
     criteria: DeviceCriteria,
     service: impl AsRef<str>,
-  ) -> adb::Result<(TransportId, Box<Socket>)> {
+  ) -> adb::Result<(TransportId, Box<dyn Socket>)> {
     let service = service.as_ref();
-    // Use the host:tport service to select a device and get its transport id back.
-    let (transport_id, channel) = match criteria {
-      DeviceCriteria::Any => self.open_device_channel_tport("host:tport:any", service).await?,
-      DeviceCriteria::Usb => self.open_device_channel_tport("host:tport:usb", service).await?,
-      DeviceCriteria::Tcp => self.open_device_channel_tport("host:tport:tcp", service).await?,
-      DeviceCriteria::Serial(serial) => {
-        let s = format!("host:tport:serial:{}", serial);
-        self.open_device_channel_tport(&s, service).await?
+    let span = debug_span!(
+      "open_device_channel",
+      criteria = ?criteria,
+      service,
+      transport_id = field::Empty
+    );
+
+    async move {
+      let start = Instant::now();
+      let result = RetryPolicy::run(self.retry_policy.as_ref(), || {
+        self.open_device_channel_once(criteria.clone(), service)
+      })
+      .await
+      .map(|(transport_id, channel)| (transport_id, TimeoutSocket::wrap(channel, self.timeouts.read)));
+
+      if let Ok((transport_id, _)) = &result {
+        tracing::Span::current().record("transport_id", transport_id.0);
       }
-      DeviceCriteria::TransportId(id) => (id, self.open_device_channel_id(id, service).await?),
-    };
+      log_result(&result, start, "open device channel");
+      result
+    }
+    .instrument(span)
+    .await
+  }
 
-    Ok((transport_id, channel))
+  /// Resolves [DeviceCriteria] to the transport id of the device that it currently selects.
+  ///
+  /// Operations that talk to a device more than once should select it with [DeviceCriteria::TransportId] after
+  /// resolving it, so that they can't end up talking to different devices.
+  pub async fn transport_id(&self, criteria: DeviceCriteria) -> adb::Result<TransportId> {
+    if let DeviceCriteria::TransportId(id) = criteria {
+      return Ok(id);
+    }
+
+    // Select the device without sending a service to it, and close the channel once its id has been read.
+    let service = tport_service(&criteria);
+    let mut channel = self.open_channel(&service).await?;
+    let mut tport = [0u8; 8];
+    with_timeout(self.timeouts.handshake, "handshake", async {
+      channel.read_exact(&mut tport).await?;
+      Ok(())
+    })
+    .await
+    .map_err(|err| err.with_service(service).with_socket_spec(self.socket_spec.clone()))?;
+    Ok(TransportId(LittleEndian::read_u64(&tport)))
   }
 
   /// Get the server's protocol version.
@@ -130,102 +285,165 @@ impl Remote {
   pub async fn devices(&self) -> adb::Result<Vec<DeviceDescription>> {
     let mut channel = self.open_channel("host:devices-l").await?;
     let devices = read_hex_length_prefixed(&mut channel).await?;
-    let devices_str = String::from_utf8_lossy(&devices);
+    parse_devices(&String::from_utf8_lossy(&devices))
+  }
 
-    let mut result = Vec::new();
-    // TODO: Use an actual protocol instead of parsing user-readable string output.
-    for line in devices_str.split('\n') {
-      if line.is_empty() {
-        continue;
-      }
+  /// Gets the features supported by a device (e.g. `shell_v2`), as reported by the server.
+  pub async fn device_features(&self, criteria: DeviceCriteria) -> adb::Result<Vec<String>> {
+    let service = match criteria {
+      DeviceCriteria::Any => "host:features".to_string(),
+      DeviceCriteria::Usb => "host-usb:features".to_string(),
+      DeviceCriteria::Tcp => "host-local:features".to_string(),
+      DeviceCriteria::Serial(serial) => format!("host-serial:{}:features", serial),
+      DeviceCriteria::TransportId(id) => format!("host-transport-id:{}:features", id.0),
+    };
 
-      let (serial, middle) = line
-        .split_once(" ")
-        .ok_or_else(|| adb::Error::UnexpectedData(format!("invalid device line: '{}'", line)))?;
-
-      let (transport_id_str, middle) = middle
-        .rsplit_once(" transport_id:")
-        .ok_or_else(|| adb::Error::UnexpectedData(format!("transport_id missing in device line: '{}'", line)))?;
-
-      let transport_id = TransportId(
-        transport_id_str
-          .parse()
-          .map_err(|_| adb::Error::UnexpectedData(format!("invalid transport id in device line: '{}'", line)))?,
-      );
-
-      // The easy part is done. Now for some especially horrible string parsing:
-      // First, trim the alignment spaces.
-      let middle = middle.trim_start();
-
-      // Next, parse the transport type.
-      // This is especially horrible, because it can be the following text:
-      //   "no permissions; see [http://developer.android.com/tools/device.html]"
-      // Thankfully, we can just check for "no permissions" and stop there, because there won't be any additional info.
-      let (transport_type, middle) = if middle.starts_with("offline") {
-        (TransportType::Offline, "")
-      } else if middle.starts_with("no permissions") {
-        (TransportType::NoPermissions, "")
-      } else if middle.starts_with("unauthorized") {
-        (TransportType::Unauthorized, "")
-      } else if middle.starts_with("authorizing") {
-        (TransportType::Authorizing, "")
-      } else if middle.starts_with("connecting") {
-        (TransportType::Connecting, "")
-      } else {
-        // We are presumably connected. Figure out what our DeviceType is.
-        let (device_type, middle) = if let Some(s) = middle.consume_prefix("bootloader ") {
-          (DeviceType::Bootloader, s)
-        } else if let Some(s) = middle.consume_prefix("device ") {
-          (DeviceType::Device, s)
-        } else if let Some(s) = middle.consume_prefix("host ") {
-          (DeviceType::Host, s)
-        } else if let Some(s) = middle.consume_prefix("recovery ") {
-          (DeviceType::Recovery, s)
-        } else if let Some(s) = middle.consume_prefix("rescue ") {
-          (DeviceType::Rescue, s)
-        } else if let Some(s) = middle.consume_prefix("sideload ") {
-          (DeviceType::Sideload, s)
-        } else {
-          return Err(adb::Error::UnexpectedData(format!(
-            "failed to parse device type from device line '{}'",
-            line
-          )));
-        };
-
-        (TransportType::Online(device_type), middle)
-      };
+    let mut channel = self.open_channel(service).await?;
+    let features = read_hex_length_prefixed(&mut channel).await?;
+    Ok(
+      String::from_utf8_lossy(&features)
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .map(String::from)
+        .collect(),
+    )
+  }
+
+  /// Starts tracking the devices connected to the server.
+  ///
+  /// The read timeout of the `Remote` applies to the time between updates, so it should usually be left unset when
+  /// tracking devices.
+  pub async fn track_devices(&self) -> adb::Result<DeviceTracker> {
+    let channel = self.open_channel("host:track-devices-l").await?;
+    Ok(DeviceTracker { channel })
+  }
+}
+
+/// A subscription to the list of devices connected to an adb server, created by [Remote::track_devices].
+pub struct DeviceTracker {
+  channel: Box<dyn Socket>,
+}
+
+impl DeviceTracker {
+  /// Waits for the next list of devices.
+  ///
+  /// The first call returns the current list of devices immediately, and subsequent calls wait until it changes.
+  pub async fn next(&mut self) -> adb::Result<Vec<DeviceDescription>> {
+    let devices = read_hex_length_prefixed(&mut self.channel).await?;
+    parse_devices(&String::from_utf8_lossy(&devices))
+  }
+}
 
-      // The rest is relatively easy.
-      // The first element might be a device path, after which we might have product, model, and device.
-      let captures = if middle.is_empty() {
-        None
+fn parse_devices(devices_str: &str) -> adb::Result<Vec<DeviceDescription>> {
+  let re = Regex::new(
+    r"(?P<device_path>\S+)(?: product:(?P<product>\S+))?(?: model:(?P<model>\S+))?(?: device:(?P<device>\S+))?",
+  )
+  .unwrap();
+
+  let mut result = Vec::new();
+  // TODO: Use an actual protocol instead of parsing user-readable string output.
+  for line in devices_str.split('\n') {
+    if line.is_empty() {
+      continue;
+    }
+
+    let (serial, middle) = line
+      .split_once(' ')
+      .ok_or_else(|| adb::Error::UnexpectedData(format!("invalid device line: '{}'", line)))?;
+
+    let (middle, transport_id_str) = middle
+      .rsplit_once(" transport_id:")
+      .ok_or_else(|| adb::Error::UnexpectedData(format!("transport_id missing in device line: '{}'", line)))?;
+
+    let transport_id = TransportId(
+      transport_id_str
+        .parse()
+        .map_err(|_| adb::Error::UnexpectedData(format!("invalid transport id in device line: '{}'", line)))?,
+    );
+
+    // The easy part is done. Now for some especially horrible string parsing:
+    // First, trim the alignment spaces.
+    let middle = middle.trim_start();
+
+    // Next, parse the transport type.
+    // This is especially horrible, because it can be the following text:
+    //   "no permissions; see [http://developer.android.com/tools/device.html]"
+    // Thankfully, we can just check for "no permissions" and stop there, because there won't be any additional info.
+    let (transport_type, middle) = if middle.starts_with("offline") {
+      (TransportType::Offline, "")
+    } else if middle.starts_with("no permissions") {
+      (TransportType::NoPermissions, "")
+    } else if middle.starts_with("unauthorized") {
+      (TransportType::Unauthorized, "")
+    } else if middle.starts_with("authorizing") {
+      (TransportType::Authorizing, "")
+    } else if middle.starts_with("connecting") {
+      (TransportType::Connecting, "")
+    } else {
+      // We are presumably connected. Figure out what our DeviceType is.
+      let (device_type, middle) = if let Some(s) = middle.consume_prefix("bootloader ") {
+        (DeviceType::Bootloader, s)
+      } else if let Some(s) = middle.consume_prefix("device ") {
+        (DeviceType::Device, s)
+      } else if let Some(s) = middle.consume_prefix("host ") {
+        (DeviceType::Host, s)
+      } else if let Some(s) = middle.consume_prefix("recovery ") {
+        (DeviceType::Recovery, s)
+      } else if let Some(s) = middle.consume_prefix("rescue ") {
+        (DeviceType::Rescue, s)
+      } else if let Some(s) = middle.consume_prefix("sideload ") {
+        (DeviceType::Sideload, s)
       } else {
-        let re = Regex::new(
-          r"(?P<device_path>\S+)(?: product:(?P<product>\S+))?(?: model:(?P<model>\S+))?(?: device:(?P<device>\S+))?",
-        )
-        .unwrap();
-        re.captures(middle)
+        return Err(adb::Error::UnexpectedData(format!(
+          "failed to parse device type from device line '{}'",
+          line
+        )));
       };
 
-      result.push(DeviceDescription {
-        serial: serial.into(),
-        id: transport_id,
-        transport_type,
-        device_path: captures
-          .as_ref()
-          .and_then(|c| c.name("device_path").map(|s| s.as_str().into())),
-        product: captures
-          .as_ref()
-          .and_then(|c| c.name("product").map(|s| s.as_str().into())),
-        model: captures
-          .as_ref()
-          .and_then(|c| c.name("model").map(|s| s.as_str().into())),
-        device: captures
-          .as_ref()
-          .and_then(|c| c.name("device").map(|s| s.as_str().into())),
-      })
-    }
-    Ok(result)
+      (TransportType::Online(device_type), middle)
+    };
+
+    // The rest is relatively easy.
+    // The first element might be a device path, after which we might have product, model, and device.
+    let captures = if middle.is_empty() { None } else { re.captures(middle) };
+
+    result.push(DeviceDescription {
+      serial: serial.into(),
+      id: transport_id,
+      transport_type,
+      device_path: captures
+        .as_ref()
+        .and_then(|c| c.name("device_path").map(|s| s.as_str().into())),
+      product: captures
+        .as_ref()
+        .and_then(|c| c.name("product").map(|s| s.as_str().into())),
+      model: captures
+        .as_ref()
+        .and_then(|c| c.name("model").map(|s| s.as_str().into())),
+      device: captures
+        .as_ref()
+        .and_then(|c| c.name("device").map(|s| s.as_str().into())),
+    })
+  }
+  Ok(result)
+}
+
+/// Returns the `host:tport:` service that selects a device with [DeviceCriteria] other than a transport id.
+fn tport_service(criteria: &DeviceCriteria) -> String {
+  match criteria {
+    DeviceCriteria::Any => "host:tport:any".into(),
+    DeviceCriteria::Usb => "host:tport:usb".into(),
+    DeviceCriteria::Tcp => "host:tport:tcp".into(),
+    DeviceCriteria::Serial(serial) => format!("host:tport:serial:{}", serial),
+    DeviceCriteria::TransportId(id) => unreachable!("transport {} is selected with host:transport-id", id.0),
+  }
+}
+
+fn log_result<T>(result: &adb::Result<T>, start: Instant, operation: &str) {
+  let latency_us = start.elapsed().as_micros() as u64;
+  match result {
+    Ok(_) => debug!(latency_us, "{} succeeded", operation),
+    Err(err) => debug!(latency_us, error = ?err, "{} failed", operation),
   }
 }
 
@@ -0,0 +1,83 @@
+//! Buffering shared by the shell implementations.
+
+use bytes::{Buf, BytesMut};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::ready;
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Amount of space made available for each read from the socket.
+pub(super) const READ_SIZE: usize = 64 * 1024;
+
+/// Amount of buffered input above which a writer applies backpressure until the buffer is drained.
+const WRITE_HIGH_WATER: usize = 64 * 1024;
+
+/// Reads as much as is available (up to [READ_SIZE] bytes, or more if already reserved) onto the end of a buffer.
+pub(crate) fn poll_fill(
+  read: &mut (impl AsyncRead + Unpin),
+  buf: &mut BytesMut,
+  cx: &mut Context,
+) -> Poll<std::io::Result<usize>> {
+  let len = buf.len();
+  buf.reserve(READ_SIZE);
+  buf.resize(buf.capacity(), 0);
+  let result = Pin::new(read).poll_read(cx, &mut buf[len..]);
+  match result {
+    Poll::Ready(Ok(read)) => buf.truncate(len + read),
+    _ => buf.truncate(len),
+  }
+  result
+}
+
+/// A socket write half with a buffer of pending data in front of it.
+pub(super) struct WriteBuffer<W> {
+  write: W,
+  buf: BytesMut,
+}
+
+impl<W: AsyncWrite + Unpin> WriteBuffer<W> {
+  pub(super) fn new(write: W) -> WriteBuffer<W> {
+    WriteBuffer {
+      write,
+      buf: BytesMut::new(),
+    }
+  }
+
+  /// Returns the buffer of data waiting to be written.
+  pub(super) fn buf_mut(&mut self) -> &mut BytesMut {
+    &mut self.buf
+  }
+
+  /// Waits until there's room to buffer more data.
+  pub(super) fn poll_ready(&mut self, cx: &mut Context) -> Poll<std::io::Result<()>> {
+    if self.buf.len() >= WRITE_HIGH_WATER {
+      self.poll_drain(cx)
+    } else {
+      Poll::Ready(Ok(()))
+    }
+  }
+
+  fn poll_drain(&mut self, cx: &mut Context) -> Poll<std::io::Result<()>> {
+    while !self.buf.is_empty() {
+      let written = ready!(Pin::new(&mut self.write).poll_write(cx, &self.buf))?;
+      if written == 0 {
+        return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+      }
+      self.buf.advance(written);
+    }
+    Poll::Ready(Ok(()))
+  }
+
+  /// Writes all of the buffered data, and flushes the socket.
+  pub(super) fn poll_flush(&mut self, cx: &mut Context) -> Poll<std::io::Result<()>> {
+    ready!(self.poll_drain(cx))?;
+    Pin::new(&mut self.write).poll_flush(cx)
+  }
+
+  /// Writes all of the buffered data, and closes the socket.
+  pub(super) fn poll_close(&mut self, cx: &mut Context) -> Poll<std::io::Result<()>> {
+    ready!(self.poll_drain(cx))?;
+    Pin::new(&mut self.write).poll_close(cx)
+  }
+}
@@ -1,20 +1,30 @@
-use futures::future::Future;
-use std::pin::Pin;
+use bytes::Bytes;
+use futures::sink::{Sink, SinkExt};
+use futures::stream::Stream;
+use tracing::debug;
+
+use std::borrow::Cow;
+use std::time::Duration;
 
 use crate as adb;
 use crate::client::Remote;
 use crate::host::DeviceCriteria;
 
-mod raw;
+pub(crate) mod buffer;
+
+pub(crate) mod raw;
 use raw::RawShell;
 
 mod protocol;
 use protocol::ProtocolShell;
 
+mod streams;
+pub use streams::{ShellExitCode, ShellStderr, ShellStdin, ShellStdout, ShellStreams};
+
 /// Events transmitted from the shell client to the shell service.
 #[derive(Debug)]
 pub enum ShellInput {
-  Stdin(Vec<u8>),
+  Stdin(Bytes),
   CloseStdin,
   WindowSizeChange {
     rows: u16,
@@ -27,35 +37,86 @@ pub enum ShellInput {
 /// Events transmitted from the shell service to the shell client.
 #[derive(Debug)]
 pub enum ShellOutput {
-  Stdout(Vec<u8>),
-  Stderr(Vec<u8>),
+  Stdout(Bytes),
+  Stderr(Bytes),
   Exit(u8),
 }
 
-pub trait ShellRead: Send {
-  fn read(&mut self) -> Pin<Box<dyn Future<Output = adb::Result<ShellOutput>> + Send + '_>>;
-}
+/// The output half of a shell.
+///
+/// The stream ends after yielding [ShellOutput::Exit].
+pub trait ShellRead: Stream<Item = adb::Result<ShellOutput>> + Send + Unpin {}
+impl<T: Stream<Item = adb::Result<ShellOutput>> + Send + Unpin> ShellRead for T {}
 
-pub trait ShellWrite: Send {
-  fn write(&mut self, event: ShellInput) -> Pin<Box<dyn Future<Output = adb::Result<()>> + Send + '_>>;
-}
+/// The input half of a shell.
+///
+/// Input is buffered: it's only guaranteed to have been sent once the sink has been flushed.
+pub trait ShellWrite: Sink<ShellInput, Error = adb::Error> + Send + Unpin {}
+impl<T: Sink<ShellInput, Error = adb::Error> + Send + Unpin> ShellWrite for T {}
 
-pub trait Shell: ShellRead + ShellWrite + Send {
-  fn split(self: Box<Self>) -> (Box<ShellRead>, Box<ShellWrite>);
+/// A connection to a shell service, created by [ShellBuilder::connect].
+pub trait Shell: ShellRead + ShellWrite {
+  /// Splits the shell into halves that can be used independently, e.g. from separate tasks.
+  fn split(self: Box<Self>) -> (Box<dyn ShellRead>, Box<dyn ShellWrite>);
 }
 
-impl Shell {
+impl dyn Shell {
   pub fn builder() -> ShellBuilder {
     ShellBuilder::new()
   }
 }
 
+/// Quotes an argument for a POSIX shell, so that it's passed to the command unchanged.
+///
+/// Arguments that consist only of characters that the shell doesn't interpret are returned as is, and everything
+/// else is wrapped in single quotes.
+pub fn quote(arg: &str) -> Cow<'_, str> {
+  let is_safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./-_".contains(c);
+  if !arg.is_empty() && arg.chars().all(is_safe) {
+    return Cow::Borrowed(arg);
+  }
+
+  // Single quotes can't be escaped inside of single quotes, so end the quoted string, and add an escaped one.
+  Cow::Owned(format!("'{}'", arg.replace('\'', "'\\''")))
+}
+
+/// The command run by a shell.
+#[derive(Clone, Debug, PartialEq)]
+enum ShellCommand {
+  /// Arguments, which are quoted individually.
+  Argv(Vec<String>),
+
+  /// A command line that is interpreted by the device's shell.
+  Raw(String),
+}
+
+impl ShellCommand {
+  fn to_command_line(&self) -> String {
+    match self {
+      ShellCommand::Argv(argv) => argv.iter().map(|arg| quote(arg)).collect::<Vec<_>>().join(" "),
+      ShellCommand::Raw(command) => command.clone(),
+    }
+  }
+}
+
 /// Builder for a [Shell].
 pub struct ShellBuilder {
-  command: Option<Vec<String>>,
+  command: Option<ShellCommand>,
   shell_protocol: Option<bool>,
   term: Option<String>,
   tty: Option<bool>,
+  exit_code_sentinel: bool,
+  env: Vec<(String, String)>,
+  cwd: Option<String>,
+  run_as: Option<String>,
+  timeout: Option<Duration>,
+  no_stdin: bool,
+}
+
+impl Default for ShellBuilder {
+  fn default() -> ShellBuilder {
+    ShellBuilder::new()
+  }
 }
 
 impl ShellBuilder {
@@ -65,11 +126,30 @@ impl ShellBuilder {
       shell_protocol: None,
       term: None,
       tty: None,
+      exit_code_sentinel: false,
+      env: Vec::new(),
+      cwd: None,
+      run_as: None,
+      timeout: None,
+      no_stdin: false,
     }
   }
 
-  pub fn command(&mut self, cmd: Option<Vec<String>>) -> &mut ShellBuilder {
-    self.command = cmd;
+  /// Sets the arguments of the command to run, or `None` for an interactive shell.
+  ///
+  /// Each argument is [quoted](quote), so the command receives them unchanged, even if they contain whitespace or
+  /// characters that are special to the shell.
+  pub fn command(&mut self, argv: Option<Vec<String>>) -> &mut ShellBuilder {
+    self.command = argv.map(ShellCommand::Argv);
+    self
+  }
+
+  /// Sets the command to run as a command line that is interpreted by the device's shell, or `None` for an
+  /// interactive shell.
+  ///
+  /// This allows the use of pipes, redirections, variables, etc., but the command must be quoted by the caller.
+  pub fn raw_command(&mut self, command: Option<String>) -> &mut ShellBuilder {
+    self.command = command.map(ShellCommand::Raw);
     self
   }
 
@@ -88,7 +168,117 @@ impl ShellBuilder {
     self
   }
 
-  pub async fn connect(&self, remote: Remote, device_criteria: DeviceCriteria) -> adb::Result<Box<Shell>> {
+  /// Sets whether to report the real exit code of the command when not using the shell protocol.
+  ///
+  /// Without the shell protocol, the exit code is normally always 1. With this enabled, the command is wrapped so
+  /// that it prints a unique sentinel with its exit code after it exits, which is removed from the output. The last
+  /// few bytes of output are held back until it's clear that they aren't the sentinel.
+  ///
+  /// This has no effect on the shell protocol, or on interactive shells.
+  pub fn exit_code_sentinel(&mut self, enabled: bool) -> &mut ShellBuilder {
+    self.exit_code_sentinel = enabled;
+    self
+  }
+
+  /// Sets an environment variable for the command.
+  ///
+  /// The key must be a valid shell variable name, or connecting fails with
+  /// [Error::InvalidArgument](crate::Error::InvalidArgument).
+  pub fn env(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut ShellBuilder {
+    self.env.push((key.into(), value.into()));
+    self
+  }
+
+  /// Sets the directory to run the command in.
+  pub fn cwd(&mut self, path: Option<String>) -> &mut ShellBuilder {
+    self.cwd = path;
+    self
+  }
+
+  /// Runs the command as the user of a debuggable app, with `run-as`.
+  pub fn run_as(&mut self, package: Option<String>) -> &mut ShellBuilder {
+    self.run_as = package;
+    self
+  }
+
+  /// Sets the maximum time for the command to run, after which it's killed on the device.
+  ///
+  /// This uses the device's `timeout` command (available since Android 6.0), so a command that times out exits with
+  /// exit code 124.
+  pub fn timeout(&mut self, timeout: Option<Duration>) -> &mut ShellBuilder {
+    self.timeout = timeout;
+    self
+  }
+
+  /// Sets whether to close the command's stdin immediately, as if it were redirected from `/dev/null`.
+  pub fn no_stdin(&mut self, no_stdin: bool) -> &mut ShellBuilder {
+    self.no_stdin = no_stdin;
+    self
+  }
+
+  /// Renders the command and its options into the command line passed to the shell service.
+  ///
+  /// Returns `None` for an interactive shell without any options.
+  pub(crate) fn command_line(&self, shell_protocol: bool) -> adb::Result<Option<String>> {
+    let mut prefix = Vec::new();
+    if let Some(cwd) = &self.cwd {
+      prefix.push(format!("cd {}", quote(cwd)));
+    }
+
+    for (key, value) in &self.env {
+      let mut chars = key.chars();
+      let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+      if !valid {
+        return Err(adb::Error::InvalidArgument(format!(
+          "invalid environment variable name '{}'",
+          key
+        )));
+      }
+      prefix.push(format!("export {}={}", key, quote(value)));
+    }
+
+    // The shell protocol can close stdin explicitly, see ShellBuilder::connect.
+    if self.no_stdin && !shell_protocol {
+      prefix.push("exec </dev/null".into());
+    }
+
+    let wrapped = self.run_as.is_some() || self.timeout.is_some();
+    let command = match (&self.command, prefix.is_empty()) {
+      (None, true) if !wrapped => return Ok(None),
+      (None, _) => "exec sh".to_string(),
+      (Some(command), true) => command.to_command_line(),
+
+      // A raw command line can contain multiple commands, so make sure that none of them run if the prefix failed.
+      (Some(command), false) => match command {
+        ShellCommand::Argv(_) => command.to_command_line(),
+        ShellCommand::Raw(command) => format!("sh -c {}", quote(command)),
+      },
+    };
+
+    prefix.push(command);
+    let mut command_line = prefix.join(" && ");
+    if wrapped {
+      let mut wrapper = Vec::new();
+      if let Some(timeout) = self.timeout {
+        wrapper.push(format!("timeout {}", timeout.as_secs_f64()));
+      }
+
+      if let Some(package) = &self.run_as {
+        wrapper.push(format!("run-as {}", quote(package)));
+      }
+
+      wrapper.push(format!("sh -c {}", quote(&command_line)));
+      command_line = wrapper.join(" ");
+    }
+    Ok(Some(command_line))
+  }
+
+  /// Connects to the shell service.
+  ///
+  /// The [Timeouts](crate::client::Timeouts) and [RetryPolicy](crate::client::RetryPolicy) of `remote` apply to
+  /// opening the service, and its read timeout applies to the shell's output.
+  pub async fn connect(&self, remote: Remote, device_criteria: DeviceCriteria) -> adb::Result<Box<dyn Shell>> {
     let shell_protocol = match self.shell_protocol {
       Some(value) => value,
       None => {
@@ -111,23 +301,133 @@ impl ShellBuilder {
       }
       service.push(':');
 
-      if let Some(cmd) = &self.command {
-        service.push_str(&cmd.join(" "));
+      if let Some(command_line) = self.command_line(true)? {
+        service.push_str(&command_line);
       }
 
+      debug!(service = %service, "connecting to shell protocol service");
       let (_, channel) = remote.open_device_channel(device_criteria, service).await?;
-      let shell: Box<Shell> = Box::new(ProtocolShell::new(channel));
+      let mut shell: Box<dyn Shell> = Box::new(ProtocolShell::new(channel));
+      if self.no_stdin {
+        shell.send(ShellInput::CloseStdin).await?;
+      }
       Ok(shell)
     } else {
-      let service = if let Some(cmd) = &self.command {
-        "shell:".to_string() + &cmd.join(" ")
+      let mut sentinel = None;
+      let service = if let Some(mut command_line) = self.command_line(false)? {
+        if self.exit_code_sentinel && self.command.is_some() {
+          let marker = raw::sentinel_marker();
+          command_line = raw::wrap_command(&command_line, &marker);
+          sentinel = Some(marker);
+        }
+        "shell:".to_string() + &command_line
       } else {
         "shell:".into()
       };
 
+      debug!(service = %service, "connecting to raw shell service");
       let (_, channel) = remote.open_device_channel(device_criteria, service).await?;
-      let shell: Box<Shell> = Box::new(RawShell::new(channel));
+      let shell: Box<dyn Shell> = Box::new(RawShell::new(channel, sentinel));
       Ok(shell)
     }
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::runtime::block_on;
+  use crate::testing::{device, FakeServer};
+
+  #[test]
+  fn quoting() {
+    assert_eq!("foo", quote("foo"));
+    assert_eq!("/data/local/tmp/a-b_c.txt", quote("/data/local/tmp/a-b_c.txt"));
+    assert_eq!("''", quote(""));
+    assert_eq!("'a b'", quote("a b"));
+    assert_eq!("'a;b'", quote("a;b"));
+    assert_eq!("'$HOME'", quote("$HOME"));
+    assert_eq!("'\"'", quote("\""));
+    assert_eq!("'it'\\''s'", quote("it's"));
+    assert_eq!("'a\nb'", quote("a\nb"));
+    assert_eq!("'*'", quote("*"));
+    assert_eq!("'\\'", quote("\\"));
+  }
+
+  #[test]
+  fn command_lines() {
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1));
+    server.on_shell("echo 'a b' '$(reboot)' 'it'\\''s'", b"", b"", 0);
+    server.on_shell("ls | grep foo", b"", b"", 0);
+
+    block_on(async {
+      ShellBuilder::new()
+        .command(Some(vec![
+          "echo".into(),
+          "a b".into(),
+          "$(reboot)".into(),
+          "it's".into(),
+        ]))
+        .shell_protocol(false)
+        .connect(server.remote(), DeviceCriteria::Any)
+        .await
+        .unwrap();
+
+      ShellBuilder::new()
+        .raw_command(Some("ls | grep foo".into()))
+        .shell_protocol(true)
+        .connect(server.remote(), DeviceCriteria::Any)
+        .await
+        .unwrap();
+    });
+
+    server.assert_device_services(&["shell:echo 'a b' '$(reboot)' 'it'\\''s'", "shell,v2,raw:ls | grep foo"]);
+  }
+
+  #[test]
+  fn command_options() {
+    let mut builder = ShellBuilder::new();
+    builder
+      .command(Some(vec!["ls".into(), "-l".into()]))
+      .cwd(Some("/data/local/tmp".into()))
+      .env("FOO", "1")
+      .env("BAR", "a b");
+    assert_eq!(
+      "cd /data/local/tmp && export FOO=1 && export BAR='a b' && ls -l",
+      builder.command_line(true).unwrap().unwrap()
+    );
+
+    builder.raw_command(Some("ls | grep $FOO".into()));
+    assert_eq!(
+      "cd /data/local/tmp && export FOO=1 && export BAR='a b' && sh -c 'ls | grep $FOO'",
+      builder.command_line(true).unwrap().unwrap()
+    );
+
+    let mut builder = ShellBuilder::new();
+    builder
+      .command(Some(vec!["id".into()]))
+      .run_as(Some("com.example".into()))
+      .timeout(Some(Duration::from_millis(1500)));
+    assert_eq!(
+      "timeout 1.5 run-as com.example sh -c id",
+      builder.command_line(true).unwrap().unwrap()
+    );
+
+    let mut builder = ShellBuilder::new();
+    builder.command(Some(vec!["cat".into()])).no_stdin(true);
+    assert_eq!("cat", builder.command_line(true).unwrap().unwrap());
+    assert_eq!("exec </dev/null && cat", builder.command_line(false).unwrap().unwrap());
+
+    let mut builder = ShellBuilder::new();
+    assert_eq!(None, builder.command_line(true).unwrap());
+    builder.cwd(Some("/sdcard".into()));
+    assert_eq!("cd /sdcard && exec sh", builder.command_line(true).unwrap().unwrap());
+
+    builder.env("NOT VALID", "");
+    match builder.command_line(true) {
+      Err(adb::Error::InvalidArgument(_)) => {}
+      other => panic!("unexpected result: {:?}", other),
+    }
+  }
+}
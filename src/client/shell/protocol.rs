@@ -1,14 +1,21 @@
-use futures::future::Future;
-use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use std::pin::Pin;
-
 use byteorder::{ByteOrder, LittleEndian};
+use bytes::{Buf, BufMut, BytesMut};
+use futures::io::{AsyncReadExt, ReadHalf, WriteHalf};
+use futures::ready;
+use futures::sink::Sink;
+use futures::stream::Stream;
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
+use tracing::trace;
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 use crate as adb;
+use crate::client::shell::buffer::{poll_fill, WriteBuffer};
 use crate::client::shell::{Shell, ShellInput, ShellOutput, ShellRead, ShellWrite};
 use crate::core::Socket;
+use crate::util::hexdump;
 
 #[derive(FromPrimitive, ToPrimitive)]
 #[repr(u8)]
@@ -20,120 +27,335 @@ enum Id {
   Exit = 3,
 
   CloseStdin = 4,
+  WindowSizeChange = 5,
 }
 
+/// Size of a packet header: a one byte id, followed by a little-endian u32 length.
+const HEADER_SIZE: usize = 5;
+
+/// Largest packet that will be accepted from a device, matching adb's maximum payload size. adbd sends much smaller
+/// packets than this, so anything larger means that the stream is corrupt.
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
 pub(crate) struct ProtocolShell {
   read: ProtocolShellRead,
   write: ProtocolShellWrite,
 }
 
 struct ProtocolShellRead {
-  read: Box<AsyncRead + Send + Unpin>,
+  read: ReadHalf<Box<dyn Socket>>,
+  buf: BytesMut,
+  done: bool,
 }
 
 struct ProtocolShellWrite {
-  write: Box<AsyncWrite + Send + Unpin>,
+  write: WriteBuffer<WriteHalf<Box<dyn Socket>>>,
 }
 
 impl ProtocolShell {
-  pub(crate) fn new(channel: Box<Socket>) -> ProtocolShell {
+  pub(crate) fn new(channel: Box<dyn Socket>) -> ProtocolShell {
     let (read, write) = channel.split();
     ProtocolShell {
-      read: ProtocolShellRead { read: Box::new(read) },
-      write: ProtocolShellWrite { write: Box::new(write) },
+      read: ProtocolShellRead {
+        read,
+        buf: BytesMut::new(),
+        done: false,
+      },
+      write: ProtocolShellWrite {
+        write: WriteBuffer::new(write),
+      },
     }
   }
 }
 
 impl Shell for ProtocolShell {
-  fn split(self: Box<Self>) -> (Box<ShellRead>, Box<ShellWrite>) {
+  fn split(self: Box<Self>) -> (Box<dyn ShellRead>, Box<dyn ShellWrite>) {
     (Box::new(self.read), Box::new(self.write))
   }
 }
 
-impl ShellRead for ProtocolShell {
-  fn read(&mut self) -> Pin<Box<dyn Future<Output = adb::Result<ShellOutput>> + Send + '_>> {
-    self.read.read()
+impl Stream for ProtocolShell {
+  type Item = adb::Result<ShellOutput>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+    Pin::new(&mut self.read).poll_next(cx)
   }
 }
 
-impl ShellWrite for ProtocolShell {
-  fn write(&mut self, event: ShellInput) -> Pin<Box<dyn Future<Output = adb::Result<()>> + Send + '_>> {
-    self.write.write(event)
+impl Sink<ShellInput> for ProtocolShell {
+  type Error = adb::Error;
+
+  fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<adb::Result<()>> {
+    Pin::new(&mut self.write).poll_ready(cx)
+  }
+
+  fn start_send(mut self: Pin<&mut Self>, item: ShellInput) -> adb::Result<()> {
+    Pin::new(&mut self.write).start_send(item)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<adb::Result<()>> {
+    Pin::new(&mut self.write).poll_flush(cx)
+  }
+
+  fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<adb::Result<()>> {
+    Pin::new(&mut self.write).poll_close(cx)
   }
 }
 
-impl ShellRead for ProtocolShellRead {
-  fn read(&mut self) -> Pin<Box<dyn Future<Output = adb::Result<ShellOutput>> + Send + '_>> {
-    Box::pin(async move {
-      let mut id = [0u8; 1];
-      if self.read.read_exact(&mut id).await.is_err() {
-        return Err(adb::Error::UnexpectedData("failed to read shell packet header".into()));
-      }
+impl ProtocolShellRead {
+  /// Parses a packet out of the buffer, if a complete one has been received.
+  fn parse_packet(&mut self) -> Option<adb::Result<ShellOutput>> {
+    if self.buf.len() < HEADER_SIZE {
+      return None;
+    }
+
+    let data_len = LittleEndian::read_u32(&self.buf[1..HEADER_SIZE]) as usize;
+    if data_len > MAX_PACKET_SIZE {
+      return Some(Err(adb::Error::UnexpectedData(format!(
+        "received oversized shell packet: {} bytes",
+        data_len
+      ))));
+    }
+
+    if self.buf.len() < HEADER_SIZE + data_len {
+      // Make sure that the rest of the packet fits without further reallocation.
+      self.buf.reserve(HEADER_SIZE + data_len - self.buf.len());
+      return None;
+    }
 
-      let mut data_len_buf = [0u8; 4];
-      if self.read.read_exact(&mut data_len_buf).await.is_err() {
-        return Err(adb::Error::UnexpectedData("failed to read shell data length".into()));
+    let id = self.buf[0];
+    self.buf.advance(HEADER_SIZE);
+    let data = self.buf.split_to(data_len).freeze();
+    trace!(id, len = data_len, data = %hexdump(&data), "received shell packet");
+
+    let result = match FromPrimitive::from_u8(id) {
+      Some(Id::Stdin) => Err(adb::Error::UnexpectedData(
+        "received unexpected Stdin packet from device".into(),
+      )),
+      Some(Id::CloseStdin) => Err(adb::Error::UnexpectedData(
+        "received unexpected CloseStdin packet from device".into(),
+      )),
+      Some(Id::WindowSizeChange) => Err(adb::Error::UnexpectedData(
+        "received unexpected WindowSizeChange packet from device".into(),
+      )),
+
+      Some(Id::Stdout) => Ok(ShellOutput::Stdout(data)),
+      Some(Id::Stderr) => Ok(ShellOutput::Stderr(data)),
+
+      Some(Id::Exit) => {
+        if data.len() != 1 {
+          Err(adb::Error::UnexpectedData(format!(
+            "received exit packet with incorrect size: {}",
+            data.len()
+          )))
+        } else {
+          Ok(ShellOutput::Exit(data[0]))
+        }
       }
 
-      let data_len = LittleEndian::read_u32(&data_len_buf) as usize;
-      let mut data = vec![0u8; data_len];
-      if self.read.read_exact(&mut data).await.is_err() {
-        return Err(adb::Error::UnexpectedData("failed to read shell data".into()));
+      None => Err(adb::Error::UnexpectedData(format!(
+        "received unexpected packet from device: {}",
+        id
+      ))),
+    };
+    Some(result)
+  }
+}
+
+impl Stream for ProtocolShellRead {
+  type Item = adb::Result<ShellOutput>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+    let this = &mut *self;
+    if this.done {
+      return Poll::Ready(None);
+    }
+
+    loop {
+      if let Some(result) = this.parse_packet() {
+        if let Ok(ShellOutput::Exit(_)) | Err(_) = result {
+          this.done = true;
+        }
+        return Poll::Ready(Some(result));
       }
 
-      match FromPrimitive::from_u8(id[0]) {
-        Some(Id::Stdin) => Err(adb::Error::UnexpectedData(
-          "received unexpected Stdin packet from device".into(),
-        )),
-        Some(Id::CloseStdin) => Err(adb::Error::UnexpectedData(
-          "received unexpected CloseStdin packet from device".into(),
-        )),
-
-        Some(Id::Stdout) => Ok(ShellOutput::Stdout(data)),
-        Some(Id::Stderr) => Ok(ShellOutput::Stderr(data)),
-
-        Some(Id::Exit) => {
-          if data.len() != 1 {
-            Err(adb::Error::UnexpectedData(format!(
-              "received exit packet with incorrect size: {}",
-              data.len()
-            )))
-          } else {
-            Ok(ShellOutput::Exit(data[0]))
-          }
+      match ready!(poll_fill(&mut this.read, &mut this.buf, cx)) {
+        Ok(0) => {
+          this.done = true;
+          let msg = "shell closed before sending an exit code".into();
+          return Poll::Ready(Some(Err(adb::Error::UnexpectedData(msg))));
+        }
+        Ok(_) => {}
+        Err(err) => {
+          this.done = true;
+          return Poll::Ready(Some(Err(err.into())));
         }
+      }
+    }
+  }
+}
+
+impl ProtocolShellWrite {
+  fn write_packet(&mut self, id: Id, data: &[u8]) {
+    let buf = self.write.buf_mut();
+    buf.reserve(HEADER_SIZE + data.len());
+    buf.put_u8(id.to_u8().unwrap());
+    buf.put_u32_le(data.len() as u32);
+    buf.put_slice(data);
+  }
+}
 
-        None => Err(adb::Error::UnexpectedData(format!(
-          "received unexpected packet from device: {}",
-          id[0]
-        ))),
+impl Sink<ShellInput> for ProtocolShellWrite {
+  type Error = adb::Error;
+
+  fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<adb::Result<()>> {
+    Poll::Ready(Ok(ready!(self.write.poll_ready(cx))?))
+  }
+
+  fn start_send(mut self: Pin<&mut Self>, item: ShellInput) -> adb::Result<()> {
+    match item {
+      ShellInput::Stdin(data) => {
+        trace!(len = data.len(), data = %hexdump(&data), "sending stdin");
+        self.write_packet(Id::Stdin, &data);
+      }
+
+      ShellInput::WindowSizeChange {
+        rows,
+        cols,
+        xpixels,
+        ypixels,
+      } => {
+        trace!(rows, cols, xpixels, ypixels, "sending window size");
+        let size = format!("{}x{},{}x{}", rows, cols, xpixels, ypixels);
+        self.write_packet(Id::WindowSizeChange, size.as_bytes());
       }
-    })
+
+      ShellInput::CloseStdin => {
+        trace!("closing stdin");
+        self.write_packet(Id::CloseStdin, &[]);
+      }
+    }
+    Ok(())
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<adb::Result<()>> {
+    Poll::Ready(Ok(ready!(self.write.poll_flush(cx))?))
+  }
+
+  fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<adb::Result<()>> {
+    Poll::Ready(Ok(ready!(self.write.poll_close(cx))?))
   }
 }
 
-impl ShellWrite for ProtocolShellWrite {
-  fn write(&mut self, event: ShellInput) -> Pin<Box<dyn Future<Output = adb::Result<()>> + Send + '_>> {
-    Box::pin(async move {
-      match event {
-        ShellInput::Stdin(data) => {
-          let id = [Id::Stdin.to_u8().unwrap()];
-          let mut buf = [0u8; 4];
-          LittleEndian::write_u32(&mut buf, data.len() as u32);
-          self.write.write_all(&id).await?;
-          self.write.write_all(&buf).await?;
-          self.write.write_all(&data).await?;
-          Ok(())
-        }
+#[cfg(test)]
+mod test {
+  use super::*;
+  use futures::io::{AsyncRead, AsyncWrite};
+  use futures::{SinkExt, StreamExt};
+  use std::sync::{Arc, Mutex};
 
-        ShellInput::WindowSizeChange { .. } => Ok(()),
+  /// A socket that returns its input one byte at a time, and records its output.
+  struct TrickleSocket {
+    input: Vec<u8>,
+    offset: usize,
+    output: Arc<Mutex<Vec<u8>>>,
+  }
 
-        ShellInput::CloseStdin => {
-          self.write.close().await?;
-          Ok(())
-        }
+  impl AsyncRead for TrickleSocket {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+      if self.offset == self.input.len() || buf.is_empty() {
+        return Poll::Ready(Ok(0));
       }
-    })
+      buf[0] = self.input[self.offset];
+      self.offset += 1;
+      Poll::Ready(Ok(1))
+    }
+  }
+
+  impl AsyncWrite for TrickleSocket {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+      self.output.lock().unwrap().extend_from_slice(buf);
+      Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+      Poll::Ready(Ok(()))
+    }
+  }
+
+  fn shell(input: Vec<u8>) -> (ProtocolShell, Arc<Mutex<Vec<u8>>>) {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let socket = TrickleSocket {
+      input,
+      offset: 0,
+      output: output.clone(),
+    };
+    (ProtocolShell::new(Box::new(socket)), output)
+  }
+
+  #[test]
+  fn reassemble_packets() {
+    let input = b"\x01\x03\0\0\0foo\x02\0\0\0\0\x03\x01\0\0\0\x2a".to_vec();
+    let (shell, _) = shell(input);
+    let events: Vec<_> = futures::executor::block_on(shell.collect());
+    assert_eq!(3, events.len());
+    match &events[..] {
+      [Ok(ShellOutput::Stdout(stdout)), Ok(ShellOutput::Stderr(stderr)), Ok(ShellOutput::Exit(42))] => {
+        assert_eq!(b"foo", &stdout[..]);
+        assert!(stderr.is_empty());
+      }
+      events => panic!("unexpected events: {:?}", events),
+    }
+  }
+
+  #[test]
+  fn truncated_stream() {
+    let (shell, _) = shell(b"\x01\x03\0\0\0fo".to_vec());
+    let events: Vec<_> = futures::executor::block_on(shell.collect());
+    match &events[..] {
+      [Err(adb::Error::UnexpectedData(_))] => {}
+      events => panic!("unexpected events: {:?}", events),
+    }
+  }
+
+  #[test]
+  fn oversized_packet() {
+    let (shell, _) = shell(b"\x01\xff\xff\xff\xffdata".to_vec());
+    let events: Vec<_> = futures::executor::block_on(shell.collect());
+    match &events[..] {
+      [Err(adb::Error::UnexpectedData(msg))] => assert!(msg.contains("oversized"), "unexpected message: {}", msg),
+      events => panic!("unexpected events: {:?}", events),
+    }
+  }
+
+  #[test]
+  fn write_packets() {
+    let (mut shell, output) = shell(Vec::new());
+    futures::executor::block_on(async {
+      shell.feed(ShellInput::Stdin("ls\n".into())).await.unwrap();
+      shell.feed(ShellInput::CloseStdin).await.unwrap();
+      assert!(output.lock().unwrap().is_empty());
+      shell.flush().await.unwrap();
+    });
+    assert_eq!(b"\x00\x03\0\0\0ls\n\x04\0\0\0\0", &output.lock().unwrap()[..]);
+  }
+
+  #[test]
+  fn window_size_change() {
+    let (mut shell, output) = shell(Vec::new());
+    futures::executor::block_on(async {
+      let size = ShellInput::WindowSizeChange {
+        rows: 24,
+        cols: 80,
+        xpixels: 640,
+        ypixels: 480,
+      };
+      shell.send(size).await.unwrap();
+    });
+    assert_eq!(b"\x05\x0d\0\0\x0024x80,640x480", &output.lock().unwrap()[..]);
   }
 }
@@ -1,10 +1,18 @@
-use futures::future::Future;
-use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use bytes::BytesMut;
+use futures::io::{AsyncReadExt, ReadHalf, WriteHalf};
+use futures::ready;
+use futures::sink::Sink;
+use futures::stream::Stream;
+use tracing::trace;
+
 use std::pin::Pin;
+use std::task::{Context, Poll};
 
 use crate as adb;
+use crate::client::shell::buffer::{poll_fill, WriteBuffer};
 use crate::client::shell::{Shell, ShellInput, ShellOutput, ShellRead, ShellWrite};
 use crate::core::Socket;
+use crate::util::hexdump;
 
 pub(crate) struct RawShell {
   read: RawShellRead,
@@ -12,71 +20,385 @@ pub(crate) struct RawShell {
 }
 
 struct RawShellRead {
-  read: Box<AsyncRead + Send + Unpin>,
+  read: ReadHalf<Box<dyn Socket>>,
+  buf: BytesMut,
+
+  /// Marker of the sentinel that reports the exit code at the end of the output, if the command was wrapped with
+  /// [wrap_command].
+  sentinel: Option<Vec<u8>>,
+  eof: bool,
+  done: bool,
 }
 
 struct RawShellWrite {
-  write: Box<AsyncWrite + Send + Unpin>,
+  write: WriteBuffer<WriteHalf<Box<dyn Socket>>>,
+  close_requested: bool,
 }
 
 impl RawShell {
-  pub(crate) fn new(channel: Box<Socket>) -> RawShell {
+  pub(crate) fn new(channel: Box<dyn Socket>, sentinel: Option<String>) -> RawShell {
     let (read, write) = channel.split();
     RawShell {
-      read: RawShellRead { read: Box::new(read) },
-      write: RawShellWrite { write: Box::new(write) },
+      read: RawShellRead {
+        read,
+        buf: BytesMut::new(),
+        sentinel: sentinel.map(String::into_bytes),
+        eof: false,
+        done: false,
+      },
+      write: RawShellWrite {
+        write: WriteBuffer::new(write),
+        close_requested: false,
+      },
     }
   }
 }
 
 impl Shell for RawShell {
-  fn split(self: Box<Self>) -> (Box<ShellRead>, Box<ShellWrite>) {
+  fn split(self: Box<Self>) -> (Box<dyn ShellRead>, Box<dyn ShellWrite>) {
     (Box::new(self.read), Box::new(self.write))
   }
 }
 
-impl ShellRead for RawShell {
-  fn read(&mut self) -> Pin<Box<dyn Future<Output = adb::Result<ShellOutput>> + Send + '_>> {
-    self.read.read()
+impl Stream for RawShell {
+  type Item = adb::Result<ShellOutput>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+    Pin::new(&mut self.read).poll_next(cx)
   }
 }
 
-impl ShellWrite for RawShell {
-  fn write(&mut self, event: ShellInput) -> Pin<Box<dyn Future<Output = adb::Result<()>> + Send + '_>> {
-    self.write.write(event)
+impl Sink<ShellInput> for RawShell {
+  type Error = adb::Error;
+
+  fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<adb::Result<()>> {
+    Pin::new(&mut self.write).poll_ready(cx)
+  }
+
+  fn start_send(mut self: Pin<&mut Self>, item: ShellInput) -> adb::Result<()> {
+    Pin::new(&mut self.write).start_send(item)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<adb::Result<()>> {
+    Pin::new(&mut self.write).poll_flush(cx)
+  }
+
+  fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<adb::Result<()>> {
+    Pin::new(&mut self.write).poll_close(cx)
   }
 }
 
-impl ShellRead for RawShellRead {
-  fn read(&mut self) -> Pin<Box<dyn Future<Output = adb::Result<ShellOutput>> + Send + '_>> {
-    Box::pin(async move {
-      let mut buf = [0u8; 2048];
-      let len = self.read.read(&mut buf).await;
-      let event = match len {
-        Ok(0) | Err(_) => ShellOutput::Exit(1),
-        Ok(len) => ShellOutput::Stdout(buf[..len].to_vec()),
-      };
-      Ok(event)
-    })
+impl RawShellRead {
+  /// Returns the length of the longest suffix of the buffer that could be the start of the sentinel.
+  fn sentinel_candidate_len(&self, marker: &[u8]) -> usize {
+    let max_len = std::cmp::min(self.buf.len(), marker.len() + MAX_SENTINEL_SUFFIX);
+    (1..=max_len)
+      .rev()
+      .find(|&len| could_be_sentinel(marker, &self.buf[self.buf.len() - len..]))
+      .unwrap_or(0)
+  }
+
+  fn poll_next_with_sentinel(&mut self, cx: &mut Context, marker: &[u8]) -> Poll<Option<adb::Result<ShellOutput>>> {
+    loop {
+      if self.eof {
+        self.done = true;
+        let event = match parse_sentinel(marker, &self.buf) {
+          Some(exit_code) => Ok(ShellOutput::Exit(exit_code)),
+          None => Err(adb::Error::UnexpectedData(
+            "shell closed without reporting an exit code".into(),
+          )),
+        };
+        return Poll::Ready(Some(event));
+      }
+
+      match ready!(poll_fill(&mut self.read, &mut self.buf, cx)) {
+        Ok(0) => {
+          trace!("received EOF");
+          self.eof = true;
+
+          // Flush everything before the sentinel, or everything if there isn't one.
+          let output_len = match parse_sentinel_start(marker, &self.buf) {
+            Some(start) => start,
+            None => self.buf.len(),
+          };
+          if output_len > 0 {
+            return Poll::Ready(Some(Ok(ShellOutput::Stdout(self.buf.split_to(output_len).freeze()))));
+          }
+        }
+
+        Err(err) => {
+          trace!(error = %err, "read failed");
+          self.done = true;
+          return Poll::Ready(Some(Err(err.into())));
+        }
+
+        Ok(len) => {
+          trace!(len, data = %hexdump(&self.buf), "received output");
+
+          // Hold back anything that might be the sentinel, until we know whether it's at the end of the output.
+          let output_len = self.buf.len() - self.sentinel_candidate_len(marker);
+          if output_len > 0 {
+            return Poll::Ready(Some(Ok(ShellOutput::Stdout(self.buf.split_to(output_len).freeze()))));
+          }
+        }
+      }
+    }
+  }
+}
+
+impl Stream for RawShellRead {
+  type Item = adb::Result<ShellOutput>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+    let this = &mut *self;
+    if this.done {
+      return Poll::Ready(None);
+    }
+
+    if let Some(marker) = this.sentinel.take() {
+      let result = this.poll_next_with_sentinel(cx, &marker);
+      this.sentinel = Some(marker);
+      return result;
+    }
+
+    // Without the sentinel, there's no way to find out the exit code, so EOF is reported as a failure.
+    let event = match ready!(poll_fill(&mut this.read, &mut this.buf, cx)) {
+      Ok(0) => {
+        trace!("received EOF");
+        this.done = true;
+        ShellOutput::Exit(1)
+      }
+      Err(err) => {
+        trace!(error = %err, "read failed");
+        this.done = true;
+        return Poll::Ready(Some(Err(err.into())));
+      }
+      Ok(len) => {
+        trace!(len, data = %hexdump(&this.buf), "received output");
+        ShellOutput::Stdout(this.buf.split().freeze())
+      }
+    };
+    Poll::Ready(Some(Ok(event)))
+  }
+}
+
+/// Maximum length of the sentinel after its marker: a three digit exit code, followed by `\r\n` when using a pty.
+const MAX_SENTINEL_SUFFIX: usize = 5;
+
+/// Generates a marker for the sentinel that is unlikely to appear in the output of a command.
+pub(crate) fn sentinel_marker() -> String {
+  use std::hash::{BuildHasher, Hasher};
+  let random = std::collections::hash_map::RandomState::new().build_hasher().finish();
+  format!("ADB_EXIT_{:016x}:", random)
+}
+
+/// Wraps a command line to print the sentinel with its exit code after it exits.
+pub(crate) fn wrap_command(command: &str, marker: &str) -> String {
+  // A newline instead of `;` works even if the command ends with `&`.
+  format!("{}\necho {}$?", command, marker)
+}
+
+/// Splits a command line wrapped with [wrap_command] into the original command line and the marker, for fakes that
+/// emulate the sentinel.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) fn unwrap_command(command_line: &str) -> Option<(&str, &str)> {
+  let (command, tail) = command_line.rsplit_once("\necho ")?;
+  Some((command, tail.strip_suffix("$?")?))
+}
+
+/// Returns whether `data` could be the start of the sentinel (or all of it).
+fn could_be_sentinel(marker: &[u8], data: &[u8]) -> bool {
+  if data.len() <= marker.len() {
+    return marker.starts_with(data);
+  }
+
+  if !data.starts_with(marker) {
+    return false;
+  }
+
+  let suffix = &data[marker.len()..];
+  let digits = suffix.iter().take_while(|c| c.is_ascii_digit()).count();
+  digits <= 3 && matches!(&suffix[digits..], [] | [b'\r'] | [b'\n'] | [b'\r', b'\n'])
+}
+
+/// Returns the offset of the complete sentinel at the end of `data`, if there is one.
+fn parse_sentinel_start(marker: &[u8], data: &[u8]) -> Option<usize> {
+  let max_len = std::cmp::min(data.len(), marker.len() + MAX_SENTINEL_SUFFIX);
+  let start = (data.len() - max_len..data.len()).find(|&start| data[start..].starts_with(marker))?;
+  parse_sentinel(marker, &data[start..]).map(|_| start)
+}
+
+/// Parses the exit code from a buffer that ends with the sentinel.
+fn parse_sentinel(marker: &[u8], data: &[u8]) -> Option<u8> {
+  let data = data.strip_suffix(b"\n")?;
+  let data = data.strip_suffix(b"\r").unwrap_or(data);
+  let max_len = std::cmp::min(data.len(), marker.len() + 3);
+  let start = (data.len() - max_len..data.len()).find(|&start| data[start..].starts_with(marker))?;
+  let exit_code = &data[start + marker.len()..];
+  if exit_code.is_empty() || !exit_code.iter().all(|c| c.is_ascii_digit()) {
+    return None;
+  }
+  std::str::from_utf8(exit_code).ok()?.parse().ok()
+}
+
+impl Sink<ShellInput> for RawShellWrite {
+  type Error = adb::Error;
+
+  fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<adb::Result<()>> {
+    Poll::Ready(Ok(ready!(self.write.poll_ready(cx))?))
+  }
+
+  fn start_send(mut self: Pin<&mut Self>, item: ShellInput) -> adb::Result<()> {
+    match item {
+      ShellInput::Stdin(data) => {
+        trace!(len = data.len(), data = %hexdump(&data), "sending stdin");
+        self.write.buf_mut().extend_from_slice(&data);
+      }
+
+      // There's no way to tell the raw shell about the window size.
+      ShellInput::WindowSizeChange { .. } => {}
+
+      ShellInput::CloseStdin => {
+        // Without the shell protocol, the only way to signal EOF is to close the socket.
+        trace!("closing stdin");
+        self.close_requested = true;
+      }
+    }
+    Ok(())
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<adb::Result<()>> {
+    let result = if self.close_requested {
+      ready!(self.write.poll_close(cx))
+    } else {
+      ready!(self.write.poll_flush(cx))
+    };
+    Poll::Ready(Ok(result?))
+  }
+
+  fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<adb::Result<()>> {
+    Poll::Ready(Ok(ready!(self.write.poll_close(cx))?))
   }
 }
 
-impl ShellWrite for RawShellWrite {
-  fn write(&mut self, event: ShellInput) -> Pin<Box<dyn Future<Output = adb::Result<()>> + Send + '_>> {
-    Box::pin(async move {
-      match event {
-        ShellInput::Stdin(data) => {
-          self.write.write_all(&data).await?;
-          Ok(())
+#[cfg(test)]
+mod test {
+  use super::*;
+  use futures::io::{AsyncRead, AsyncWrite};
+  use futures::stream::StreamExt;
+
+  /// Socket that returns its input in fixed size chunks, followed by EOF or an error.
+  struct ChunkedSocket {
+    input: Vec<u8>,
+    offset: usize,
+    chunk_size: usize,
+    fail: bool,
+  }
+
+  impl AsyncRead for ChunkedSocket {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+      if self.offset == self.input.len() {
+        if self.fail {
+          return Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()));
         }
+        return Poll::Ready(Ok(0));
+      }
+
+      let len = std::cmp::min(
+        buf.len(),
+        std::cmp::min(self.chunk_size, self.input.len() - self.offset),
+      );
+      buf[..len].copy_from_slice(&self.input[self.offset..self.offset + len]);
+      self.offset += len;
+      Poll::Ready(Ok(len))
+    }
+  }
+
+  impl AsyncWrite for ChunkedSocket {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+      Poll::Ready(Ok(buf.len()))
+    }
 
-        ShellInput::WindowSizeChange { .. } => Ok(()),
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+      Poll::Ready(Ok(()))
+    }
+  }
 
-        ShellInput::CloseStdin => {
-          self.write.close().await?;
-          Ok(())
+  /// Runs a raw shell over the input, returning its stdout and the result of the last event.
+  fn run(input: &[u8], chunk_size: usize, fail: bool, marker: Option<&str>) -> (Vec<u8>, adb::Result<u8>) {
+    let socket = ChunkedSocket {
+      input: input.to_vec(),
+      offset: 0,
+      chunk_size,
+      fail,
+    };
+    let mut shell = RawShell::new(Box::new(socket), marker.map(String::from));
+    futures::executor::block_on(async {
+      let mut stdout = Vec::new();
+      loop {
+        match shell.next().await.unwrap() {
+          Ok(ShellOutput::Stdout(data)) => stdout.extend_from_slice(&data),
+          Ok(ShellOutput::Stderr(_)) => panic!("raw shell produced stderr"),
+          Ok(ShellOutput::Exit(exit_code)) => {
+            assert!(shell.next().await.is_none());
+            return (stdout, Ok(exit_code));
+          }
+          Err(err) => return (stdout, Err(err)),
         }
       }
     })
   }
+
+  #[test]
+  fn sentinel() {
+    let marker = sentinel_marker();
+    assert_eq!("ls\necho ".to_string() + &marker + "$?", wrap_command("ls", &marker));
+    assert_eq!(
+      Some(("ls", marker.as_str())),
+      unwrap_command(&wrap_command("ls", &marker))
+    );
+    assert_eq!(None, unwrap_command("ls"));
+
+    // The command line (and therefore the marker) can show up in the output, e.g. from `ps`.
+    let output = format!("sh -c ls\\necho {}$?\nfoo", marker);
+    for suffix in ["\n", "\r\n"] {
+      let input = format!("{}{}42{}", output, marker, suffix);
+      for chunk_size in [1, 3, 16, 4096] {
+        let (stdout, result) = run(input.as_bytes(), chunk_size, false, Some(&marker));
+        assert_eq!(output.as_bytes(), &stdout[..]);
+        assert_eq!(42, result.unwrap());
+      }
+    }
+  }
+
+  #[test]
+  fn missing_sentinel() {
+    let marker = sentinel_marker();
+    let input = format!("foo\n{}", &marker[..5]);
+    let (stdout, result) = run(input.as_bytes(), 1, false, Some(&marker));
+    assert_eq!(input.as_bytes(), &stdout[..]);
+    match result {
+      Err(adb::Error::UnexpectedData(_)) => {}
+      other => panic!("unexpected result: {:?}", other),
+    }
+  }
+
+  #[test]
+  fn read_errors() {
+    let marker = sentinel_marker();
+    for marker in [None, Some(marker.as_str())] {
+      let (stdout, result) = run(b"foo", 4096, true, marker);
+      assert_eq!(b"foo", &stdout[..]);
+      match result {
+        Err(adb::Error::IoError(err)) => assert_eq!(std::io::ErrorKind::ConnectionReset, err.kind()),
+        other => panic!("unexpected result: {:?}", other),
+      }
+    }
+
+    assert_eq!(1, run(b"foo", 4096, false, None).1.unwrap());
+  }
 }
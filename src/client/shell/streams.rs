@@ -0,0 +1,331 @@
+//! Byte stream adapters over a [Shell].
+
+use bytes::{Buf, Bytes};
+use futures::future::Future;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::ready;
+use futures::sink::Sink;
+use futures::stream::Stream;
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate as adb;
+use crate::client::shell::{Shell, ShellInput, ShellOutput, ShellRead, ShellWrite};
+
+/// A [Shell] split into byte streams, created with [ShellStreams::new].
+///
+/// Output is read from the shell by whichever of `stdout`, `stderr` and `exit_code` is polled, and data for the
+/// other streams is buffered until they're read. Drop a stream to discard its output instead.
+///
+/// Without the shell protocol, stderr is merged into stdout, and the exit code is always 1 unless
+/// [ShellBuilder::exit_code_sentinel](crate::client::shell::ShellBuilder::exit_code_sentinel) is enabled.
+pub struct ShellStreams {
+  pub stdin: ShellStdin,
+  pub stdout: ShellStdout,
+  pub stderr: ShellStderr,
+  pub exit_code: ShellExitCode,
+}
+
+impl ShellStreams {
+  pub fn new(shell: Box<dyn Shell>) -> ShellStreams {
+    let (read, write) = shell.split();
+    let demux = Arc::new(Mutex::new(Demux {
+      read,
+      channels: Default::default(),
+      exit_code: None,
+      error: None,
+      finished: false,
+      exit_waker: None,
+    }));
+
+    ShellStreams {
+      stdin: ShellStdin { write, closed: false },
+      stdout: ShellStdout(OutputStream::new(demux.clone(), STDOUT)),
+      stderr: ShellStderr(OutputStream::new(demux.clone(), STDERR)),
+      exit_code: ShellExitCode { demux },
+    }
+  }
+}
+
+impl From<Box<dyn Shell>> for ShellStreams {
+  fn from(shell: Box<dyn Shell>) -> ShellStreams {
+    ShellStreams::new(shell)
+  }
+}
+
+const STDOUT: usize = 0;
+const STDERR: usize = 1;
+
+#[derive(Default)]
+struct Channel {
+  chunks: VecDeque<Bytes>,
+  waker: Option<Waker>,
+  dropped: bool,
+}
+
+/// Shared state that dispatches the output of a shell to its consumers.
+struct Demux {
+  read: Box<dyn ShellRead>,
+  channels: [Channel; 2],
+  exit_code: Option<u8>,
+  error: Option<(std::io::ErrorKind, String)>,
+  finished: bool,
+  exit_waker: Option<Waker>,
+}
+
+impl Demux {
+  /// Reads output from the shell until `done` returns true or the shell finishes.
+  fn poll_until(&mut self, cx: &mut Context, done: impl Fn(&Demux) -> bool) -> Poll<()> {
+    while !self.finished && !done(self) {
+      match ready!(Pin::new(&mut self.read).poll_next(cx)) {
+        Some(Ok(ShellOutput::Stdout(data))) => self.push(STDOUT, data),
+        Some(Ok(ShellOutput::Stderr(data))) => self.push(STDERR, data),
+        Some(Ok(ShellOutput::Exit(exit_code))) => {
+          self.exit_code = Some(exit_code);
+          self.finish();
+        }
+        Some(Err(err)) => {
+          let err = std::io::Error::from(err);
+          self.error = Some((err.kind(), err.to_string()));
+          self.finish();
+        }
+        None => {
+          if self.exit_code.is_none() && self.error.is_none() {
+            self.error = Some((std::io::ErrorKind::UnexpectedEof, "shell closed without exiting".into()));
+          }
+          self.finish();
+        }
+      }
+    }
+    Poll::Ready(())
+  }
+
+  fn push(&mut self, index: usize, data: Bytes) {
+    let channel = &mut self.channels[index];
+    if channel.dropped || data.is_empty() {
+      return;
+    }
+
+    channel.chunks.push_back(data);
+    if let Some(waker) = channel.waker.take() {
+      waker.wake();
+    }
+  }
+
+  fn finish(&mut self) {
+    self.finished = true;
+    self.wake_all();
+  }
+
+  fn wake_all(&mut self) {
+    for channel in &mut self.channels {
+      if let Some(waker) = channel.waker.take() {
+        waker.wake();
+      }
+    }
+
+    if let Some(waker) = self.exit_waker.take() {
+      waker.wake();
+    }
+  }
+
+  fn error(&self) -> Option<std::io::Error> {
+    self
+      .error
+      .as_ref()
+      .map(|(kind, message)| std::io::Error::new(*kind, message.clone()))
+  }
+}
+
+struct OutputStream {
+  demux: Arc<Mutex<Demux>>,
+  index: usize,
+}
+
+impl OutputStream {
+  fn new(demux: Arc<Mutex<Demux>>, index: usize) -> OutputStream {
+    OutputStream { demux, index }
+  }
+
+  fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+    let index = self.index;
+    let mut demux = self.demux.lock().unwrap();
+    demux.channels[index].waker = Some(cx.waker().clone());
+
+    if demux
+      .poll_until(cx, |demux| !demux.channels[index].chunks.is_empty())
+      .is_pending()
+    {
+      return Poll::Pending;
+    }
+
+    let chunks = &mut demux.channels[index].chunks;
+    match chunks.front_mut() {
+      Some(chunk) => {
+        let len = std::cmp::min(chunk.len(), buf.len());
+        buf[..len].copy_from_slice(&chunk[..len]);
+        chunk.advance(len);
+        if chunk.is_empty() {
+          chunks.pop_front();
+        }
+        Poll::Ready(Ok(len))
+      }
+
+      // Report errors on the output streams as well, so that truncated output isn't mistaken for EOF.
+      None => Poll::Ready(demux.error().map_or(Ok(0), Err)),
+    }
+  }
+}
+
+impl Drop for OutputStream {
+  fn drop(&mut self) {
+    let mut demux = self.demux.lock().unwrap();
+    let channel = &mut demux.channels[self.index];
+    channel.dropped = true;
+    channel.chunks.clear();
+    channel.waker = None;
+
+    // This stream might have been the one that the shell was going to wake up.
+    demux.wake_all();
+  }
+}
+
+/// The stdout of a shell, as an [AsyncRead].
+pub struct ShellStdout(OutputStream);
+
+impl AsyncRead for ShellStdout {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+    self.0.poll_read(cx, buf)
+  }
+}
+
+/// The stderr of a shell, as an [AsyncRead].
+pub struct ShellStderr(OutputStream);
+
+impl AsyncRead for ShellStderr {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+    self.0.poll_read(cx, buf)
+  }
+}
+
+/// A future that resolves to the exit code of a shell.
+pub struct ShellExitCode {
+  demux: Arc<Mutex<Demux>>,
+}
+
+impl Future for ShellExitCode {
+  type Output = adb::Result<u8>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<adb::Result<u8>> {
+    let mut demux = self.demux.lock().unwrap();
+    demux.exit_waker = Some(cx.waker().clone());
+    ready!(demux.poll_until(cx, |_| false));
+    match (demux.exit_code, demux.error()) {
+      (Some(exit_code), _) => Poll::Ready(Ok(exit_code)),
+      (None, Some(err)) => Poll::Ready(Err(err.into())),
+      (None, None) => unreachable!("shell finished without an exit code or error"),
+    }
+  }
+}
+
+/// The stdin of a shell, as an [AsyncWrite].
+///
+/// Closing it sends [ShellInput::CloseStdin], and doesn't affect the shell's output.
+pub struct ShellStdin {
+  write: Box<dyn ShellWrite>,
+  closed: bool,
+}
+
+impl AsyncWrite for ShellStdin {
+  fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+    if self.closed {
+      return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+    }
+
+    ready!(Pin::new(&mut self.write).poll_ready(cx))?;
+    Pin::new(&mut self.write).start_send(ShellInput::Stdin(Bytes::copy_from_slice(buf)))?;
+    Poll::Ready(Ok(buf.len()))
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+    Poll::Ready(Ok(ready!(Pin::new(&mut self.write).poll_flush(cx))?))
+  }
+
+  fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+    if !self.closed {
+      ready!(Pin::new(&mut self.write).poll_ready(cx))?;
+      Pin::new(&mut self.write).start_send(ShellInput::CloseStdin)?;
+      self.closed = true;
+    }
+    self.poll_flush(cx)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::client::shell::ShellBuilder;
+  use crate::host::DeviceCriteria;
+  use crate::runtime::block_on;
+  use crate::testing::{device, FakeServer};
+  use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+  #[test]
+  fn demultiplex() {
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1));
+    server.on_shell("foo", b"out", b"err", 7);
+
+    block_on(async {
+      let shell = ShellBuilder::new()
+        .command(Some(vec!["foo".into()]))
+        .shell_protocol(true)
+        .connect(server.remote(), DeviceCriteria::Any)
+        .await
+        .unwrap();
+      let mut streams = ShellStreams::new(shell);
+
+      let mut stdout = Vec::new();
+      streams.stdout.read_to_end(&mut stdout).await.unwrap();
+      assert_eq!(b"out", &stdout[..]);
+
+      let mut stderr = Vec::new();
+      streams.stderr.read_to_end(&mut stderr).await.unwrap();
+      assert_eq!(b"err", &stderr[..]);
+
+      assert_eq!(7, streams.exit_code.await.unwrap());
+    });
+  }
+
+  #[test]
+  fn exit_code_with_dropped_output() {
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1));
+    server.on_shell("foo", b"out", b"err", 3);
+
+    block_on(async {
+      let shell = ShellBuilder::new()
+        .command(Some(vec!["foo".into()]))
+        .shell_protocol(true)
+        .connect(server.remote(), DeviceCriteria::Any)
+        .await
+        .unwrap();
+      let ShellStreams {
+        mut stdin,
+        stdout,
+        stderr,
+        exit_code,
+      } = ShellStreams::new(shell);
+      drop(stdout);
+      drop(stderr);
+
+      stdin.write_all(b"input").await.unwrap();
+      stdin.close().await.unwrap();
+      assert!(stdin.write_all(b"more").await.is_err());
+      assert_eq!(3, exit_code.await.unwrap());
+    });
+  }
+}
@@ -0,0 +1,298 @@
+//! Client for adb's file synchronization service (`sync:`).
+//!
+//! A [SyncClient] holds a single sync session, over which any number of requests can be made sequentially.
+
+use byteorder::{ByteOrder, LittleEndian};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, trace};
+
+use crate as adb;
+use crate::client::Remote;
+use crate::core::sync::*;
+use crate::core::Socket;
+use crate::host::{DeviceCriteria, TransportId};
+
+/// An entry in a directory listing returned by [SyncClient::list].
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirEntry {
+  pub name: String,
+  pub mode: u32,
+  pub size: u32,
+  pub mtime: u32,
+}
+
+/// A session with a device's file synchronization service.
+pub struct SyncClient {
+  channel: Box<dyn Socket>,
+  transport_id: TransportId,
+}
+
+impl SyncClient {
+  /// Opens a sync session on the device specified by the provided [DeviceCriteria].
+  pub async fn connect(remote: Remote, device_criteria: DeviceCriteria) -> adb::Result<SyncClient> {
+    let (transport_id, channel) = remote.open_device_channel(device_criteria, "sync:").await?;
+    Ok(SyncClient { channel, transport_id })
+  }
+
+  /// Returns the id of the transport of the device that the session is connected to.
+  pub fn transport_id(&self) -> TransportId {
+    self.transport_id
+  }
+
+  async fn send_request(&mut self, id: SyncId, path: &str) -> adb::Result<()> {
+    if path.len() > SYNC_PATH_MAX {
+      return Err(adb::Error::InvalidArgument(format!("path too long: '{}'", path)));
+    }
+
+    trace!(id = ?id, path, "sending sync request");
+    let mut buf = encode_header(id, path.len() as u32).to_vec();
+    buf.extend_from_slice(path.as_bytes());
+    self.channel.write_all(&buf).await?;
+    Ok(())
+  }
+
+  async fn read_id(&mut self) -> adb::Result<SyncId> {
+    let mut id = [0u8; 4];
+    self.channel.read_exact(&mut id).await?;
+    SyncId::from_bytes(&id)
+      .ok_or_else(|| adb::Error::UnexpectedData(format!("unknown sync id '{}'", String::from_utf8_lossy(&id))))
+  }
+
+  async fn read_u32(&mut self) -> adb::Result<u32> {
+    let mut buf = [0u8; 4];
+    self.channel.read_exact(&mut buf).await?;
+    Ok(LittleEndian::read_u32(&buf))
+  }
+
+  /// Reads the message of a FAIL response, after its id.
+  async fn read_fail(&mut self) -> adb::Error {
+    let message = async {
+      let length = self.read_u32().await? as usize;
+      let mut message = vec![0u8; length];
+      self.channel.read_exact(&mut message).await?;
+      Ok(String::from_utf8_lossy(&message).into_owned())
+    };
+
+    match message.await {
+      Ok(message) => {
+        debug!(message = %message, "received sync FAIL");
+        adb::Error::ServiceError(message)
+      }
+      Err(err) => err,
+    }
+  }
+
+  async fn expect_id(&mut self, expected: SyncId) -> adb::Result<()> {
+    match self.read_id().await? {
+      id if id == expected => Ok(()),
+      SyncId::Fail => Err(self.read_fail().await),
+      id => Err(unexpected(expected, id)),
+    }
+  }
+
+  /// Gets the metadata of a file, with the v1 protocol.
+  ///
+  /// The v1 protocol can't report errors: a missing file results in a zeroed [SyncStatV1].
+  pub async fn stat(&mut self, path: &str) -> adb::Result<SyncStatV1> {
+    self.send_request(SyncId::Stat, path).await?;
+    self.expect_id(SyncId::Stat).await?;
+    let mut buf = [0u8; SyncStatV1::ENCODED_SIZE];
+    self.channel.read_exact(&mut buf).await?;
+    Ok(SyncStatV1::decode(&buf))
+  }
+
+  /// Gets the metadata of a file, following symlinks, with the v2 protocol.
+  ///
+  /// This requires the device to support the `stat_v2` feature.
+  pub async fn stat_v2(&mut self, path: &str) -> adb::Result<SyncStatV2> {
+    self.stat_v2_impl(SyncId::Stat2, path).await
+  }
+
+  /// Gets the metadata of a file, without following symlinks, with the v2 protocol.
+  ///
+  /// This requires the device to support the `stat_v2` feature.
+  pub async fn lstat_v2(&mut self, path: &str) -> adb::Result<SyncStatV2> {
+    self.stat_v2_impl(SyncId::Lstat2, path).await
+  }
+
+  async fn stat_v2_impl(&mut self, id: SyncId, path: &str) -> adb::Result<SyncStatV2> {
+    self.send_request(id, path).await?;
+    self.expect_id(id).await?;
+    let mut buf = [0u8; SyncStatV2::ENCODED_SIZE];
+    self.channel.read_exact(&mut buf).await?;
+    let stat = SyncStatV2::decode(&buf);
+    if stat.error != 0 {
+      return Err(std::io::Error::from_raw_os_error(stat.error as i32).into());
+    }
+    Ok(stat)
+  }
+
+  /// Lists the contents of a directory, with the v1 protocol.
+  ///
+  /// As with adbd, a directory that can't be opened results in an empty listing.
+  pub async fn list(&mut self, path: &str) -> adb::Result<Vec<DirEntry>> {
+    self.send_request(SyncId::List, path).await?;
+
+    let mut entries = Vec::new();
+    loop {
+      let id = self.read_id().await?;
+      let mut buf = [0u8; SyncStatV1::ENCODED_SIZE + 4];
+      match id {
+        SyncId::Dent | SyncId::Done => self.channel.read_exact(&mut buf).await?,
+        SyncId::Fail => return Err(self.read_fail().await),
+        id => return Err(unexpected(SyncId::Dent, id)),
+      }
+
+      if id == SyncId::Done {
+        return Ok(entries);
+      }
+
+      let stat = SyncStatV1::decode(&buf[..SyncStatV1::ENCODED_SIZE]);
+      let name_len = LittleEndian::read_u32(&buf[SyncStatV1::ENCODED_SIZE..]) as usize;
+      if name_len > SYNC_PATH_MAX {
+        return Err(adb::Error::UnexpectedData(format!(
+          "oversized directory entry: {}",
+          name_len
+        )));
+      }
+
+      let mut name = vec![0u8; name_len];
+      self.channel.read_exact(&mut name).await?;
+      entries.push(DirEntry {
+        name: String::from_utf8_lossy(&name).into_owned(),
+        mode: stat.mode,
+        size: stat.size,
+        mtime: stat.mtime,
+      });
+    }
+  }
+
+  /// Sends the contents of `source` to a file on the device, creating it with the given mode and mtime.
+  ///
+  /// Returns the number of bytes sent.
+  pub async fn push(
+    &mut self,
+    mut source: impl AsyncRead + Unpin,
+    path: &str,
+    mode: u32,
+    mtime: u32,
+  ) -> adb::Result<u64> {
+    self.send_request(SyncId::Send, &format!("{},{}", path, mode)).await?;
+
+    let mut buf = vec![0u8; 8 + SYNC_DATA_MAX];
+    let mut total_bytes = 0u64;
+    loop {
+      let len = source.read(&mut buf[8..]).await?;
+      if len == 0 {
+        break;
+      }
+
+      buf[..8].copy_from_slice(&encode_header(SyncId::Data, len as u32));
+      self.channel.write_all(&buf[..8 + len]).await?;
+      total_bytes += len as u64;
+    }
+
+    self.channel.write_all(&encode_header(SyncId::Done, mtime)).await?;
+    self.expect_id(SyncId::Okay).await?;
+    self.read_u32().await?;
+    debug!(path, bytes = total_bytes, "pushed file");
+    Ok(total_bytes)
+  }
+
+  /// Receives the contents of a file on the device, writing them to `destination`.
+  ///
+  /// Returns the number of bytes received.
+  pub async fn pull(&mut self, path: &str, mut destination: impl AsyncWrite + Unpin) -> adb::Result<u64> {
+    self.send_request(SyncId::Recv, path).await?;
+
+    let mut buf = vec![0u8; SYNC_DATA_MAX];
+    let mut total_bytes = 0u64;
+    loop {
+      match self.read_id().await? {
+        SyncId::Data => {}
+        SyncId::Done => {
+          self.read_u32().await?;
+          break;
+        }
+        SyncId::Fail => return Err(self.read_fail().await),
+        id => return Err(unexpected(SyncId::Data, id)),
+      }
+
+      let len = self.read_u32().await? as usize;
+      if len > SYNC_DATA_MAX {
+        return Err(adb::Error::UnexpectedData(format!("oversized DATA packet: {}", len)));
+      }
+
+      self.channel.read_exact(&mut buf[..len]).await?;
+      destination.write_all(&buf[..len]).await?;
+      total_bytes += len as u64;
+    }
+
+    destination.flush().await?;
+    debug!(path, bytes = total_bytes, "pulled file");
+    Ok(total_bytes)
+  }
+
+  /// Ends the session.
+  pub async fn quit(mut self) -> adb::Result<()> {
+    self.send_request(SyncId::Quit, "").await?;
+    self.channel.close().await?;
+    Ok(())
+  }
+}
+
+fn unexpected(expected: SyncId, actual: SyncId) -> adb::Error {
+  adb::Error::UnexpectedData(format!("expected {:?}, got {:?}", expected, actual))
+}
+
+#[cfg(all(test, feature = "daemon"))]
+mod test {
+  use super::*;
+  use crate::runtime::block_on;
+  use crate::testing::{device, FakeServer};
+
+  #[test]
+  fn push_pull_list() {
+    let root = tempfile::tempdir().unwrap();
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1)).on_sync(root.path());
+
+    block_on(async {
+      let mut client = SyncClient::connect(server.remote(), DeviceCriteria::Any).await.unwrap();
+      assert_eq!(TransportId(1), client.transport_id());
+
+      let sent = client
+        .push(&b"hello, world"[..], "/dir/file", 0o100644, 1_234_567_890)
+        .await
+        .unwrap();
+      assert_eq!(12, sent);
+
+      let stat = client.stat("/dir/file").await.unwrap();
+      assert_eq!(12, stat.size);
+      assert_eq!(1_234_567_890, stat.mtime);
+      assert_eq!(0, client.stat("/missing").await.unwrap().mode);
+
+      let stat = client.lstat_v2("/dir/file").await.unwrap();
+      assert_eq!(12, stat.size);
+      assert!(client.stat_v2("/missing").await.is_err());
+
+      let entries = client.list("/dir").await.unwrap();
+      assert_eq!(1, entries.len());
+      assert_eq!("file", entries[0].name);
+      assert_eq!(12, entries[0].size);
+
+      let mut data = Vec::new();
+      client.pull("/dir/file", &mut data).await.unwrap();
+      assert_eq!(b"hello, world", &data[..]);
+
+      match client.pull("/missing", Vec::new()).await {
+        Err(adb::Error::ServiceError(_)) => {}
+        other => panic!("unexpected result: {:?}", other),
+      }
+
+      client.quit().await.unwrap();
+    });
+
+    server.assert_device_services(&["sync:"]);
+  }
+}
@@ -1,12 +1,42 @@
+use crate::core::SocketSpec;
+use crate::util::ConsumePrefix;
+
 /// Error type returned by library functions.
 #[derive(Debug)]
 pub enum Error {
   /// Received unexpected data of some sort.
   UnexpectedData(String),
 
-  /// Failed to connect to a service with a reason.
+  /// Failed to connect to a service with a reason that isn't covered by a more specific variant.
   ServiceError(String),
 
+  /// No devices are connected.
+  NoDevices,
+
+  /// More than one device matched the device selection criteria.
+  MultipleDevices,
+
+  /// No device with the requested serial is connected.
+  DeviceNotFound(String),
+
+  /// The device hasn't authorized this host.
+  DeviceUnauthorized,
+
+  /// The device is offline.
+  DeviceOffline,
+
+  /// The device is still in the process of authorizing or connecting (e.g. "authorizing").
+  DeviceNotReady(String),
+
+  /// The service was closed by the other end before a response was received.
+  Closed,
+
+  /// An operation didn't complete within its timeout.
+  TimedOut(String),
+
+  /// An argument passed to the library was invalid.
+  InvalidArgument(String),
+
   /// Attempted an operation that should be supported, but isn't implemented yet.
   UnimplementedOperation(String),
 
@@ -19,15 +49,330 @@ pub enum Error {
   /// Attempted to use a SocketSpec that is unavailable on the current platform.
   SocketSpecUnsupportedType,
 
+  /// A command run on a device exited with a non-zero exit code.
+  CommandFailed { exit_code: u8, stderr: String },
+
+  /// The package manager reported a failure, like `Failure [INSTALL_FAILED_ALREADY_EXISTS: ...]`.
+  ///
+  /// The code is `None` if the output didn't contain one, in which case the message is the whole output.
+  PackageManagerFailed { code: Option<String>, message: String },
+
   /// An I/O error occurred.
+  ///
+  /// Like [Error::Context], the underlying error isn't reported as a source, since it's this error's message.
   IoError(std::io::Error),
+
+  /// An error that occurred while talking to a specific service, server, or device.
+  ///
+  /// The underlying error is available through [Error::root], rather than [std::error::Error::source], since it's
+  /// already included in this error's message.
+  Context { context: ErrorContext, source: Box<Error> },
+}
+
+/// Information about where an [Error] occurred.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ErrorContext {
+  /// The service that was being used.
+  pub service: Option<String>,
+
+  /// The location of the server that was being talked to.
+  pub socket_spec: Option<SocketSpec>,
+
+  /// The id of the transport of the device that was being talked to.
+  pub transport_id: Option<u64>,
+}
+
+impl ErrorContext {
+  fn is_empty(&self) -> bool {
+    self.service.is_none() && self.socket_spec.is_none() && self.transport_id.is_none()
+  }
+
+  /// Fills in the fields that aren't set with the values from another context.
+  fn merge(&mut self, other: ErrorContext) {
+    if self.service.is_none() {
+      self.service = other.service;
+    }
+
+    if self.socket_spec.is_none() {
+      self.socket_spec = other.socket_spec;
+    }
+
+    if self.transport_id.is_none() {
+      self.transport_id = other.transport_id;
+    }
+  }
 }
 
+impl std::fmt::Display for ErrorContext {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let mut parts = Vec::new();
+    if let Some(service) = &self.service {
+      parts.push(format!("service '{}'", service));
+    }
+
+    if let Some(transport_id) = self.transport_id {
+      parts.push(format!("transport {}", transport_id));
+    }
+
+    if let Some(socket_spec) = &self.socket_spec {
+      parts.push(format!("server {}", socket_spec));
+    }
+
+    write!(f, "{}", parts.join(", "))
+  }
+}
+
+impl Error {
+  /// Classifies the message of a FAIL response from an adb server or device.
+  pub fn from_service_failure(message: impl Into<String>) -> Error {
+    let message = message.into();
+    match message.as_str() {
+      "no devices/emulators found" | "no devices found" | "no emulators found" => return Error::NoDevices,
+      "more than one device/emulator" | "more than one device" | "more than one emulator" => {
+        return Error::MultipleDevices
+      }
+      "closed" => return Error::Closed,
+      _ => {}
+    }
+
+    if message.starts_with("device unauthorized") {
+      Error::DeviceUnauthorized
+    } else if message.starts_with("device offline") {
+      Error::DeviceOffline
+    } else if let Some(state) = message.consume_prefix("device still ") {
+      Error::DeviceNotReady(state.trim().into())
+    } else if let Some(serial) = message
+      .consume_prefix("device '")
+      .and_then(|tail| tail.rfind("' not found").map(|idx| &tail[..idx]))
+    {
+      Error::DeviceNotFound(serial.into())
+    } else {
+      Error::ServiceError(message)
+    }
+  }
+
+  /// Returns the underlying error, without any [ErrorContext].
+  ///
+  /// This is the error that should be matched against to determine what went wrong.
+  pub fn root(&self) -> &Error {
+    match self {
+      Error::Context { source, .. } => source.root(),
+      err => err,
+    }
+  }
+
+  /// Returns the context in which the error occurred, if any.
+  pub fn context(&self) -> Option<&ErrorContext> {
+    match self {
+      Error::Context { context, .. } => Some(context),
+      _ => None,
+    }
+  }
+
+  /// Returns whether the error is likely to go away if the operation is retried.
+  ///
+  /// This is the case for devices that are offline or still connecting, and for adb servers that are still starting
+  /// up (connection refused or reset).
+  pub fn is_transient(&self) -> bool {
+    match self.root() {
+      Error::DeviceOffline | Error::DeviceNotReady(_) => true,
+      Error::IoError(err) => matches!(
+        err.kind(),
+        std::io::ErrorKind::ConnectionRefused
+          | std::io::ErrorKind::ConnectionReset
+          | std::io::ErrorKind::ConnectionAborted
+      ),
+      _ => false,
+    }
+  }
+
+  /// Attaches context to an error.
+  ///
+  /// If the error already has context, the fields that are already set take precedence, since they are more specific.
+  pub fn with_context(self, context: ErrorContext) -> Error {
+    if context.is_empty() {
+      return self;
+    }
+
+    match self {
+      Error::Context {
+        context: mut existing,
+        source,
+      } => {
+        existing.merge(context);
+        Error::Context {
+          context: existing,
+          source,
+        }
+      }
+
+      err => Error::Context {
+        context,
+        source: Box::new(err),
+      },
+    }
+  }
+
+  /// Attaches the name of the service being used to an error.
+  pub fn with_service(self, service: impl Into<String>) -> Error {
+    self.with_context(ErrorContext {
+      service: Some(service.into()),
+      ..Default::default()
+    })
+  }
+
+  /// Attaches the location of the server being talked to to an error.
+  pub fn with_socket_spec(self, socket_spec: SocketSpec) -> Error {
+    self.with_context(ErrorContext {
+      socket_spec: Some(socket_spec),
+      ..Default::default()
+    })
+  }
+
+  /// Attaches the transport id of the device being talked to to an error.
+  pub fn with_transport_id(self, transport_id: u64) -> Error {
+    self.with_context(ErrorContext {
+      transport_id: Some(transport_id),
+      ..Default::default()
+    })
+  }
+}
+
+impl std::fmt::Display for Error {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::UnexpectedData(msg) => write!(f, "unexpected data: {}", msg),
+      Error::ServiceError(msg) => write!(f, "{}", msg),
+      Error::NoDevices => write!(f, "no devices/emulators found"),
+      Error::MultipleDevices => write!(f, "more than one device/emulator"),
+      Error::DeviceNotFound(serial) => write!(f, "device '{}' not found", serial),
+      Error::DeviceUnauthorized => write!(f, "device unauthorized"),
+      Error::DeviceOffline => write!(f, "device offline"),
+      Error::DeviceNotReady(state) => write!(f, "device still {}", state),
+      Error::Closed => write!(f, "closed"),
+      Error::TimedOut(msg) => write!(f, "timed out: {}", msg),
+      Error::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+      Error::UnimplementedOperation(msg) => write!(f, "unimplemented: {}", msg),
+      Error::SocketSpecInvalid => write!(f, "invalid socket specification"),
+      Error::SocketSpecMissingHost => write!(f, "socket specification is missing a host"),
+      Error::SocketSpecUnsupportedType => write!(f, "socket specification type is unsupported on this platform"),
+      Error::CommandFailed { exit_code, stderr } => {
+        write!(f, "command exited with status {}", exit_code)?;
+        match stderr.trim() {
+          "" => Ok(()),
+          stderr => write!(f, ": {}", stderr),
+        }
+      }
+      Error::PackageManagerFailed { code, message } => {
+        write!(f, "package manager failed")?;
+        match (code, message.as_str()) {
+          (Some(code), "") => write!(f, ": {}", code),
+          (Some(code), message) => write!(f, ": {}: {}", code, message),
+          (None, message) => write!(f, ": {}", message),
+        }
+      }
+      Error::IoError(err) => write!(f, "{}", err),
+      Error::Context { context, source } => write!(f, "{} ({})", source, context),
+    }
+  }
+}
+
+// Every variant that wraps another error already includes its message in ours, so none of them report it as a source:
+// reporters that walk the chain of sources would print it twice.
+impl std::error::Error for Error {}
+
 impl From<std::io::Error> for Error {
   fn from(err: std::io::Error) -> Error {
     Error::IoError(err)
   }
 }
 
+impl From<Error> for std::io::Error {
+  fn from(err: Error) -> std::io::Error {
+    match err {
+      Error::IoError(err) => err,
+      err => std::io::Error::other(err),
+    }
+  }
+}
+
 /// `Result` typedef using the library's Error type.
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn classify_service_failures() {
+    match Error::from_service_failure("no devices/emulators found") {
+      Error::NoDevices => {}
+      err => panic!("unexpected error: {:?}", err),
+    }
+
+    match Error::from_service_failure("more than one device/emulator") {
+      Error::MultipleDevices => {}
+      err => panic!("unexpected error: {:?}", err),
+    }
+
+    match Error::from_service_failure("device unauthorized.\nThis adb server's $ADB_VENDOR_KEYS is not set") {
+      Error::DeviceUnauthorized => {}
+      err => panic!("unexpected error: {:?}", err),
+    }
+
+    match Error::from_service_failure("device offline") {
+      Error::DeviceOffline => {}
+      err => panic!("unexpected error: {:?}", err),
+    }
+
+    match Error::from_service_failure("device still authorizing") {
+      Error::DeviceNotReady(state) => assert_eq!("authorizing", state),
+      err => panic!("unexpected error: {:?}", err),
+    }
+
+    match Error::from_service_failure("device 'emulator-5554' not found") {
+      Error::DeviceNotFound(serial) => assert_eq!("emulator-5554", serial),
+      err => panic!("unexpected error: {:?}", err),
+    }
+
+    match Error::from_service_failure("closed") {
+      Error::Closed => {}
+      err => panic!("unexpected error: {:?}", err),
+    }
+
+    match Error::from_service_failure("unknown host service") {
+      Error::ServiceError(msg) => assert_eq!("unknown host service", msg),
+      err => panic!("unexpected error: {:?}", err),
+    }
+  }
+
+  #[test]
+  fn context() {
+    let err = Error::DeviceOffline
+      .with_transport_id(3)
+      .with_service("shell:ls")
+      .with_service("host:tport:any");
+
+    match err.root() {
+      Error::DeviceOffline => {}
+      err => panic!("unexpected error: {:?}", err),
+    }
+
+    let context = err.context().unwrap();
+    assert_eq!(Some("shell:ls".to_string()), context.service);
+    assert_eq!(Some(3), context.transport_id);
+    assert_eq!("device offline (service 'shell:ls', transport 3)", err.to_string());
+    assert!(std::error::Error::source(&err).is_none());
+
+    let err = Error::IoError(std::io::ErrorKind::BrokenPipe.into());
+    assert!(std::error::Error::source(&err).is_none());
+  }
+
+  #[test]
+  fn transient() {
+    assert!(Error::DeviceOffline.with_service("shell:").is_transient());
+    assert!(Error::IoError(std::io::ErrorKind::ConnectionRefused.into()).is_transient());
+    assert!(!Error::DeviceUnauthorized.is_transient());
+    assert!(!Error::TimedOut("connect".into()).is_transient());
+  }
+}
@@ -5,3 +5,5 @@ pub use error::*;
 
 mod socketspec;
 pub use socketspec::*;
+
+pub mod sync;
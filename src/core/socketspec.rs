@@ -1,11 +1,12 @@
 use futures::io::{AsyncRead, AsyncWrite};
-use romio::TcpStream;
+use tracing::debug;
 
 use std::convert::TryFrom;
-use std::net::ToSocketAddrs;
-use std::path::Path;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Instant;
 
 use crate as adb;
+use crate::runtime::{self, RawListener};
 use crate::util::ConsumePrefix;
 
 /// An implementation of adb's socket address specifiers.
@@ -28,17 +29,37 @@ pub enum SocketSpec {
   Vsock { host: Option<String>, port: u32 },
 }
 
-#[cfg(not(windows))]
-async fn connect_unix_stream(path: impl AsRef<Path>) -> adb::Result<Box<Socket>> {
-  use romio::uds::UnixStream;
-  let stream = UnixStream::connect(path).await?;
-  let stream: Box<Socket> = Box::new(stream);
-  Ok(stream)
+#[cfg(unix)]
+fn unix_addr(spec: &SocketSpec) -> adb::Result<std::os::unix::net::SocketAddr> {
+  match spec {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    SocketSpec::UnixAbstract { path } => {
+      #[cfg(target_os = "android")]
+      use std::os::android::net::SocketAddrExt;
+      #[cfg(target_os = "linux")]
+      use std::os::linux::net::SocketAddrExt;
+      Ok(std::os::unix::net::SocketAddr::from_abstract_name(path)?)
+    }
+
+    SocketSpec::UnixFilesystem { path } => Ok(std::os::unix::net::SocketAddr::from_pathname(path)?),
+    _ => Err(adb::Error::SocketSpecUnsupportedType),
+  }
 }
 
-#[cfg(windows)]
-async fn connect_unix_stream(_path: impl AsRef<Path>) -> adb::Result<Box<Socket>> {
-  Err(adb::Error::SocketSpecUnsupportedType)
+/// Resolves the address of a TCP [SocketSpec], defaulting to localhost if there's no host.
+fn tcp_addr(host: Option<&str>, port: u16) -> adb::Result<SocketAddr> {
+  // IPv6 addresses are enclosed in brackets in socket specs.
+  let host = host
+    .unwrap_or("127.0.0.1")
+    .trim_start_matches('[')
+    .trim_end_matches(']');
+  (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+    std::io::Error::new(
+      std::io::ErrorKind::NotFound,
+      format!("failed to resolve host '{}'", host),
+    )
+    .into()
+  })
 }
 
 impl SocketSpec {
@@ -54,7 +75,7 @@ impl SocketSpec {
 
   /// Constructs a Unix domain socket [SocketSpec].
   pub fn unix_filesystem(path: impl Into<String>) -> SocketSpec {
-    SocketSpec::UnixAbstract { path: path.into() }
+    SocketSpec::UnixFilesystem { path: path.into() }
   }
 
   /// Constructs a vsock [SocketSpec].
@@ -68,27 +89,91 @@ impl SocketSpec {
   ///   - network failure
   ///   - attempt to connect to a `Tcp` or `Vsock` [SocketSpec] with no host
   ///   - lack of support (e.g. attempting to use Unix domain sockets on Windows)
-  pub async fn connect(&self) -> adb::Result<Box<Socket>> {
+  ///
+  /// With the tokio backend, this must be called from within a tokio runtime.
+  pub async fn connect(&self) -> adb::Result<Box<dyn Socket>> {
+    let start = Instant::now();
+    let result = self
+      .connect_impl()
+      .await
+      .map_err(|err| err.with_socket_spec(self.clone()));
+    let latency_us = start.elapsed().as_micros() as u64;
+    match &result {
+      Ok(_) => debug!(socket_spec = %self, latency_us, "connected"),
+      Err(err) => debug!(socket_spec = %self, latency_us, error = ?err, "failed to connect"),
+    }
+    result
+  }
+
+  async fn connect_impl(&self) -> adb::Result<Box<dyn Socket>> {
     match self {
       SocketSpec::Tcp { host, port } => {
-        let host = host.as_ref().ok_or(adb::Error::SocketSpecMissingHost)?;
-        let addr = (host.as_str(), *port)
-          .to_socket_addrs()?
-          .next()
-          .expect("to_socket_addrs empty");
-        let stream = TcpStream::connect(&addr).await?;
-        let stream: Box<Socket> = Box::new(stream);
-        Ok(stream)
-      }
+        let host = host.clone().ok_or(adb::Error::SocketSpecMissingHost)?;
+        let port = *port;
 
-      SocketSpec::UnixAbstract { path } => connect_unix_stream(format!("\0{}", path)).await,
-      SocketSpec::UnixFilesystem { path } => connect_unix_stream(path).await,
+        // Resolving a hostname blocks, so don't do it on the runtime's threads.
+        let addr = runtime::spawn_blocking(move || tcp_addr(Some(&host), port)).await?;
+        Ok(runtime::connect_tcp(addr).await?)
+      }
 
-      SocketSpec::Vsock { .. } => {
-        unimplemented!("SocketSpec::connect unimplemented for Vsock");
+      #[cfg(unix)]
+      SocketSpec::UnixAbstract { .. } | SocketSpec::UnixFilesystem { .. } => {
+        Ok(runtime::connect_unix(&unix_addr(self)?)?)
       }
+
+      _ => Err(adb::Error::SocketSpecUnsupportedType),
     }
   }
+
+  /// Listens on the address described by the [SocketSpec].
+  ///
+  /// TCP [SocketSpec]s without a host listen on localhost. Use port 0 to listen on an ephemeral port, and
+  /// [Listener::local_socket_spec] to find out which port was picked.
+  ///
+  /// With the tokio backend, this must be called from within a tokio runtime.
+  pub fn listen(&self) -> adb::Result<Listener> {
+    let result = match self {
+      SocketSpec::Tcp { host, port } => {
+        let addr = tcp_addr(host.as_ref().map(String::as_str), *port)?;
+        let listener = std::net::TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let local_socket_spec = SocketSpec::tcp(host.clone(), local_addr.port());
+        RawListener::tcp(listener).map(|raw| Listener { raw, local_socket_spec })
+      }
+
+      #[cfg(unix)]
+      SocketSpec::UnixAbstract { .. } | SocketSpec::UnixFilesystem { .. } => {
+        let listener = std::os::unix::net::UnixListener::bind_addr(&unix_addr(self)?)?;
+        RawListener::unix(listener).map(|raw| Listener {
+          raw,
+          local_socket_spec: self.clone(),
+        })
+      }
+
+      _ => return Err(adb::Error::SocketSpecUnsupportedType.with_socket_spec(self.clone())),
+    };
+
+    debug!(socket_spec = %self, "listening");
+    result.map_err(|err| adb::Error::from(err).with_socket_spec(self.clone()))
+  }
+}
+
+/// A socket listening for connections, created by [SocketSpec::listen].
+pub struct Listener {
+  raw: RawListener,
+  local_socket_spec: SocketSpec,
+}
+
+impl Listener {
+  /// Waits for and accepts a connection.
+  pub async fn accept(&self) -> adb::Result<Box<dyn Socket>> {
+    Ok(self.raw.accept().await?)
+  }
+
+  /// Returns the [SocketSpec] that the listener is bound to.
+  pub fn local_socket_spec(&self) -> &SocketSpec {
+    &self.local_socket_spec
+  }
 }
 
 impl std::fmt::Display for SocketSpec {
@@ -103,7 +188,7 @@ impl std::fmt::Display for SocketSpec {
       }
 
       SocketSpec::UnixAbstract { path } => write!(fmt, "localabstract:{}", path),
-      SocketSpec::UnixFilesystem { path } => write!(fmt, "localabstract:{}", path),
+      SocketSpec::UnixFilesystem { path } => write!(fmt, "localfilesystem:{}", path),
 
       SocketSpec::Vsock { host, port } => {
         if let Some(h) = host {
@@ -125,10 +210,10 @@ impl TryFrom<&str> for SocketSpec {
       } else {
         let (addr, tail) = if tail.starts_with('[') {
           // IPv6 bracket-enclosed address.
-          let close = tail.find(']').ok_or_else(|| adb::Error::SocketSpecInvalid)?;
+          let close = tail.find(']').ok_or(adb::Error::SocketSpecInvalid)?;
           tail.split_at(close + 1)
         } else {
-          let colon = tail.find(':').ok_or_else(|| adb::Error::SocketSpecInvalid)?;
+          let colon = tail.find(':').ok_or(adb::Error::SocketSpecInvalid)?;
           tail.split_at(colon)
         };
 
@@ -208,4 +293,29 @@ mod test {
     assert_eq!(None, SocketSpec::from_str("tcp:::1:-1").ok());
     assert_eq!(None, SocketSpec::from_str("tcp:::1:1234").ok());
   }
+
+  #[test]
+  fn listen_and_connect() {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    crate::runtime::block_on(async {
+      let listener = SocketSpec::tcp(Some("127.0.0.1".into()), 0).listen().unwrap();
+      let spec = listener.local_socket_spec().clone();
+      assert_ne!(SocketSpec::tcp(Some("127.0.0.1".into()), 0), spec);
+
+      let server = crate::runtime::spawn(async move {
+        let mut socket = listener.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        socket.read_exact(&mut buf).await.unwrap();
+        socket.write_all(&buf).await.unwrap();
+      });
+
+      let mut socket = spec.connect().await.unwrap();
+      socket.write_all(b"ping").await.unwrap();
+      let mut buf = [0u8; 4];
+      socket.read_exact(&mut buf).await.unwrap();
+      assert_eq!(b"ping", &buf);
+      server.await;
+    });
+  }
 }
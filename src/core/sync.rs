@@ -0,0 +1,253 @@
+//! Wire definitions for adb's file synchronization protocol (the `sync:` service).
+//!
+//! Every request consists of a 4-byte id, a little-endian u32 length, and a payload of that length (usually a path).
+//! Responses are id-tagged structures of fixed size, optionally followed by a payload.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Maximum size of the payload of a single `DATA` packet.
+pub const SYNC_DATA_MAX: usize = 64 * 1024;
+
+/// Maximum length of a path in a sync request.
+pub const SYNC_PATH_MAX: usize = 1024;
+
+/// Identifiers for sync requests and responses.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SyncId {
+  /// `stat` a path (v1 response format).
+  Stat,
+
+  /// `stat` a path (v2 response format).
+  Stat2,
+
+  /// `lstat` a path (v2 response format).
+  Lstat2,
+
+  /// List a directory (v1 response format).
+  List,
+
+  /// List a directory (v2 response format).
+  List2,
+
+  /// Send a file to the device.
+  Send,
+
+  /// Receive a file from the device.
+  Recv,
+
+  /// End the sync session.
+  Quit,
+
+  /// A v1 directory entry.
+  Dent,
+
+  /// A v2 directory entry.
+  Dent2,
+
+  /// A chunk of file data.
+  Data,
+
+  /// End of a listing or file transfer.
+  Done,
+
+  /// Success.
+  Okay,
+
+  /// Failure, followed by an error message.
+  Fail,
+}
+
+impl SyncId {
+  /// Returns the on-the-wire representation of the id.
+  pub fn to_bytes(self) -> [u8; 4] {
+    *match self {
+      SyncId::Stat => b"STAT",
+      SyncId::Stat2 => b"STA2",
+      SyncId::Lstat2 => b"LST2",
+      SyncId::List => b"LIST",
+      SyncId::List2 => b"LIS2",
+      SyncId::Send => b"SEND",
+      SyncId::Recv => b"RECV",
+      SyncId::Quit => b"QUIT",
+      SyncId::Dent => b"DENT",
+      SyncId::Dent2 => b"DNT2",
+      SyncId::Data => b"DATA",
+      SyncId::Done => b"DONE",
+      SyncId::Okay => b"OKAY",
+      SyncId::Fail => b"FAIL",
+    }
+  }
+
+  /// Parses an id from its on-the-wire representation.
+  pub fn from_bytes(bytes: &[u8; 4]) -> Option<SyncId> {
+    match bytes {
+      b"STAT" => Some(SyncId::Stat),
+      b"STA2" => Some(SyncId::Stat2),
+      b"LST2" => Some(SyncId::Lstat2),
+      b"LIST" => Some(SyncId::List),
+      b"LIS2" => Some(SyncId::List2),
+      b"SEND" => Some(SyncId::Send),
+      b"RECV" => Some(SyncId::Recv),
+      b"QUIT" => Some(SyncId::Quit),
+      b"DENT" => Some(SyncId::Dent),
+      b"DNT2" => Some(SyncId::Dent2),
+      b"DATA" => Some(SyncId::Data),
+      b"DONE" => Some(SyncId::Done),
+      b"OKAY" => Some(SyncId::Okay),
+      b"FAIL" => Some(SyncId::Fail),
+      _ => None,
+    }
+  }
+}
+
+/// Encodes a packet header: an id followed by a little-endian u32.
+pub fn encode_header(id: SyncId, value: u32) -> [u8; 8] {
+  let mut buf = [0u8; 8];
+  buf[..4].copy_from_slice(&id.to_bytes());
+  LittleEndian::write_u32(&mut buf[4..], value);
+  buf
+}
+
+/// File metadata as transmitted by the v1 protocol (`STAT` and `DENT`).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SyncStatV1 {
+  pub mode: u32,
+  pub size: u32,
+  pub mtime: u32,
+}
+
+impl SyncStatV1 {
+  /// Size of the encoded structure, excluding the id.
+  pub const ENCODED_SIZE: usize = 12;
+
+  /// Appends the encoded structure to a buffer.
+  pub fn encode(&self, buf: &mut Vec<u8>) {
+    let mut tmp = [0u8; Self::ENCODED_SIZE];
+    LittleEndian::write_u32(&mut tmp[0..4], self.mode);
+    LittleEndian::write_u32(&mut tmp[4..8], self.size);
+    LittleEndian::write_u32(&mut tmp[8..12], self.mtime);
+    buf.extend_from_slice(&tmp);
+  }
+
+  /// Decodes the structure from a buffer of exactly [SyncStatV1::ENCODED_SIZE] bytes.
+  pub fn decode(buf: &[u8]) -> SyncStatV1 {
+    SyncStatV1 {
+      mode: LittleEndian::read_u32(&buf[0..4]),
+      size: LittleEndian::read_u32(&buf[4..8]),
+      mtime: LittleEndian::read_u32(&buf[8..12]),
+    }
+  }
+}
+
+/// File metadata as transmitted by the v2 protocol (`STA2`, `LST2` and `DNT2`).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SyncStatV2 {
+  /// errno value of a failed stat, or 0 on success.
+  pub error: u32,
+  pub dev: u64,
+  pub ino: u64,
+  pub mode: u32,
+  pub nlink: u32,
+  pub uid: u32,
+  pub gid: u32,
+  pub size: u64,
+  pub atime: i64,
+  pub mtime: i64,
+  pub ctime: i64,
+}
+
+impl SyncStatV2 {
+  /// Size of the encoded structure, excluding the id.
+  pub const ENCODED_SIZE: usize = 68;
+
+  /// Constructs a structure reporting a failed stat.
+  pub fn error(errno: u32) -> SyncStatV2 {
+    SyncStatV2 {
+      error: errno,
+      ..Default::default()
+    }
+  }
+
+  /// Appends the encoded structure to a buffer.
+  pub fn encode(&self, buf: &mut Vec<u8>) {
+    let mut tmp = [0u8; Self::ENCODED_SIZE];
+    LittleEndian::write_u32(&mut tmp[0..4], self.error);
+    LittleEndian::write_u64(&mut tmp[4..12], self.dev);
+    LittleEndian::write_u64(&mut tmp[12..20], self.ino);
+    LittleEndian::write_u32(&mut tmp[20..24], self.mode);
+    LittleEndian::write_u32(&mut tmp[24..28], self.nlink);
+    LittleEndian::write_u32(&mut tmp[28..32], self.uid);
+    LittleEndian::write_u32(&mut tmp[32..36], self.gid);
+    LittleEndian::write_u64(&mut tmp[36..44], self.size);
+    LittleEndian::write_i64(&mut tmp[44..52], self.atime);
+    LittleEndian::write_i64(&mut tmp[52..60], self.mtime);
+    LittleEndian::write_i64(&mut tmp[60..68], self.ctime);
+    buf.extend_from_slice(&tmp);
+  }
+
+  /// Decodes the structure from a buffer of exactly [SyncStatV2::ENCODED_SIZE] bytes.
+  pub fn decode(buf: &[u8]) -> SyncStatV2 {
+    SyncStatV2 {
+      error: LittleEndian::read_u32(&buf[0..4]),
+      dev: LittleEndian::read_u64(&buf[4..12]),
+      ino: LittleEndian::read_u64(&buf[12..20]),
+      mode: LittleEndian::read_u32(&buf[20..24]),
+      nlink: LittleEndian::read_u32(&buf[24..28]),
+      uid: LittleEndian::read_u32(&buf[28..32]),
+      gid: LittleEndian::read_u32(&buf[32..36]),
+      size: LittleEndian::read_u64(&buf[36..44]),
+      atime: LittleEndian::read_i64(&buf[44..52]),
+      mtime: LittleEndian::read_i64(&buf[52..60]),
+      ctime: LittleEndian::read_i64(&buf[60..68]),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn id_roundtrip() {
+    for id in &[
+      SyncId::Stat,
+      SyncId::Stat2,
+      SyncId::Lstat2,
+      SyncId::List,
+      SyncId::List2,
+      SyncId::Send,
+      SyncId::Recv,
+      SyncId::Quit,
+      SyncId::Dent,
+      SyncId::Dent2,
+      SyncId::Data,
+      SyncId::Done,
+      SyncId::Okay,
+      SyncId::Fail,
+    ] {
+      assert_eq!(Some(*id), SyncId::from_bytes(&id.to_bytes()));
+    }
+    assert_eq!(None, SyncId::from_bytes(b"NOPE"));
+  }
+
+  #[test]
+  fn stat_v2_roundtrip() {
+    let stat = SyncStatV2 {
+      error: 0,
+      dev: 1,
+      ino: 2,
+      mode: 0o100644,
+      nlink: 1,
+      uid: 2000,
+      gid: 2000,
+      size: 1 << 40,
+      atime: -1,
+      mtime: 1_500_000_000,
+      ctime: 1_500_000_001,
+    };
+    let mut buf = Vec::new();
+    stat.encode(&mut buf);
+    assert_eq!(SyncStatV2::ENCODED_SIZE, buf.len());
+    assert_eq!(stat, SyncStatV2::decode(&buf));
+  }
+}
@@ -0,0 +1,3 @@
+//! Types and functions for daemon (device-side) implementations.
+
+pub mod sync;
@@ -0,0 +1,740 @@
+//! Implementation of the `sync:` service, serving files from the local filesystem.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use byteorder::{ByteOrder, LittleEndian};
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, debug_span, trace, Instrument};
+
+use crate as adb;
+use crate::core::sync::{encode_header, SyncId, SyncStatV1, SyncStatV2, SYNC_DATA_MAX, SYNC_PATH_MAX};
+use crate::core::Socket;
+use crate::runtime;
+
+const S_IFMT: u32 = 0o170_000;
+const S_IFLNK: u32 = 0o120_000;
+
+/// Server for the `sync:` service.
+///
+/// By default, paths are interpreted relative to the filesystem root. A root directory can be configured with
+/// [SyncServer::root], in which case all paths are resolved inside of it, as if the server were chrooted into it.
+#[derive(Clone, Debug, Default)]
+pub struct SyncServer {
+  root: Option<PathBuf>,
+}
+
+/// Failure of a single sync request, reported to the client with a FAIL message.
+struct RequestError(String);
+
+impl RequestError {
+  fn io(operation: &str, err: std::io::Error) -> RequestError {
+    RequestError(format!("{} failed: {}", operation, err))
+  }
+}
+
+type RequestResult<T> = std::result::Result<T, RequestError>;
+
+impl SyncServer {
+  pub fn new() -> SyncServer {
+    SyncServer { root: None }
+  }
+
+  /// Confines all file accesses to a directory.
+  pub fn root(&mut self, root: Option<PathBuf>) -> &mut SyncServer {
+    self.root = root;
+    self
+  }
+
+  /// Serves sync requests on a socket until the client sends QUIT or disconnects.
+  ///
+  /// Failures of individual requests are reported to the client, and don't terminate the session. Protocol errors
+  /// and I/O errors on the socket are returned. Filesystem accesses run with [runtime::spawn_blocking], so they don't
+  /// block the runtime's other tasks.
+  pub async fn serve(&self, socket: &mut dyn Socket) -> adb::Result<()> {
+    let span = debug_span!("sync", root = ?self.root);
+    self.serve_impl(socket).instrument(span).await
+  }
+
+  async fn serve_impl(&self, socket: &mut dyn Socket) -> adb::Result<()> {
+    loop {
+      let mut header = [0u8; 8];
+      match socket.read_exact(&mut header).await {
+        Ok(()) => {}
+        Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+        Err(err) => return Err(err.into()),
+      }
+
+      let mut id_bytes = [0u8; 4];
+      id_bytes.copy_from_slice(&header[..4]);
+      let length = LittleEndian::read_u32(&header[4..]) as usize;
+
+      let id = match SyncId::from_bytes(&id_bytes) {
+        Some(id) => id,
+        None => {
+          let msg = format!("unknown sync command '{}'", String::from_utf8_lossy(&id_bytes));
+          write_fail(socket, &msg).await?;
+          return Err(adb::Error::UnexpectedData(msg));
+        }
+      };
+
+      if id == SyncId::Quit {
+        return Ok(());
+      }
+
+      if length > SYNC_PATH_MAX {
+        let msg = format!("path too long: {}", length);
+        write_fail(socket, &msg).await?;
+        return Err(adb::Error::UnexpectedData(msg));
+      }
+
+      let mut path = vec![0u8; length];
+      socket.read_exact(&mut path).await?;
+      let path = String::from_utf8(path)
+        .map_err(|_| adb::Error::UnexpectedData("sync request path is not valid UTF-8".into()))?;
+
+      debug!(request = ?id, path = %path, "received sync request");
+      match id {
+        SyncId::Stat => self.handle_stat_v1(socket, &path).await?,
+        SyncId::Stat2 => self.handle_stat_v2(socket, &path, true).await?,
+        SyncId::Lstat2 => self.handle_stat_v2(socket, &path, false).await?,
+        SyncId::List => self.handle_list(socket, &path, false).await?,
+        SyncId::List2 => self.handle_list(socket, &path, true).await?,
+        SyncId::Send => self.handle_send(socket, &path).await?,
+        SyncId::Recv => self.handle_recv(socket, &path).await?,
+        _ => {
+          let msg = format!("unexpected sync command '{}'", String::from_utf8_lossy(&id_bytes));
+          write_fail(socket, &msg).await?;
+          return Err(adb::Error::UnexpectedData(msg));
+        }
+      }
+    }
+  }
+
+  /// Maps a path requested by the client to a path on the local filesystem.
+  ///
+  /// When confined to a root, `..` can't ascend past the root, and paths that escape the root through a symlink are
+  /// rejected.
+  fn resolve(&self, path: &str, follow_final_symlink: bool) -> RequestResult<PathBuf> {
+    let root = match &self.root {
+      Some(root) => root,
+      None => return Ok(PathBuf::from(path)),
+    };
+
+    let mut resolved = root.clone();
+    let mut depth = 0;
+    for component in Path::new(path).components() {
+      match component {
+        Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        Component::ParentDir => {
+          if depth > 0 {
+            resolved.pop();
+            depth -= 1;
+          }
+        }
+        Component::Normal(name) => {
+          resolved.push(name);
+          depth += 1;
+        }
+      }
+    }
+
+    // Find the deepest existing ancestor, and make sure that it is still within the root once symlinks are resolved.
+    let canonical_root = root.canonicalize().map_err(|err| RequestError::io("root", err))?;
+    let mut existing = if follow_final_symlink || depth == 0 {
+      resolved.as_path()
+    } else {
+      resolved.parent().unwrap_or(root)
+    };
+
+    loop {
+      match existing.canonicalize() {
+        Ok(canonical) => {
+          if !canonical.starts_with(&canonical_root) {
+            return Err(RequestError(format!("path '{}' escapes the sync root", path)));
+          }
+          break;
+        }
+        Err(_) => match existing.parent() {
+          Some(parent) if existing != root.as_path() => existing = parent,
+          _ => break,
+        },
+      }
+    }
+
+    Ok(resolved)
+  }
+
+  /// Runs a filesystem operation on a path with [runtime::spawn_blocking].
+  async fn blocking<T: Send + 'static>(
+    &self,
+    path: &str,
+    f: impl FnOnce(&SyncServer, &str) -> T + Send + 'static,
+  ) -> T {
+    let server = self.clone();
+    let path = path.to_string();
+    runtime::spawn_blocking(move || f(&server, &path)).await
+  }
+
+  async fn handle_stat_v1(&self, socket: &mut dyn Socket, path: &str) -> adb::Result<()> {
+    // The v1 protocol has no way to report errors: failures are signalled with a zeroed response.
+    let stat = self
+      .blocking(path, |server, path| {
+        server
+          .resolve(path, true)
+          .ok()
+          .and_then(|path| fs::metadata(path).ok())
+          .map(|metadata| stat_v1(&metadata))
+          .unwrap_or_default()
+      })
+      .await;
+
+    let mut buf = SyncId::Stat.to_bytes().to_vec();
+    stat.encode(&mut buf);
+    socket.write_all(&buf).await?;
+    Ok(())
+  }
+
+  async fn handle_stat_v2(&self, socket: &mut dyn Socket, path: &str, follow_symlinks: bool) -> adb::Result<()> {
+    let id = if follow_symlinks { SyncId::Stat2 } else { SyncId::Lstat2 };
+    let stat = self
+      .blocking(path, move |server, path| match server.resolve(path, follow_symlinks) {
+        Ok(path) => {
+          let metadata = if follow_symlinks {
+            fs::metadata(path)
+          } else {
+            fs::symlink_metadata(path)
+          };
+          match metadata {
+            Ok(metadata) => stat_v2(&metadata),
+            Err(err) => SyncStatV2::error(errno(&err)),
+          }
+        }
+        Err(_) => SyncStatV2::error(EACCES),
+      })
+      .await;
+
+    let mut buf = id.to_bytes().to_vec();
+    stat.encode(&mut buf);
+    socket.write_all(&buf).await?;
+    Ok(())
+  }
+
+  async fn handle_list(&self, socket: &mut dyn Socket, path: &str, v2: bool) -> adb::Result<()> {
+    let entries = self
+      .blocking(path, move |server, path| list_dir(server, path, v2))
+      .await;
+    for entry in entries {
+      socket.write_all(&entry).await?;
+    }
+
+    // The terminating DONE has the same size as an entry, with all fields zeroed.
+    let mut buf = SyncId::Done.to_bytes().to_vec();
+    let zero_size = if v2 {
+      SyncStatV2::ENCODED_SIZE
+    } else {
+      SyncStatV1::ENCODED_SIZE
+    };
+    buf.resize(buf.len() + zero_size + 4, 0);
+    socket.write_all(&buf).await?;
+    Ok(())
+  }
+
+  async fn handle_send(&self, socket: &mut dyn Socket, spec: &str) -> adb::Result<()> {
+    // The path is followed by a comma and the file's mode, in decimal.
+    let (path, mode) = match spec.rfind(',') {
+      Some(idx) => (&spec[..idx], spec[idx + 1..].parse::<u32>().ok()),
+      None => (spec, None),
+    };
+
+    let mut receiver = match mode {
+      Some(mode) => {
+        self
+          .blocking(path, move |server, path| {
+            server
+              .resolve(path, false)
+              .and_then(|path| FileReceiver::new(path, mode))
+          })
+          .await
+      }
+      None => Err(RequestError(format!("invalid SEND request '{}'", spec))),
+    };
+
+    // Keep consuming data after a failure, so that the session stays in sync with the client.
+    let mut total_bytes = 0;
+    let mtime = loop {
+      let mut header = [0u8; 8];
+      socket.read_exact(&mut header).await?;
+      let mut id = [0u8; 4];
+      id.copy_from_slice(&header[..4]);
+      let value = LittleEndian::read_u32(&header[4..]);
+
+      match SyncId::from_bytes(&id) {
+        Some(SyncId::Data) => {
+          let length = value as usize;
+          if length > SYNC_DATA_MAX {
+            let msg = format!("oversized DATA packet: {}", length);
+            write_fail(socket, &msg).await?;
+            return Err(adb::Error::UnexpectedData(msg));
+          }
+
+          let mut data = vec![0u8; length];
+          socket.read_exact(&mut data).await?;
+          trace!(len = length, "received DATA");
+          total_bytes += length;
+          if let Ok(mut r) = receiver {
+            receiver = runtime::spawn_blocking(move || r.write(&data).map(|()| r)).await;
+          }
+        }
+
+        Some(SyncId::Done) => break value,
+
+        _ => {
+          let msg = format!("unexpected packet during SEND: '{}'", String::from_utf8_lossy(&id));
+          write_fail(socket, &msg).await?;
+          return Err(adb::Error::UnexpectedData(msg));
+        }
+      }
+    };
+
+    match runtime::spawn_blocking(move || receiver.and_then(|r| r.finish(mtime))).await {
+      Ok(()) => {
+        debug!(path, bytes = total_bytes, mtime, "received file");
+        socket.write_all(&encode_header(SyncId::Okay, 0)).await?
+      }
+      Err(RequestError(msg)) => write_fail(socket, &msg).await?,
+    }
+    Ok(())
+  }
+
+  async fn handle_recv(&self, socket: &mut dyn Socket, path: &str) -> adb::Result<()> {
+    let file = self
+      .blocking(path, |server, path| {
+        server
+          .resolve(path, true)
+          .and_then(|path| File::open(path).map_err(|err| RequestError::io("open", err)))
+      })
+      .await;
+
+    let mut file = match file {
+      Ok(file) => file,
+      Err(RequestError(msg)) => return write_fail(socket, &msg).await,
+    };
+
+    let mut buf = vec![0u8; 8 + SYNC_DATA_MAX];
+    let mut total_bytes = 0;
+    loop {
+      let (result, returned_file, returned_buf) = runtime::spawn_blocking(move || {
+        let result = file.read(&mut buf[8..]);
+        (result, file, buf)
+      })
+      .await;
+      file = returned_file;
+      buf = returned_buf;
+
+      let len = match result {
+        Ok(0) => break,
+        Ok(len) => len,
+        Err(err) => return write_fail(socket, &format!("read failed: {}", err)).await,
+      };
+
+      buf[..8].copy_from_slice(&encode_header(SyncId::Data, len as u32));
+      socket.write_all(&buf[..8 + len]).await?;
+      total_bytes += len;
+    }
+
+    debug!(path, bytes = total_bytes, "sent file");
+    socket.write_all(&encode_header(SyncId::Done, 0)).await?;
+    Ok(())
+  }
+}
+
+/// Lists a directory for a LIST or LIST2 request, returning an encoded DENT or DENT2 message for every entry.
+///
+/// Failure to open the directory results in an empty listing, as with upstream adbd.
+fn list_dir(server: &SyncServer, path: &str, v2: bool) -> Vec<Vec<u8>> {
+  let entries = server
+    .resolve(path, true)
+    .ok()
+    .and_then(|path| fs::read_dir(path).ok())
+    .map(|dir| dir.filter_map(|entry| entry.ok()).collect::<Vec<_>>())
+    .unwrap_or_default();
+
+  let mut messages = Vec::new();
+  for entry in entries {
+    let name = entry.file_name();
+    let name = name.to_string_lossy();
+    let mut buf = Vec::new();
+    if v2 {
+      buf.extend_from_slice(&SyncId::Dent2.to_bytes());
+      match entry.path().symlink_metadata() {
+        Ok(metadata) => stat_v2(&metadata).encode(&mut buf),
+        Err(err) => SyncStatV2::error(errno(&err)).encode(&mut buf),
+      }
+    } else {
+      // Entries that can't be stat'ed are skipped in the v1 protocol.
+      let metadata = match entry.path().symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(_) => continue,
+      };
+      buf.extend_from_slice(&SyncId::Dent.to_bytes());
+      stat_v1(&metadata).encode(&mut buf);
+    }
+
+    let mut name_len = [0u8; 4];
+    LittleEndian::write_u32(&mut name_len, name.len() as u32);
+    buf.extend_from_slice(&name_len);
+    buf.extend_from_slice(name.as_bytes());
+    messages.push(buf);
+  }
+  messages
+}
+
+/// Distinguishes the temporary files of concurrent transfers to the same path.
+static TRANSFER_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Destination of a SEND request.
+///
+/// Regular files are written to a temporary file in the destination directory, which is renamed over the destination
+/// once the transfer completes successfully. Symlinks are received in memory and created at the end.
+enum FileReceiver {
+  File {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    file: Option<File>,
+    mode: u32,
+  },
+
+  Symlink {
+    path: PathBuf,
+    target: Vec<u8>,
+  },
+}
+
+impl FileReceiver {
+  fn new(path: PathBuf, mode: u32) -> RequestResult<FileReceiver> {
+    let file_name = path
+      .file_name()
+      .ok_or_else(|| RequestError(format!("invalid destination path '{}'", path.display())))?
+      .to_owned();
+
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent).map_err(|err| RequestError::io("mkdir", err))?;
+    }
+
+    if mode & S_IFMT == S_IFLNK {
+      return Ok(FileReceiver::Symlink {
+        path,
+        target: Vec::new(),
+      });
+    }
+
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(&file_name);
+    tmp_name.push(format!(
+      ".adb-tmp-{}-{}",
+      std::process::id(),
+      TRANSFER_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let file = File::create(&tmp_path).map_err(|err| RequestError::io("create", err))?;
+    Ok(FileReceiver::File {
+      path,
+      tmp_path,
+      file: Some(file),
+      mode,
+    })
+  }
+
+  fn write(&mut self, data: &[u8]) -> RequestResult<()> {
+    match self {
+      FileReceiver::File { file, .. } => file
+        .as_mut()
+        .expect("FileReceiver used after finish")
+        .write_all(data)
+        .map_err(|err| RequestError::io("write", err)),
+
+      FileReceiver::Symlink { target, .. } => {
+        target.extend_from_slice(data);
+        Ok(())
+      }
+    }
+  }
+
+  fn finish(mut self, mtime: u32) -> RequestResult<()> {
+    match &mut self {
+      FileReceiver::File {
+        path,
+        tmp_path,
+        file,
+        mode,
+      } => {
+        let file = file.take().expect("FileReceiver finished twice");
+        file.sync_all().map_err(|err| RequestError::io("fsync", err))?;
+        drop(file);
+
+        set_mode(tmp_path, *mode & 0o7777).map_err(|err| RequestError::io("chmod", err))?;
+        let mtime = filetime::FileTime::from_unix_time(i64::from(mtime), 0);
+        filetime::set_file_times(&tmp_path, mtime, mtime).map_err(|err| RequestError::io("utime", err))?;
+        fs::rename(&tmp_path, path).map_err(|err| RequestError::io("rename", err))?;
+
+        // The temporary file is gone, don't let Drop try to clean it up.
+        tmp_path.clear();
+        Ok(())
+      }
+
+      FileReceiver::Symlink { path, target } => {
+        // Symlink targets are NUL-terminated on the wire.
+        if target.last() == Some(&0) {
+          target.pop();
+        }
+        let target =
+          String::from_utf8(target.clone()).map_err(|_| RequestError("symlink target is not valid UTF-8".into()))?;
+        let _ = fs::remove_file(&path);
+        create_symlink(&target, path).map_err(|err| RequestError::io("symlink", err))
+      }
+    }
+  }
+}
+
+impl Drop for FileReceiver {
+  fn drop(&mut self) {
+    if let FileReceiver::File { tmp_path, .. } = self {
+      if !tmp_path.as_os_str().is_empty() {
+        let _ = fs::remove_file(tmp_path);
+      }
+    }
+  }
+}
+
+async fn write_fail(socket: &mut dyn Socket, msg: &str) -> adb::Result<()> {
+  debug!(message = msg, "sending FAIL");
+  let mut buf = encode_header(SyncId::Fail, msg.len() as u32).to_vec();
+  buf.extend_from_slice(msg.as_bytes());
+  socket.write_all(&buf).await?;
+  Ok(())
+}
+
+const EACCES: u32 = 13;
+
+fn errno(err: &std::io::Error) -> u32 {
+  err.raw_os_error().map(|e| e as u32).unwrap_or(EACCES)
+}
+
+#[cfg(unix)]
+fn stat_v2(metadata: &fs::Metadata) -> SyncStatV2 {
+  use std::os::unix::fs::MetadataExt;
+  SyncStatV2 {
+    error: 0,
+    dev: metadata.dev(),
+    ino: metadata.ino(),
+    mode: metadata.mode(),
+    nlink: metadata.nlink() as u32,
+    uid: metadata.uid(),
+    gid: metadata.gid(),
+    size: metadata.size(),
+    atime: metadata.atime(),
+    mtime: metadata.mtime(),
+    ctime: metadata.ctime(),
+  }
+}
+
+#[cfg(not(unix))]
+fn stat_v2(metadata: &fs::Metadata) -> SyncStatV2 {
+  let mtime = filetime::FileTime::from_last_modification_time(metadata).unix_seconds();
+  let atime = filetime::FileTime::from_last_access_time(metadata).unix_seconds();
+  let mode = if metadata.is_dir() { 0o040_755 } else { 0o100_644 };
+  SyncStatV2 {
+    mode,
+    nlink: 1,
+    size: metadata.len(),
+    atime,
+    mtime,
+    ctime: mtime,
+    ..Default::default()
+  }
+}
+
+fn stat_v1(metadata: &fs::Metadata) -> SyncStatV1 {
+  let stat = stat_v2(metadata);
+  SyncStatV1 {
+    mode: stat.mode,
+    size: stat.size as u32,
+    mtime: stat.mtime as u32,
+  }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+  use std::os::unix::fs::PermissionsExt;
+  fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+  Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &str, path: &Path) -> std::io::Result<()> {
+  std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &str, _path: &Path) -> std::io::Result<()> {
+  Err(std::io::Error::new(
+    std::io::ErrorKind::Other,
+    "symlinks are unsupported on this platform",
+  ))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use futures::executor::block_on;
+  use futures::io::{AsyncRead, AsyncWrite};
+  use std::pin::Pin;
+  use std::task::{Context, Poll};
+
+  /// A socket that reads from a prepared buffer of requests, and collects all responses.
+  struct ScriptedSocket {
+    input: std::io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+  }
+
+  impl AsyncRead for ScriptedSocket {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+      Poll::Ready(self.input.read(buf))
+    }
+  }
+
+  impl AsyncWrite for ScriptedSocket {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+      self.output.extend_from_slice(buf);
+      Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+      Poll::Ready(Ok(()))
+    }
+  }
+
+  fn request(id: SyncId, payload: &[u8]) -> Vec<u8> {
+    let mut buf = encode_header(id, payload.len() as u32).to_vec();
+    buf.extend_from_slice(payload);
+    buf
+  }
+
+  fn serve(server: &SyncServer, input: Vec<u8>) -> Vec<u8> {
+    let mut socket = ScriptedSocket {
+      input: std::io::Cursor::new(input),
+      output: Vec::new(),
+    };
+    block_on(server.serve(&mut socket)).expect("sync session failed");
+    socket.output
+  }
+
+  fn confined_server(root: &Path) -> SyncServer {
+    let mut server = SyncServer::new();
+    server.root(Some(root.to_path_buf()));
+    server
+  }
+
+  #[test]
+  fn send_then_recv() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = confined_server(dir.path());
+
+    let mut input = request(SyncId::Send, b"/foo/bar,33188");
+    input.extend(request(SyncId::Data, b"hello, "));
+    input.extend(request(SyncId::Data, b"world"));
+    input.extend_from_slice(&encode_header(SyncId::Done, 1_234_567_890));
+    input.extend(request(SyncId::Recv, b"/foo/bar"));
+    input.extend(request(SyncId::Quit, b""));
+
+    let output = serve(&server, input);
+    let mut expected = encode_header(SyncId::Okay, 0).to_vec();
+    expected.extend(request(SyncId::Data, b"hello, world"));
+    expected.extend_from_slice(&encode_header(SyncId::Done, 0));
+    assert_eq!(expected, output);
+
+    let metadata = fs::metadata(dir.path().join("foo/bar")).unwrap();
+    let mtime = filetime::FileTime::from_last_modification_time(&metadata);
+    assert_eq!(1_234_567_890, mtime.unix_seconds());
+    assert_eq!(0o644, stat_v2(&metadata).mode & 0o777);
+    assert_eq!(1, fs::read_dir(dir.path().join("foo")).unwrap().count());
+  }
+
+  #[test]
+  fn concurrent_sends() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("file");
+
+    // Two transfers to the same path write to their own temporary files, and the last one to finish wins.
+    let mut first = FileReceiver::new(path.clone(), 0o100_644).ok().unwrap();
+    let mut second = FileReceiver::new(path.clone(), 0o100_644).ok().unwrap();
+    first.write(b"first").ok().unwrap();
+    second.write(b"second").ok().unwrap();
+    first.finish(0).ok().unwrap();
+    assert_eq!(b"first", &fs::read(&path).unwrap()[..]);
+    second.finish(0).ok().unwrap();
+    assert_eq!(b"second", &fs::read(&path).unwrap()[..]);
+    assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+  }
+
+  #[test]
+  fn recv_missing_file_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = confined_server(dir.path());
+
+    let output = serve(&server, request(SyncId::Recv, b"missing"));
+    assert_eq!(b"FAIL", &output[..4]);
+    let msg = String::from_utf8_lossy(&output[8..]);
+    assert!(msg.starts_with("open failed"), "unexpected message: {}", msg);
+  }
+
+  #[test]
+  fn root_confinement() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = confined_server(dir.path());
+
+    assert_eq!(dir.path().join("a"), server.resolve("/../../a", true).ok().unwrap());
+    assert_eq!(dir.path().join("b"), server.resolve("a/../../b", true).ok().unwrap());
+
+    #[cfg(unix)]
+    {
+      std::os::unix::fs::symlink("/", dir.path().join("escape")).unwrap();
+      assert!(server.resolve("/escape/etc", true).is_err());
+      assert!(server.resolve("/escape", false).is_ok());
+    }
+  }
+
+  #[test]
+  fn list_v2() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("file"), b"contents").unwrap();
+    let server = confined_server(dir.path());
+
+    let output = serve(&server, request(SyncId::List2, b"/"));
+    assert_eq!(b"DNT2", &output[..4]);
+    let stat = SyncStatV2::decode(&output[4..4 + SyncStatV2::ENCODED_SIZE]);
+    assert_eq!(0, stat.error);
+    assert_eq!(8, stat.size);
+
+    let name_offset = 4 + SyncStatV2::ENCODED_SIZE;
+    let name_len = LittleEndian::read_u32(&output[name_offset..]) as usize;
+    assert_eq!(b"file", &output[name_offset + 4..name_offset + 4 + name_len]);
+
+    let done = &output[name_offset + 4 + name_len..];
+    assert_eq!(b"DONE", &done[..4]);
+    assert_eq!(4 + SyncStatV2::ENCODED_SIZE + 4, done.len());
+    assert!(done[4..].iter().all(|&b| b == 0));
+  }
+}
@@ -1,10 +1,11 @@
 //! Types and functions shared across host implementations (client and server).
 
 /// Integral identifier for transports.
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct TransportId(pub u64);
 
 /// Selection criteria for a device.
+#[derive(Clone, Debug)]
 pub enum DeviceCriteria {
   /// Any device (default in the CLI).
   Any,
@@ -23,7 +24,7 @@ pub enum DeviceCriteria {
 }
 
 /// Information about a device.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DeviceDescription {
   pub serial: String,
   pub id: TransportId,
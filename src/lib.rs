@@ -1,13 +1,22 @@
-#![feature(async_await)]
-
 pub mod core;
 
 #[cfg(feature = "client")]
 pub mod client;
 
+#[cfg(feature = "daemon")]
+pub mod daemon;
+
 #[cfg(feature = "host")]
 pub mod host;
 
+pub mod runtime;
+
+#[cfg(feature = "client")]
+pub mod blocking;
+
+#[cfg(all(feature = "client", any(test, feature = "testing")))]
+pub mod testing;
+
 pub(crate) mod util;
 
 pub use crate::core::*;
@@ -0,0 +1,65 @@
+use async_io::Async;
+use futures::future::Future;
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+use crate::core::Socket;
+
+pub(crate) fn block_on_impl<F: Future>(future: F) -> F::Output {
+  async_io::block_on(future)
+}
+
+/// async-io only provides a reactor, so each task gets a thread of its own.
+pub(crate) fn spawn_impl(future: impl Future<Output = ()> + Send + 'static) {
+  std::thread::spawn(move || async_io::block_on(future));
+}
+
+/// Blocking functions run on the thread pool of the `blocking` crate, which async-io is usually paired with.
+pub(crate) fn spawn_blocking_impl(f: impl FnOnce() + Send + 'static) {
+  blocking::unblock(f).detach();
+}
+
+pub(crate) async fn connect_tcp(addr: SocketAddr) -> std::io::Result<Box<dyn Socket>> {
+  let stream = Async::<TcpStream>::connect(addr).await?;
+  stream.get_ref().set_nodelay(true)?;
+  Ok(Box::new(stream))
+}
+
+#[cfg(unix)]
+pub(crate) fn connect_unix(addr: &std::os::unix::net::SocketAddr) -> std::io::Result<Box<dyn Socket>> {
+  let stream = std::os::unix::net::UnixStream::connect_addr(addr)?;
+  Ok(Box::new(Async::new(stream)?))
+}
+
+pub(crate) enum RawListener {
+  Tcp(Async<TcpListener>),
+  #[cfg(unix)]
+  Unix(Async<std::os::unix::net::UnixListener>),
+}
+
+impl RawListener {
+  pub(crate) fn tcp(listener: TcpListener) -> std::io::Result<RawListener> {
+    Ok(RawListener::Tcp(Async::new(listener)?))
+  }
+
+  #[cfg(unix)]
+  pub(crate) fn unix(listener: std::os::unix::net::UnixListener) -> std::io::Result<RawListener> {
+    Ok(RawListener::Unix(Async::new(listener)?))
+  }
+
+  pub(crate) async fn accept(&self) -> std::io::Result<Box<dyn Socket>> {
+    match self {
+      RawListener::Tcp(listener) => {
+        let (stream, _) = listener.accept().await?;
+        stream.get_ref().set_nodelay(true)?;
+        Ok(Box::new(stream))
+      }
+
+      #[cfg(unix)]
+      RawListener::Unix(listener) => {
+        let (stream, _) = listener.accept().await?;
+        Ok(Box::new(stream))
+      }
+    }
+  }
+}
@@ -0,0 +1,82 @@
+//! Glue between the library and the async runtime that drives its sockets.
+//!
+//! The library's sockets implement the runtime-agnostic [futures::io] traits, but establishing connections and
+//! listening requires a reactor. The backend is selected with cargo features: `tokio` (the default) or `async-io`.
+//! If both are enabled, tokio is used.
+//!
+//! When using the tokio backend, sockets must be created and used from within a tokio runtime. [block_on] and [spawn]
+//! are provided for callers that don't otherwise care about which runtime is in use: outside of a tokio runtime, they
+//! use a process-wide one that is created on first use.
+
+use futures::channel::oneshot;
+use futures::future::Future;
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+#[cfg(feature = "tokio")]
+mod tokio;
+#[cfg(feature = "tokio")]
+pub(crate) use self::tokio::*;
+
+#[cfg(all(feature = "async-io", not(feature = "tokio")))]
+mod async_io;
+#[cfg(all(feature = "async-io", not(feature = "tokio")))]
+pub(crate) use self::async_io::*;
+
+#[cfg(not(any(feature = "tokio", feature = "async-io")))]
+compile_error!("one of the `tokio` or `async-io` features must be enabled");
+
+/// Runs a future to completion on the current thread, with the selected runtime available to it.
+///
+/// This should not be called from within an async context. With the tokio backend, it's allowed from within a
+/// multi-threaded runtime, where it blocks in place, but panics from within a current-thread runtime.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+  block_on_impl(future)
+}
+
+/// Spawns a future onto the selected runtime.
+///
+/// The future starts running immediately, and keeps running if the returned [JoinHandle] is dropped.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+  F: Future + Send + 'static,
+  F::Output: Send + 'static,
+{
+  let (sender, receiver) = oneshot::channel();
+  spawn_impl(async move {
+    let _ = sender.send(future.await);
+  });
+  JoinHandle { receiver }
+}
+
+/// Runs a blocking function (e.g. filesystem I/O) on a thread where blocking is allowed, without blocking the
+/// runtime's other tasks.
+pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
+{
+  let (sender, receiver) = oneshot::channel();
+  spawn_blocking_impl(move || {
+    let _ = sender.send(f());
+  });
+  JoinHandle { receiver }
+}
+
+/// Handle to a task spawned with [spawn] or [spawn_blocking], which resolves to the task's output.
+pub struct JoinHandle<T> {
+  receiver: oneshot::Receiver<T>,
+}
+
+impl<T> Future for JoinHandle<T> {
+  type Output = T;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+    match Pin::new(&mut self.receiver).poll(cx) {
+      Poll::Ready(Ok(value)) => Poll::Ready(value),
+      Poll::Ready(Err(_)) => panic!("spawned task panicked"),
+      Poll::Pending => Poll::Pending,
+    }
+  }
+}
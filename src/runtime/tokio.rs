@@ -0,0 +1,101 @@
+use futures::future::Future;
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+
+use crate::core::Socket;
+
+/// Runtime used by callers that aren't already running inside of one.
+///
+/// It lives for the rest of the process, so that sockets created by one call to [block_on_impl] keep working in the
+/// next one.
+fn global_runtime() -> &'static ::tokio::runtime::Runtime {
+  static RUNTIME: OnceLock<::tokio::runtime::Runtime> = OnceLock::new();
+  RUNTIME.get_or_init(|| {
+    ::tokio::runtime::Builder::new_multi_thread()
+      .enable_all()
+      .thread_name("adb-runtime")
+      .build()
+      .expect("failed to create tokio runtime")
+  })
+}
+
+pub(crate) fn block_on_impl<F: Future>(future: F) -> F::Output {
+  match ::tokio::runtime::Handle::try_current() {
+    // Already inside of a runtime (e.g. on a thread spawned by it), block in place. That's only possible on a
+    // multi-threaded runtime: a current-thread one has nowhere else to run its other tasks while this one blocks.
+    Ok(handle) => match handle.runtime_flavor() {
+      ::tokio::runtime::RuntimeFlavor::MultiThread => ::tokio::task::block_in_place(|| handle.block_on(future)),
+      flavor => panic!(
+        "adb::runtime::block_on called from within a {:?} tokio runtime, which can't block in place; use the async API \
+         instead, or call it from a multi-threaded runtime",
+        flavor
+      ),
+    },
+    Err(_) => global_runtime().block_on(future),
+  }
+}
+
+pub(crate) fn spawn_impl(future: impl Future<Output = ()> + Send + 'static) {
+  match ::tokio::runtime::Handle::try_current() {
+    Ok(handle) => handle.spawn(future),
+    Err(_) => global_runtime().spawn(future),
+  };
+}
+
+pub(crate) fn spawn_blocking_impl(f: impl FnOnce() + Send + 'static) {
+  match ::tokio::runtime::Handle::try_current() {
+    Ok(handle) => handle.spawn_blocking(f),
+    Err(_) => global_runtime().spawn_blocking(f),
+  };
+}
+
+pub(crate) async fn connect_tcp(addr: SocketAddr) -> std::io::Result<Box<dyn Socket>> {
+  let stream = ::tokio::net::TcpStream::connect(addr).await?;
+  stream.set_nodelay(true)?;
+  Ok(Box::new(stream.compat_write()))
+}
+
+#[cfg(unix)]
+pub(crate) fn connect_unix(addr: &std::os::unix::net::SocketAddr) -> std::io::Result<Box<dyn Socket>> {
+  let stream = std::os::unix::net::UnixStream::connect_addr(addr)?;
+  stream.set_nonblocking(true)?;
+  let stream = ::tokio::net::UnixStream::from_std(stream)?;
+  Ok(Box::new(stream.compat_write()))
+}
+
+pub(crate) enum RawListener {
+  Tcp(::tokio::net::TcpListener),
+  #[cfg(unix)]
+  Unix(::tokio::net::UnixListener),
+}
+
+impl RawListener {
+  pub(crate) fn tcp(listener: std::net::TcpListener) -> std::io::Result<RawListener> {
+    listener.set_nonblocking(true)?;
+    Ok(RawListener::Tcp(::tokio::net::TcpListener::from_std(listener)?))
+  }
+
+  #[cfg(unix)]
+  pub(crate) fn unix(listener: std::os::unix::net::UnixListener) -> std::io::Result<RawListener> {
+    listener.set_nonblocking(true)?;
+    Ok(RawListener::Unix(::tokio::net::UnixListener::from_std(listener)?))
+  }
+
+  pub(crate) async fn accept(&self) -> std::io::Result<Box<dyn Socket>> {
+    match self {
+      RawListener::Tcp(listener) => {
+        let (stream, _) = listener.accept().await?;
+        stream.set_nodelay(true)?;
+        Ok(Box::new(stream.compat()))
+      }
+
+      #[cfg(unix)]
+      RawListener::Unix(listener) => {
+        let (stream, _) = listener.accept().await?;
+        Ok(Box::new(stream.compat()))
+      }
+    }
+  }
+}
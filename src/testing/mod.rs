@@ -0,0 +1,833 @@
+//! A scriptable fake adb server, for unit testing code that uses [Remote](crate::client::Remote).
+//!
+//! The server listens on a loopback port and speaks the smart socket protocol. Tests register responses for `host:`
+//! queries and device services, run the code under test against [FakeServer::remote], and then assert on the
+//! services that were requested.
+//!
+//! Each connection is handled on its own thread with blocking I/O, so the server works regardless of which executor
+//! the code under test uses.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(feature = "daemon")]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::client::shell::raw;
+use crate::client::Remote;
+use crate::core::SocketSpec;
+use crate::host::{DeviceDescription, TransportId, TransportType};
+use crate::util::ConsumePrefix;
+
+/// Features reported by devices by default.
+pub const DEFAULT_FEATURES: &[&str] = &["shell_v2", "cmd", "stat_v2", "ls_v2", "abb", "abb_exec"];
+
+/// A scripted response to a service request.
+#[derive(Clone, Debug)]
+pub enum Response {
+  /// OKAY, followed by a hex-length-prefixed payload, as returned by most `host:` queries.
+  ///
+  /// The payload can be at most 0xffff bytes long: registering a longer one panics.
+  Payload(Vec<u8>),
+
+  /// OKAY, followed by raw data until the connection is closed.
+  Raw(Vec<u8>),
+
+  /// FAIL, with an error message.
+  Fail(String),
+
+  /// OKAY, after which `len` bytes of input are read from the client (see [FakeServer::inputs]), followed by raw
+  /// output until the connection is closed, as returned by services that read from stdin like `exec:`.
+  Input { len: usize, output: Vec<u8> },
+
+  /// OKAY, followed by the output of a shell command.
+  ///
+  /// The output is sent with shell protocol framing if the service requested `v2`, otherwise stdout and stderr are
+  /// concatenated and sent raw.
+  Shell {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    exit_code: u8,
+  },
+
+  /// OKAY, followed by a sync session served from a directory on the host.
+  #[cfg(feature = "daemon")]
+  Sync(PathBuf),
+}
+
+impl Response {
+  /// Constructs a [Response::Payload].
+  ///
+  /// Panics if the payload is too long for its length prefix.
+  pub fn payload(data: impl Into<Vec<u8>>) -> Response {
+    let response = Response::Payload(data.into());
+    response.check();
+    response
+  }
+
+  /// Constructs a [Response::Raw].
+  pub fn raw(data: impl Into<Vec<u8>>) -> Response {
+    Response::Raw(data.into())
+  }
+
+  /// Constructs a [Response::Fail].
+  ///
+  /// Panics if the message is too long for its length prefix.
+  pub fn fail(message: impl Into<String>) -> Response {
+    let response = Response::Fail(message.into());
+    response.check();
+    response
+  }
+
+  /// Panics if the response can't be sent, so that tests fail where it was created or registered, rather than with
+  /// a dropped connection when it's requested.
+  fn check(&self) {
+    match self {
+      Response::Payload(data) => {
+        length_prefix(data.len());
+      }
+      Response::Fail(message) => {
+        length_prefix(message.len());
+      }
+      _ => {}
+    }
+  }
+
+  /// Constructs a [Response::Input].
+  pub fn input(len: usize, output: impl Into<Vec<u8>>) -> Response {
+    Response::Input {
+      len,
+      output: output.into(),
+    }
+  }
+
+  /// Constructs a [Response::Shell].
+  pub fn shell(stdout: impl Into<Vec<u8>>, stderr: impl Into<Vec<u8>>, exit_code: u8) -> Response {
+    Response::Shell {
+      stdout: stdout.into(),
+      stderr: stderr.into(),
+      exit_code,
+    }
+  }
+}
+
+/// A service request received by a [FakeServer].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+  /// The transport that the request was sent to, or `None` for requests to the server itself.
+  pub transport_id: Option<TransportId>,
+
+  /// The requested service.
+  pub service: String,
+}
+
+#[derive(Default)]
+struct State {
+  host_responses: HashMap<String, Response>,
+  device_responses: HashMap<String, Response>,
+  shell_responses: HashMap<String, Response>,
+  devices: Vec<DeviceDescription>,
+  features: HashMap<TransportId, Vec<String>>,
+  requests: Vec<Request>,
+  inputs: Vec<Vec<u8>>,
+
+  /// Connections tracking devices, and whether they requested the long format.
+  trackers: Vec<(TcpStream, bool)>,
+}
+
+impl State {
+  /// Formats the list of devices, as sent by `host:devices` and `host:track-devices`.
+  fn device_list(&self, long: bool) -> String {
+    let mut output = String::new();
+    for device in &self.devices {
+      output.push_str(&format_device(device, long));
+      output.push('\n');
+    }
+    output
+  }
+
+  /// Sends the list of devices to every tracker, dropping the ones that have disconnected.
+  fn notify_trackers(&mut self) {
+    let mut trackers = std::mem::take(&mut self.trackers);
+    trackers.retain_mut(|(stream, long)| {
+      let output = self.device_list(*long);
+      stream
+        .write_all(format!("{}{}", length_prefix(output.len()), output).as_bytes())
+        .is_ok()
+    });
+    self.trackers = trackers;
+  }
+}
+
+/// A fake adb server listening on a loopback port.
+///
+/// The server is shut down when dropped.
+pub struct FakeServer {
+  address: SocketAddr,
+  state: Arc<Mutex<State>>,
+  shutdown: Arc<AtomicBool>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl FakeServer {
+  /// Starts a new server on an ephemeral loopback port.
+  ///
+  /// The server responds to `host:version` with protocol version 41, and to `host:devices`, `host:devices-l` and
+  /// `host:track-devices-l` with the devices registered with [FakeServer::add_device], unless overridden. Device
+  /// trackers stay connected and receive an update whenever a device is added or removed, until the server is
+  /// dropped. Device features (e.g. `host-serial:foo:features`) are [DEFAULT_FEATURES], unless set with
+  /// [FakeServer::set_features].
+  pub fn start() -> std::io::Result<FakeServer> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?;
+    let state = Arc::new(Mutex::new(State::default()));
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    let thread = {
+      let state = state.clone();
+      let shutdown = shutdown.clone();
+      std::thread::spawn(move || {
+        for stream in listener.incoming() {
+          if shutdown.load(Ordering::SeqCst) {
+            break;
+          }
+
+          if let Ok(stream) = stream {
+            let state = state.clone();
+            std::thread::spawn(move || {
+              let _ = Connection { stream, state }.run();
+            });
+          }
+        }
+      })
+    };
+
+    let server = FakeServer {
+      address,
+      state,
+      shutdown,
+      thread: Some(thread),
+    };
+    server.on_host("host:version", Response::payload("0029"));
+    Ok(server)
+  }
+
+  /// Returns the [SocketSpec] of the server.
+  pub fn socket_spec(&self) -> SocketSpec {
+    SocketSpec::tcp(Some(self.address.ip().to_string()), self.address.port())
+  }
+
+  /// Returns a [Remote] pointing at the server.
+  pub fn remote(&self) -> Remote {
+    Remote::new(self.socket_spec())
+  }
+
+  /// Registers a response for a request to the server itself (e.g. `host:version`).
+  pub fn on_host(&self, service: impl Into<String>, response: Response) -> &FakeServer {
+    response.check();
+    self.lock().host_responses.insert(service.into(), response);
+    self
+  }
+
+  /// Registers a response for a device service, regardless of which device it is requested from.
+  pub fn on_device(&self, service: impl Into<String>, response: Response) -> &FakeServer {
+    response.check();
+    self.lock().device_responses.insert(service.into(), response);
+    self
+  }
+
+  /// Registers the result of a shell command.
+  ///
+  /// The command is matched against both `shell:` and `shell,v2,...:` services, regardless of their options.
+  pub fn on_shell(&self, command: impl Into<String>, stdout: &[u8], stderr: &[u8], exit_code: u8) -> &FakeServer {
+    self
+      .lock()
+      .shell_responses
+      .insert(command.into(), Response::shell(stdout, stderr, exit_code));
+    self
+  }
+
+  /// Serves `sync:` requests from a directory on the host, which acts as the root of the device's filesystem.
+  #[cfg(feature = "daemon")]
+  pub fn on_sync(&self, root: impl Into<PathBuf>) -> &FakeServer {
+    self.on_device("sync:", Response::Sync(root.into()))
+  }
+
+  /// Adds a device that can be selected by device services.
+  pub fn add_device(&self, device: DeviceDescription) -> &FakeServer {
+    let mut state = self.lock();
+    state.devices.push(device);
+    state.notify_trackers();
+    drop(state);
+    self
+  }
+
+  /// Removes a device that was added with [FakeServer::add_device].
+  pub fn remove_device(&self, transport_id: TransportId) -> &FakeServer {
+    let mut state = self.lock();
+    state.devices.retain(|device| device.id != transport_id);
+    state.notify_trackers();
+    drop(state);
+    self
+  }
+
+  /// Sets the features reported for a device, instead of [DEFAULT_FEATURES].
+  pub fn set_features(&self, transport_id: TransportId, features: &[&str]) -> &FakeServer {
+    let features = features.iter().map(|f| f.to_string()).collect();
+    self.lock().features.insert(transport_id, features);
+    self
+  }
+
+  /// Returns all requests received so far, in order.
+  ///
+  /// Transport selection requests (e.g. `host:tport:any`) are included, with the selected device's service following
+  /// them as a separate request.
+  pub fn requests(&self) -> Vec<Request> {
+    self.lock().requests.clone()
+  }
+
+  /// Returns the services of all requests received so far, in order.
+  pub fn services(&self) -> Vec<String> {
+    self.lock().requests.iter().map(|r| r.service.clone()).collect()
+  }
+
+  /// Returns the services of all requests sent to devices so far, in order.
+  pub fn device_services(&self) -> Vec<String> {
+    self
+      .lock()
+      .requests
+      .iter()
+      .filter(|r| r.transport_id.is_some())
+      .map(|r| r.service.clone())
+      .collect()
+  }
+
+  /// Asserts that exactly the given services were requested, in order.
+  pub fn assert_services(&self, expected: &[&str]) {
+    let actual = self.services();
+    assert_eq!(expected, &actual[..], "unexpected sequence of requested services");
+  }
+
+  /// Asserts that exactly the given device services were requested, in order.
+  pub fn assert_device_services(&self, expected: &[&str]) {
+    let actual = self.device_services();
+    assert_eq!(
+      expected,
+      &actual[..],
+      "unexpected sequence of requested device services"
+    );
+  }
+
+  /// Asserts that a service was requested at least once.
+  pub fn assert_requested(&self, service: &str) {
+    let actual = self.services();
+    assert!(
+      actual.iter().any(|s| s == service),
+      "service '{}' was not requested (requested: {:?})",
+      service,
+      actual
+    );
+  }
+
+  /// Returns the input received by [Response::Input] responses so far, in order.
+  pub fn inputs(&self) -> Vec<Vec<u8>> {
+    self.lock().inputs.clone()
+  }
+
+  /// Forgets all requests received so far.
+  pub fn clear_requests(&self) {
+    self.lock().requests.clear();
+  }
+
+  fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+    self.state.lock().unwrap()
+  }
+}
+
+impl Drop for FakeServer {
+  fn drop(&mut self) {
+    self.shutdown.store(true, Ordering::SeqCst);
+
+    // Disconnect device trackers, which would otherwise wait for updates forever.
+    self.lock().trackers.clear();
+
+    // Wake up the listener thread.
+    let _ = TcpStream::connect(self.address);
+    if let Some(thread) = self.thread.take() {
+      let _ = thread.join();
+    }
+  }
+}
+
+struct Connection {
+  stream: TcpStream,
+  state: Arc<Mutex<State>>,
+}
+
+enum Selection {
+  /// The device was selected, and the next request goes to it.
+  Selected(TransportId),
+
+  /// The request wasn't a transport selection.
+  NotTransport,
+}
+
+impl Connection {
+  fn run(&mut self) -> std::io::Result<()> {
+    let service = self.read_request()?;
+    self.record(None, &service);
+
+    match self.select_transport(&service)? {
+      Selection::Selected(id) => {
+        let service = self.read_request()?;
+        self.record(Some(id), &service);
+        let response = self.device_response(&service);
+        self.respond(&service, response)
+      }
+
+      Selection::NotTransport => {
+        let overridden = self.state.lock().unwrap().host_responses.contains_key(&service);
+        if service.starts_with("host:track-devices") && !overridden {
+          return self.track_devices(service.ends_with("-l"));
+        }
+
+        let response = self.host_response(&service);
+        self.respond(&service, response)
+      }
+    }
+  }
+
+  /// Sends the current list of devices, and keeps the connection open to send updates to it.
+  fn track_devices(&mut self, long: bool) -> std::io::Result<()> {
+    let mut state = self.state.lock().unwrap();
+    let output = state.device_list(long);
+    self.stream.write_all(b"OKAY")?;
+    self
+      .stream
+      .write_all(format!("{}{}", length_prefix(output.len()), output).as_bytes())?;
+    state.trackers.push((self.stream.try_clone()?, long));
+    Ok(())
+  }
+
+  fn record(&self, transport_id: Option<TransportId>, service: &str) {
+    self.state.lock().unwrap().requests.push(Request {
+      transport_id,
+      service: service.into(),
+    });
+  }
+
+  fn read_request(&mut self) -> std::io::Result<String> {
+    let mut length = [0u8; 4];
+    self.stream.read_exact(&mut length)?;
+    let length = std::str::from_utf8(&length)
+      .ok()
+      .and_then(|s| usize::from_str_radix(s, 16).ok())
+      .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid request length"))?;
+
+    let mut service = vec![0u8; length];
+    self.stream.read_exact(&mut service)?;
+    Ok(String::from_utf8_lossy(&service).into_owned())
+  }
+
+  fn select_transport(&mut self, service: &str) -> std::io::Result<Selection> {
+    let (selector, send_id) = if let Some(tail) = service.consume_prefix("host:tport:") {
+      (tail.to_string(), true)
+    } else if let Some(tail) = service.consume_prefix("host:transport-id:") {
+      (format!("id:{}", tail), false)
+    } else if let Some(tail) = service.consume_prefix("host:transport:") {
+      (format!("serial:{}", tail), false)
+    } else if service == "host:transport-any" {
+      ("any".into(), false)
+    } else if service == "host:transport-usb" {
+      ("usb".into(), false)
+    } else if service == "host:transport-local" {
+      ("tcp".into(), false)
+    } else {
+      return Ok(Selection::NotTransport);
+    };
+
+    let result = find_device(&self.state.lock().unwrap(), &selector);
+
+    match result {
+      Ok(id) => {
+        self.stream.write_all(b"OKAY")?;
+        if send_id {
+          let mut buf = [0u8; 8];
+          LittleEndian::write_u64(&mut buf, id.0);
+          self.stream.write_all(&buf)?;
+        }
+        Ok(Selection::Selected(id))
+      }
+
+      Err(message) => {
+        self.respond(service, Response::Fail(message))?;
+        Err(std::io::Error::new(
+          std::io::ErrorKind::NotFound,
+          "transport selection failed",
+        ))
+      }
+    }
+  }
+
+  fn host_response(&self, service: &str) -> Response {
+    let state = self.state.lock().unwrap();
+    if let Some(response) = state.host_responses.get(service) {
+      return response.clone();
+    }
+
+    match service {
+      "host:devices" | "host:devices-l" => Response::Payload(state.device_list(service.ends_with("-l")).into_bytes()),
+
+      _ => match features_selector(service) {
+        Some(selector) => match find_device(&state, &selector) {
+          Ok(id) => {
+            let features = match state.features.get(&id) {
+              Some(features) => features.join(","),
+              None => DEFAULT_FEATURES.join(","),
+            };
+            Response::Payload(features.into_bytes())
+          }
+          Err(message) => Response::Fail(message),
+        },
+        None => Response::Fail(format!("unknown host service '{}'", service)),
+      },
+    }
+  }
+
+  fn device_response(&self, service: &str) -> Response {
+    let state = self.state.lock().unwrap();
+    if let Some(response) = state.device_responses.get(service) {
+      return response.clone();
+    }
+
+    if service.starts_with("shell") {
+      if let Some((_, command)) = service.split_once(':') {
+        if let Some(response) = state.shell_responses.get(command) {
+          return response.clone();
+        }
+
+        // Emulate commands wrapped to print an exit code sentinel, as used by raw shells.
+        if let Some((command, marker)) = raw::unwrap_command(command) {
+          if let Some(Response::Shell {
+            stdout,
+            stderr,
+            exit_code,
+          }) = state.shell_responses.get(command)
+          {
+            let mut output = [&stdout[..], &stderr[..]].concat();
+            output.extend_from_slice(format!("{}{}\n", marker, exit_code).as_bytes());
+            return Response::Raw(output);
+          }
+        }
+      }
+    }
+
+    Response::Fail(format!("unknown device service '{}'", service))
+  }
+
+  fn respond(&mut self, service: &str, response: Response) -> std::io::Result<()> {
+    match response {
+      Response::Payload(data) => {
+        self.stream.write_all(b"OKAY")?;
+        self.stream.write_all(length_prefix(data.len()).as_bytes())?;
+        self.stream.write_all(&data)?;
+      }
+
+      Response::Raw(data) => {
+        self.stream.write_all(b"OKAY")?;
+        self.stream.write_all(&data)?;
+      }
+
+      Response::Input { len, output } => {
+        self.stream.write_all(b"OKAY")?;
+        let mut input = vec![0u8; len];
+        self.stream.read_exact(&mut input)?;
+        self.state.lock().unwrap().inputs.push(input);
+        self.stream.write_all(&output)?;
+      }
+
+      Response::Fail(message) => {
+        self.stream.write_all(b"FAIL")?;
+        self.stream.write_all(length_prefix(message.len()).as_bytes())?;
+        self.stream.write_all(message.as_bytes())?;
+      }
+
+      Response::Shell {
+        stdout,
+        stderr,
+        exit_code,
+      } => {
+        self.stream.write_all(b"OKAY")?;
+        let (options, _) = service.split_once(':').unwrap_or((service, ""));
+        if options.split(',').any(|option| option == "v2") {
+          write_shell_packet(&mut self.stream, 1, &stdout)?;
+          write_shell_packet(&mut self.stream, 2, &stderr)?;
+          write_shell_packet(&mut self.stream, 3, &[exit_code])?;
+        } else {
+          self.stream.write_all(&stdout)?;
+          self.stream.write_all(&stderr)?;
+        }
+      }
+
+      #[cfg(feature = "daemon")]
+      Response::Sync(root) => {
+        self.stream.write_all(b"OKAY")?;
+        let mut server = crate::daemon::sync::SyncServer::new();
+        server.root(Some(root));
+        let mut socket = futures::io::AllowStdIo::new(&mut self.stream);
+        let _ = futures::executor::block_on(server.serve(&mut socket));
+      }
+    }
+
+    // Signal the end of the response, and wait for the client to hang up, discarding anything it sends.
+    self.stream.shutdown(Shutdown::Write)?;
+    let mut buf = [0u8; 4096];
+    while let Ok(len) = self.stream.read(&mut buf) {
+      if len == 0 {
+        break;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Returns the device selector of a request for a device's features.
+fn features_selector(service: &str) -> Option<String> {
+  let prefix = service.strip_suffix(":features")?;
+  match prefix {
+    "host" => Some("any".into()),
+    "host-usb" => Some("usb".into()),
+    "host-local" => Some("tcp".into()),
+    _ => {
+      if let Some(serial) = prefix.consume_prefix("host-serial:") {
+        Some(format!("serial:{}", serial))
+      } else {
+        prefix
+          .consume_prefix("host-transport-id:")
+          .map(|id| format!("id:{}", id))
+      }
+    }
+  }
+}
+
+/// Selects a device with a selector such as `any`, `usb`, `tcp`, `serial:foo` or `id:1`.
+fn find_device(state: &State, selector: &str) -> Result<TransportId, String> {
+  let online = state
+    .devices
+    .iter()
+    .filter(|d| matches!(d.transport_type, TransportType::Online(_)))
+    .collect::<Vec<_>>();
+
+  let candidates: Vec<&DeviceDescription> = if let Some((kind, value)) = selector.split_once(':') {
+    match kind {
+      "serial" => state.devices.iter().filter(|d| d.serial == value).collect(),
+      "id" => state.devices.iter().filter(|d| d.id.0.to_string() == value).collect(),
+      _ => Vec::new(),
+    }
+  } else {
+    match selector {
+      "usb" => online
+        .into_iter()
+        .filter(|d| d.device_path.as_ref().map(|p| p.starts_with("usb:")).unwrap_or(false))
+        .collect(),
+      "tcp" => online
+        .into_iter()
+        .filter(|d| !d.device_path.as_ref().map(|p| p.starts_with("usb:")).unwrap_or(false))
+        .collect(),
+      _ => online,
+    }
+  };
+
+  match candidates.as_slice() {
+    [] => {
+      if let Some(serial) = selector.consume_prefix("serial:") {
+        Err(format!("device '{}' not found", serial))
+      } else {
+        Err("no devices/emulators found".to_string())
+      }
+    }
+    [device] => match device.transport_type {
+      TransportType::Online(_) => Ok(device.id),
+      TransportType::Unauthorized => Err("device unauthorized.".into()),
+      TransportType::Offline => Err("device offline".into()),
+      other => Err(format!("device still {}", other)),
+    },
+    _ => Err("more than one device/emulator".to_string()),
+  }
+}
+
+/// Maximum payload of the packets sent by the fake shell, matching adbd's buffer size.
+const SHELL_PACKET_MAX: usize = 32 * 1024;
+
+fn write_shell_packet(stream: &mut TcpStream, id: u8, data: &[u8]) -> std::io::Result<()> {
+  if id == 3 {
+    return write_shell_packet_impl(stream, id, data);
+  }
+
+  for chunk in data.chunks(SHELL_PACKET_MAX) {
+    write_shell_packet_impl(stream, id, chunk)?;
+  }
+  Ok(())
+}
+
+/// Formats the 4 hex digit length prefix of a payload.
+///
+/// Panics if the length doesn't fit, rather than silently corrupting the framing of the response.
+fn length_prefix(len: usize) -> String {
+  assert!(
+    len <= 0xffff,
+    "payload of {} bytes is too long for a length prefix",
+    len
+  );
+  format!("{:04x}", len)
+}
+
+fn write_shell_packet_impl(stream: &mut TcpStream, id: u8, data: &[u8]) -> std::io::Result<()> {
+  let mut header = [0u8; 5];
+  header[0] = id;
+  LittleEndian::write_u32(&mut header[1..], data.len() as u32);
+  stream.write_all(&header)?;
+  stream.write_all(data)
+}
+
+fn format_device(device: &DeviceDescription, long: bool) -> String {
+  if !long {
+    return format!("{}\t{}", device.serial, device.transport_type);
+  }
+
+  let mut options = Vec::with_capacity(5);
+  if let Some(s) = &device.device_path {
+    options.push(s.clone());
+  }
+
+  if let Some(s) = &device.product {
+    options.push(format!("product:{}", s));
+  }
+
+  if let Some(s) = &device.model {
+    options.push(format!("model:{}", s));
+  }
+
+  if let Some(s) = &device.device {
+    options.push(format!("device:{}", s));
+  }
+
+  options.push(format!("transport_id:{}", device.id.0));
+  format!("{: <22} {} {}", device.serial, device.transport_type, options.join(" "))
+}
+
+/// Constructs an online [DeviceDescription] for use with [FakeServer::add_device].
+pub fn device(serial: impl Into<String>, transport_id: u64) -> DeviceDescription {
+  DeviceDescription {
+    serial: serial.into(),
+    id: TransportId(transport_id),
+    transport_type: TransportType::Online(crate::host::DeviceType::Device),
+    device_path: Some(format!("usb:1-{}", transport_id)),
+    product: Some("fake_product".into()),
+    model: Some("Fake_Model".into()),
+    device: Some("fake".into()),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::client::shell::{ShellBuilder, ShellOutput};
+  use crate::host::DeviceCriteria;
+  use crate::runtime::block_on;
+  use futures::stream::StreamExt;
+
+  #[test]
+  fn version_and_devices() {
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 1)).add_device(device("bar", 2));
+
+    let remote = server.remote();
+    assert_eq!(41, block_on(remote.version()).unwrap());
+
+    let devices = block_on(remote.devices()).unwrap();
+    assert_eq!(2, devices.len());
+    assert_eq!("foo", devices[0].serial);
+    assert_eq!(TransportId(2), devices[1].id);
+    assert_eq!(Some("Fake_Model".to_string()), devices[1].model);
+
+    server.assert_services(&["host:version", "host:devices-l"]);
+  }
+
+  #[test]
+  fn shell_v2() {
+    let server = FakeServer::start().unwrap();
+    server.add_device(device("foo", 7));
+    server.on_shell("echo foo", b"foo\n", b"bar\n", 3);
+
+    let (stdout, stderr, exit_code) = block_on(async {
+      let mut shell = ShellBuilder::new()
+        .command(Some(vec!["echo".into(), "foo".into()]))
+        .shell_protocol(true)
+        .connect(server.remote(), DeviceCriteria::Any)
+        .await
+        .unwrap();
+
+      let mut stdout = Vec::new();
+      let mut stderr = Vec::new();
+      loop {
+        match shell.next().await.unwrap().unwrap() {
+          ShellOutput::Stdout(data) => stdout.extend_from_slice(&data),
+          ShellOutput::Stderr(data) => stderr.extend_from_slice(&data),
+          ShellOutput::Exit(rc) => return (stdout, stderr, rc),
+        }
+      }
+    });
+
+    assert_eq!(b"foo\n", &stdout[..]);
+    assert_eq!(b"bar\n", &stderr[..]);
+    assert_eq!(3, exit_code);
+
+    assert_eq!(
+      vec![
+        Request {
+          transport_id: None,
+          service: "host:tport:any".into(),
+        },
+        Request {
+          transport_id: Some(TransportId(7)),
+          service: "shell,v2,raw:echo foo".into(),
+        },
+      ],
+      server.requests()
+    );
+    server.assert_device_services(&["shell,v2,raw:echo foo"]);
+  }
+
+  #[test]
+  #[should_panic(expected = "too long for a length prefix")]
+  fn oversized_payload() {
+    Response::payload(vec![0; 0x10000]);
+  }
+
+  #[test]
+  #[should_panic(expected = "too long for a length prefix")]
+  fn oversized_registered_payload() {
+    let server = FakeServer::start().unwrap();
+    server.on_host("host:foo", Response::Payload(vec![0; 0x10000]));
+  }
+
+  #[test]
+  fn device_selection_failure() {
+    let server = FakeServer::start().unwrap();
+    let result = block_on(server.remote().open_device_channel(DeviceCriteria::Any, "shell:"));
+    match result {
+      Err(err) => match err.root() {
+        crate::Error::NoDevices => {}
+        other => panic!("unexpected error: {:?}", other),
+      },
+      Ok((id, _)) => panic!("unexpectedly connected to transport {:?}", id),
+    }
+    server.assert_services(&["host:tport:any"]);
+  }
+}
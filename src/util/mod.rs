@@ -6,42 +6,34 @@ pub(crate) trait ConsumePrefix {
 
 impl<T: AsRef<str>> ConsumePrefix for T {
   fn consume_prefix(&self, prefix: &str) -> Option<&str> {
-    let s = self.as_ref();
-    if s.starts_with(prefix) {
-      Some(&s[prefix.len()..])
-    } else {
-      None
-    }
+    self.as_ref().strip_prefix(prefix)
   }
 }
 
-/// Extension trait to add helpers to split a string once.
-pub(crate) trait SplitOnce {
-  // TODO: Pattern instead of &str?
-  fn split_once(&self, pattern: &str) -> Option<(&str, &str)>;
-  fn rsplit_once(&self, pattern: &str) -> Option<(&str, &str)>;
-}
-
-impl<T: AsRef<str>> SplitOnce for T {
-  fn split_once(&self, pattern: &str) -> Option<(&str, &str)> {
-    let mut s = self.as_ref().splitn(2, pattern);
-    let first = s.next().unwrap();
-    if let Some(second) = s.next() {
-      Some((first, second))
-    } else {
-      None
+/// Formats bytes as a multi-line hexdump, for trace logging.
+#[cfg(feature = "client")]
+pub(crate) fn hexdump(data: &[u8]) -> String {
+  let mut result = String::new();
+  for (i, chunk) in data.chunks(16).enumerate() {
+    result.push_str(&format!("\n{:08x}  ", i * 16));
+    for j in 0..16 {
+      match chunk.get(j) {
+        Some(byte) => result.push_str(&format!("{:02x} ", byte)),
+        None => result.push_str("   "),
+      }
     }
-  }
 
-  fn rsplit_once(&self, pattern: &str) -> Option<(&str, &str)> {
-    let mut s = self.as_ref().rsplitn(2, pattern);
-    let first = s.next().unwrap();
-    if let Some(second) = s.next() {
-      Some((first, second))
-    } else {
-      None
-    }
+    result.push_str(" |");
+    result.extend(chunk.iter().map(|&b| {
+      if b.is_ascii_graphic() || b == b' ' {
+        b as char
+      } else {
+        '.'
+      }
+    }));
+    result.push('|');
   }
+  result
 }
 
 #[cfg(test)]
@@ -55,19 +47,14 @@ mod test {
     assert_eq!("foobar".consume_prefix(""), Some("foobar"));
   }
 
+  #[cfg(feature = "client")]
   #[test]
-  fn split_once() {
-    use super::SplitOnce;
-    assert_eq!("foo,bar,baz".split_once("foo,bar,baz"), Some(("", "")));
-    assert_eq!("foo,bar,baz".split_once(","), Some(("foo", "bar,baz")));
-    assert_eq!("foo,bar,baz".split_once("!"), None);
-  }
-
-  #[test]
-  fn rsplit_once() {
-    use super::SplitOnce;
-    assert_eq!("foo,bar,baz".rsplit_once("foo,bar,baz"), Some(("", "")));
-    assert_eq!("foo,bar,baz".rsplit_once(","), Some(("baz", "foo,bar")));
-    assert_eq!("foo,bar,baz".rsplit_once("!"), None);
+  fn hexdump() {
+    assert_eq!("", super::hexdump(b""));
+    assert_eq!(
+      "\n00000000  4f 4b 41 59 00 0a                                |OKAY..|",
+      super::hexdump(b"OKAY\0\n")
+    );
+    assert_eq!(2, super::hexdump(&[0u8; 17]).lines().count() - 1);
   }
 }